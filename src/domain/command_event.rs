@@ -1,16 +1,30 @@
-use crate::domain::sensor::{SensorRepository, SensorValue};
+use crate::domain::sensor::SensorValue;
 use crate::domain::errors::Result;
+use crate::domain::query::{Query, QueryReply};
 use crate::domain::raw_frame::RawFrame;
+use crate::domain::rf_command::{RfCommand, RfCommandReply};
+use crate::domain::sensor_identifier::SensorIdentifier;
+use crate::domain::switch_event::SwitchEvent;
+use serde::{Deserialize, Serialize};
 
-pub type GetDataFunction = Box<dyn FnOnce(&SensorRepository) -> Result<Vec<Event>> + Send>;
+pub type ResetReply = tokio::sync::oneshot::Sender<Result<()>>;
 
 pub enum Command {
     Rejeu(Vec<Event>),
     IncomingData(String),
-    GetData(GetDataFunction),
+    Query(Query, QueryReply),
+    /// forget the history of a sensor, refused when it is unknown
+    ResetSensor(SensorIdentifier, ResetReply),
+    SendRf(RfCommand, RfCommandReply),
+    /// switch the debug output of the RFLink on or off
+    SetRfDebug(bool, RfCommandReply),
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub enum Event {
     ValueChanged(SensorValue),
-    UnknowDataReceived(RawFrame)
-}
\ No newline at end of file
+    SwitchCommandReceived(SwitchEvent),
+    UnknowDataReceived(RawFrame),
+    /// history of the sensor forgotten, its next value is taken as is
+    SensorReset(SensorIdentifier),
+}
@@ -2,6 +2,7 @@ use snafu::Snafu;
 use crate::domain::sensor_value_type::ValueTypeError;
 use crate::domain::lacrosse_v3_protocol::LacrosseError;
 use crate::domain::oregon_temp_protocol::OregonError;
+use crate::domain::rflink_protocol::RfLinkError;
 
 #[derive(Debug, Snafu)]
 #[snafu(visibility = "pub(crate)")]
@@ -15,14 +16,52 @@ pub enum DomainError {
     #[snafu(display("oregon : {}", source.to_string()))]
     InternalOregonError { source: OregonError},
 
+    #[snafu(display("rflink : {}", source.to_string()))]
+    InternalRfLinkError { source: RfLinkError},
+
     #[snafu(display("error during data extraction: {}", value))]
     DataExtractionError { value: String },
 
     #[snafu(display("error during data formating: {}", source.to_string()))]
     DataFormatingError { source: serde_json::Error },
 
-    #[snafu(display("error during message sending: {}", source.to_string()))]
-    ExternalMessageError { source: lapin::Error },
+    #[snafu(display("error during storage access: {}", source.to_string()))]
+    StorageError { source: std::io::Error },
+
+    #[snafu(display("journal written in an unsupported format (version {})", version))]
+    JournalVersionError { version: u32 },
+
+    #[snafu(display("invalid rf command: {}", value))]
+    InvalidRfCommandError { value: String },
+
+    #[snafu(display("rf command rejected by the RFLink: {}", value))]
+    RfCommandRejectedError { value: String },
+
+    #[snafu(display("unknown sensor {}", value))]
+    UnknownSensorError { value: String },
+
+    #[snafu(display("RFLink link unavailable"))]
+    RfLinkUnavailableError,
+}
+
+impl DomainError {
+    /// name of the variant, ex: `InternalOregonError`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DomainError::InvalidSensorValueError { .. } => "InvalidSensorValueError",
+            DomainError::InternalLacrosseError { .. } => "InternalLacrosseError",
+            DomainError::InternalOregonError { .. } => "InternalOregonError",
+            DomainError::InternalRfLinkError { .. } => "InternalRfLinkError",
+            DomainError::DataExtractionError { .. } => "DataExtractionError",
+            DomainError::DataFormatingError { .. } => "DataFormatingError",
+            DomainError::StorageError { .. } => "StorageError",
+            DomainError::JournalVersionError { .. } => "JournalVersionError",
+            DomainError::InvalidRfCommandError { .. } => "InvalidRfCommandError",
+            DomainError::RfCommandRejectedError { .. } => "RfCommandRejectedError",
+            DomainError::UnknownSensorError { .. } => "UnknownSensorError",
+            DomainError::RfLinkUnavailableError => "RfLinkUnavailableError",
+        }
+    }
 }
 
 pub type Result<T, E = DomainError> = std::result::Result<T, E>;
@@ -0,0 +1,330 @@
+use crate::domain::query::{self, Query};
+use crate::domain::sensor::SensorValue;
+use crate::domain::sensor_identifier::SensorIdentifier;
+use crate::domain::sensor_value_type::SensorValueType;
+use chrono::{DateTime, Utc};
+use std::str::FromStr;
+
+/// Values read from the repository at once, an export never holds more of them
+pub const EXPORT_PAGE_SIZE: usize = query::PAGE_MAX_VALUES;
+
+const INHG_PER_HPA: f64 = 0.029_53;
+const MM_PER_INCH: f64 = 25.4;
+const KM_PER_MILE: f64 = 1.609_344;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    /// one JSON object per line
+    Ndjson,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<ExportFormat, String> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "ndjson" | "jsonl" => Ok(ExportFormat::Ndjson),
+            _ => Err(format!("unknown format {}, csv or ndjson expected", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Column {
+    Timestamp,
+    Protocol,
+    ProbeId,
+    ValueName,
+    Value,
+    Unit,
+}
+
+pub const ALL_COLUMNS: &[Column] = &[
+    Column::Timestamp,
+    Column::Protocol,
+    Column::ProbeId,
+    Column::ValueName,
+    Column::Value,
+    Column::Unit,
+];
+
+impl Column {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Column::Timestamp => "timestamp",
+            Column::Protocol => "protocol",
+            Column::ProbeId => "probe_id",
+            Column::ValueName => "value_name",
+            Column::Value => "value",
+            Column::Unit => "unit",
+        }
+    }
+}
+
+/// Comma separated column names, ex: `timestamp,value`
+pub fn parse_columns(list: &str) -> std::result::Result<Vec<Column>, String> {
+    list.split(',')
+        .map(|name| {
+            ALL_COLUMNS
+                .iter()
+                .find(|c| c.name() == name.trim())
+                .cloned()
+                .ok_or_else(|| format!("unknown column {}", name))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Units {
+    /// units of the RFLink, °C, hPa, mm, km/h
+    Metric,
+    /// °F, inHg, in, mph
+    Imperial,
+}
+
+impl FromStr for Units {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Units, String> {
+        match s {
+            "metric" => Ok(Units::Metric),
+            "imperial" => Ok(Units::Imperial),
+            _ => Err(format!("unknown units {}, metric or imperial expected", s)),
+        }
+    }
+}
+
+/// History of the selected sensors between `from` and `to`, oldest value of each sensor first.
+/// An empty selection list accepts every sensor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Export {
+    pub protocols: Vec<String>,
+    /// probe ids
+    pub sensors: Vec<String>,
+    pub value_names: Vec<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub format: ExportFormat,
+    pub columns: Vec<Column>,
+    pub units: Units,
+}
+
+impl Default for Export {
+    fn default() -> Export {
+        Export {
+            protocols: vec![],
+            sensors: vec![],
+            value_names: vec![],
+            from: None,
+            to: None,
+            format: ExportFormat::Csv,
+            columns: ALL_COLUMNS.to_vec(),
+            units: Units::Metric,
+        }
+    }
+}
+
+enum Cell {
+    Number(f64),
+    Text(String),
+}
+
+impl Export {
+    pub fn selects(&self, id: &SensorIdentifier) -> bool {
+        let accepts = |list: &[String], value: &str| list.is_empty() || list.iter().any(|v| v == value);
+        accepts(&self.protocols, &id.protocol)
+            && accepts(&self.sensors, &id.probe_id)
+            && accepts(&self.value_names, &id.probe_value_name)
+    }
+
+    /// true for a value of a selected sensor between `from` and `to`, both included
+    pub fn accepts(&self, value: &SensorValue) -> bool {
+        self.selects(&value.id)
+            && self.from.is_none_or(|from| value.timestamp >= from)
+            && self.to.is_none_or(|to| value.timestamp <= to)
+    }
+
+    /// Values of `id` from the `cursor` of the page already exported, from the oldest when `None`
+    pub fn page(&self, id: &SensorIdentifier, cursor: Option<u64>) -> Query {
+        Query::Page {
+            id: id.clone(),
+            from: self.from,
+            to: self.to,
+            cursor,
+            limit: EXPORT_PAGE_SIZE,
+        }
+    }
+
+    /// column names line of a CSV export, nothing for JSON lines
+    pub fn header(&self) -> String {
+        match self.format {
+            ExportFormat::Csv => {
+                let names = self.columns.iter().map(|c| c.name()).collect::<Vec<&str>>();
+                format!("{}\n", names.join(","))
+            }
+            ExportFormat::Ndjson => String::new(),
+        }
+    }
+
+    pub fn rows(&self, values: &[SensorValue]) -> String {
+        values.iter().map(|v| self.row(v)).collect()
+    }
+
+    fn row(&self, value: &SensorValue) -> String {
+        let (converted, unit) = convert(&value.value, self.units);
+        let cell = |column: &Column| match column {
+            Column::Timestamp => Cell::Text(value.timestamp.to_rfc3339()),
+            Column::Protocol => Cell::Text(value.id.protocol.clone()),
+            Column::ProbeId => Cell::Text(value.id.probe_id.clone()),
+            Column::ValueName => Cell::Text(value.id.probe_value_name.clone()),
+            Column::Value => match &converted {
+                Cell::Number(n) => Cell::Number(*n),
+                Cell::Text(t) => Cell::Text(t.clone()),
+            },
+            Column::Unit => Cell::Text(unit.to_string()),
+        };
+        match self.format {
+            ExportFormat::Csv => {
+                let cells = self.columns.iter().map(|c| match cell(c) {
+                    Cell::Number(n) => n.to_string(),
+                    Cell::Text(t) => csv_field(&t),
+                });
+                format!("{}\n", cells.collect::<Vec<String>>().join(","))
+            }
+            ExportFormat::Ndjson => {
+                let mut object = serde_json::Map::new();
+                for column in self.columns.iter() {
+                    let json = match cell(column) {
+                        Cell::Number(n) => serde_json::Value::from(n),
+                        Cell::Text(t) => serde_json::Value::from(t),
+                    };
+                    object.insert(column.name().to_string(), json);
+                }
+                format!("{}\n", serde_json::Value::Object(object))
+            }
+        }
+    }
+}
+
+/// Value in `units`, rounded to two decimals once converted
+fn convert(value: &SensorValueType, units: Units) -> (Cell, &'static str) {
+    let number = match value.number() {
+        Some(n) => n,
+        None => return (Cell::Text(value.state()), value.unit()),
+    };
+    let imperial = match value {
+        _ if units == Units::Metric => None,
+        SensorValueType::Temperature(_) => Some((number * 1.8 + 32.0, "°F")),
+        SensorValueType::Pressure(_) => Some((number * INHG_PER_HPA, "inHg")),
+        SensorValueType::RainTotal(_) => Some((number / MM_PER_INCH, "in")),
+        SensorValueType::RainRate(_) => Some((number / MM_PER_INCH, "in/h")),
+        SensorValueType::WindSpeed(_) => Some((number / KM_PER_MILE, "mph")),
+        _ => None,
+    };
+    match imperial {
+        Some((converted, unit)) => (Cell::Number((converted * 100.0).round() / 100.0), unit),
+        None => (Cell::Number(number), value.unit()),
+    }
+}
+
+/// quoted when it holds a separator, a quote or a new line
+fn csv_field(value: &str) -> String {
+    match value.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::sensor::SensorRepository;
+    use crate::domain::sensor_value_type::{BatteryLevel, Temperature, ValueType};
+    use chrono::TimeZone;
+
+    fn at(minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2020, 9, 12, 10, minute, 0).unwrap()
+    }
+
+    fn repo() -> SensorRepository {
+        let mut repo = SensorRepository::new();
+        for (minute, temperature) in &[(0, 20.0), (10, 25.5)] {
+            repo.add_value(SensorValue {
+                id: SensorIdentifier::new("1a2b", "oregon", "temperature"),
+                timestamp: at(*minute),
+                value: SensorValueType::Temperature(Temperature::create(*temperature).unwrap()),
+            });
+        }
+        repo.add_value(SensorValue {
+            id: SensorIdentifier::new("ff,01", "lacrosse", "battery"),
+            timestamp: at(5),
+            value: SensorValueType::Battery(BatteryLevel::Low),
+        });
+        repo
+    }
+
+    fn exported(export: &Export) -> String {
+        let repo = repo();
+        let values = repo
+            .sensors()
+            .iter()
+            .flat_map(|s| s.values())
+            .filter(|v| export.accepts(v))
+            .cloned()
+            .collect::<Vec<SensorValue>>();
+        export.header() + &export.rows(&values)
+    }
+
+    #[test]
+    fn csv_of_every_sensor() {
+        assert_eq!(
+            exported(&Export::default()),
+            "timestamp,protocol,probe_id,value_name,value,unit\n\
+             2020-09-12T10:00:00+00:00,oregon,1a2b,temperature,20,°C\n\
+             2020-09-12T10:10:00+00:00,oregon,1a2b,temperature,25.5,°C\n\
+             2020-09-12T10:05:00+00:00,lacrosse,\"ff,01\",battery,LOW,\n"
+        );
+    }
+
+    #[test]
+    fn selected_columns_range_and_units() {
+        let export = Export {
+            protocols: vec!["oregon".to_string()],
+            from: Some(at(5)),
+            format: ExportFormat::Ndjson,
+            columns: parse_columns("timestamp,value,unit").unwrap(),
+            units: Units::Imperial,
+            ..Export::default()
+        };
+        assert_eq!(
+            exported(&export),
+            "{\"timestamp\":\"2020-09-12T10:10:00+00:00\",\"unit\":\"°F\",\"value\":77.9}\n"
+        );
+    }
+
+    #[test]
+    fn parse_options() {
+        assert_eq!("jsonl".parse::<ExportFormat>(), Ok(ExportFormat::Ndjson));
+        assert!("xml".parse::<ExportFormat>().is_err());
+        assert!(parse_columns("timestamp,color").is_err());
+        assert!("kelvin".parse::<Units>().is_err());
+    }
+}
@@ -1,37 +1,334 @@
+use crate::domain::command_event::Event;
 use crate::domain::errors::*;
-use serde::Serialize;
+use crate::domain::message_schema::WireMessage;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
 
+#[derive(Clone)]
 pub struct ExternalMessage {
     pub routing_key: String,
+    /// source of the message, for the senders publishing it their own way
+    pub event: Event,
     pub inner_message: String
 }
 
-#[derive(Serialize)]
-pub struct InnerMessage<T> where T: Serialize {
-    node_name: String,
-    timestamp: chrono::NaiveDateTime,
-    title: String,
-    inner: T
+/// Routing keys are templates, `{name}` being replaced by a field of the event.
+#[derive(Clone)]
+pub struct MessageSettings {
+    pub node_name: String,
+    pub sensor_routing_key: String,
+    pub switch_routing_key: String,
+    pub unknown_routing_key: String
 }
 
+pub const SENSOR_PLACEHOLDERS: &[&str] = &["node_name", "protocol", "probe_id", "value_name"];
+pub const SWITCH_PLACEHOLDERS: &[&str] = &["node_name", "protocol", "device_id", "switch"];
+pub const UNKNOWN_PLACEHOLDERS: &[&str] = &["node_name"];
+
 pub trait MessageSender {
     fn send(&self,msg: ExternalMessage) -> Result<()>;
+    /// messages accepted but not delivered yet
+    fn pending(&self) -> usize {
+        0
+    }
+    /// write the messages kept on disk, before the process stops
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+    /// state of every named sink behind this sender
+    fn sinks(&self) -> Vec<SinkState> {
+        vec![]
+    }
+    /// connection to the destination, always up for the senders without one
+    fn connection(&self) -> SinkConnection {
+        SinkConnection {
+            connected: true,
+            last_error: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SinkState {
+    pub name: String,
+    /// messages accepted but not delivered yet
+    pub pending: usize,
+    #[serde(flatten)]
+    pub connection: SinkConnection,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SinkConnection {
+    pub connected: bool,
+    /// kept once connected again, the cause of the last outage
+    pub last_error: Option<String>,
+}
+
+/// Selection of the messages given to a sender, an empty list accepts everything.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MessageFilter {
+    /// message titles (SensorValueChanged, SwitchCommandReceived, SensorUnknowDataReceived, SensorReset)
+    pub titles: Vec<String>,
+    pub protocols: Vec<String>,
+    /// probe id of a sensor or device id of a switch
+    pub sensors: Vec<String>,
+    /// measure names (temperature, humidity, ...), switches never match
+    pub value_names: Vec<String>,
+}
+
+fn accepts(list: &[String], value: Option<&str>) -> bool {
+    list.is_empty() || value.is_some_and(|v| list.iter().any(|l| l == v))
+}
+
+impl MessageFilter {
+    pub fn matches(&self, event: &Event) -> bool {
+        let (protocol, sensor, value_name) = match event {
+            Event::ValueChanged(value) => (
+                Some(value.id.protocol.as_str()),
+                Some(value.id.probe_id.as_str()),
+                Some(value.id.probe_value_name.as_str()),
+            ),
+            Event::SwitchCommandReceived(switch) => (
+                Some(switch.protocol.as_str()),
+                Some(switch.device_id.as_str()),
+                None,
+            ),
+            Event::UnknowDataReceived(_) => (None, None, None),
+            Event::SensorReset(id) => (
+                Some(id.protocol.as_str()),
+                Some(id.probe_id.as_str()),
+                Some(id.probe_value_name.as_str()),
+            ),
+        };
+        accepts(&self.titles, Some(title(event)))
+            && accepts(&self.protocols, protocol)
+            && accepts(&self.sensors, sensor)
+            && accepts(&self.value_names, value_name)
+    }
 }
 
-pub fn get_external_message<T>(msg_title:String,value: &T) -> Result<ExternalMessage> where T: Serialize {
-    let inner = InnerMessage{
-        node_name: "ayasha_rflink".to_string(),
-        timestamp: chrono::Local::now().naive_local(),
-        title: msg_title,
-        inner: value
+pub struct FilteredSender {
+    pub name: String,
+    pub filter: MessageFilter,
+    pub sender: Box<dyn MessageSender + Send>,
+}
+
+/// Fan-out of every message to several senders, each one with its own filter.
+/// A failing sender is reported and never prevents the others from receiving the message.
+pub struct CompositeSender {
+    senders: Vec<FilteredSender>,
+}
+
+impl CompositeSender {
+    pub fn new(senders: Vec<FilteredSender>) -> CompositeSender {
+        CompositeSender { senders }
+    }
+
+    pub fn add(&mut self, sender: FilteredSender) {
+        self.senders.push(sender);
+    }
+}
+
+impl MessageSender for CompositeSender {
+    fn send(&self, msg: ExternalMessage) -> Result<()> {
+        for sink in self.senders.iter().filter(|s| s.filter.matches(&msg.event)) {
+            if let Err(e) = sink.sender.send(msg.clone()) {
+                println!("error during message sending to {}: {}", sink.name, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn pending(&self) -> usize {
+        self.senders.iter().map(|s| s.sender.pending()).sum()
+    }
+
+    fn flush(&self) -> Result<()> {
+        for sink in self.senders.iter() {
+            if let Err(e) = sink.sender.flush() {
+                println!("error during flush of {}: {}", sink.name, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn sinks(&self) -> Vec<SinkState> {
+        self.senders
+            .iter()
+            .map(|s| SinkState {
+                name: s.name.clone(),
+                pending: s.sender.pending(),
+                connection: s.sender.connection(),
+            })
+            .collect()
+    }
+}
+
+pub fn title(event: &Event) -> &'static str {
+    match event {
+        Event::ValueChanged(_) => "SensorValueChanged",
+        Event::SwitchCommandReceived(_) => "SwitchCommandReceived",
+        Event::UnknowDataReceived(_) => "SensorUnknowDataReceived",
+        Event::SensorReset(_) => "SensorReset",
+    }
+}
+
+/// First placeholder of `template` not in `known`.
+pub fn unknown_placeholder<'a>(template: &'a str, known: &[&str]) -> Option<&'a str> {
+    placeholders(template).find(|name| !known.contains(name))
+}
+
+fn placeholders(template: &str) -> impl Iterator<Item = &str> {
+    template
+        .split('{')
+        .skip(1)
+        .filter_map(|part| part.find('}').map(|end| &part[..end]))
+}
+
+/// Routing key of `event` built from `template`. The dots and wildcards of the
+/// values are replaced so that every value stays a single word of the key.
+pub fn routing_key(template: &str, node_name: &str, event: &Event) -> String {
+    let field = |name: &str| -> Option<&str> {
+        match (name, event) {
+            ("node_name", _) => Some(node_name),
+            ("protocol", Event::ValueChanged(value)) => Some(&value.id.protocol),
+            ("probe_id", Event::ValueChanged(value)) => Some(&value.id.probe_id),
+            ("value_name", Event::ValueChanged(value)) => Some(&value.id.probe_value_name),
+            ("protocol", Event::SensorReset(id)) => Some(&id.protocol),
+            ("probe_id", Event::SensorReset(id)) => Some(&id.probe_id),
+            ("value_name", Event::SensorReset(id)) => Some(&id.probe_value_name),
+            ("protocol", Event::SwitchCommandReceived(switch)) => Some(&switch.protocol),
+            ("device_id", Event::SwitchCommandReceived(switch)) => Some(&switch.device_id),
+            ("switch", Event::SwitchCommandReceived(switch)) => Some(&switch.switch),
+            _ => None,
+        }
     };
+    let mut key = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        key.push_str(&rest[..start]);
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let value = field(&rest[start + 1..end]).unwrap_or("unknown");
+        key.extend(value.chars().map(|c| match c {
+            '.' | '*' | '#' | ' ' => '_',
+            c => c,
+        }));
+        rest = &rest[end + 1..];
+    }
+    key.push_str(rest);
+    key
+}
+
+/// `timestamp` is the publication time of the message.
+pub fn get_external_message(
+    node_name: &str,
+    routing_key: &str,
+    event: &Event,
+    timestamp: DateTime<Utc>,
+) -> Result<ExternalMessage> {
+    let message = WireMessage::from_event(node_name, timestamp, event);
+    let inner_message = serde_json::to_string(&message).context(DataFormatingError)?;
 
     let msg = ExternalMessage{
-        routing_key: "atmosSensor".to_string(),
-        inner_message: serde_json::to_string(&inner).context(DataFormatingError)?
+        routing_key: routing_key.to_string(),
+        event: event.clone(),
+        inner_message
     };
 
     Ok(msg)
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::raw_frame::RawFrame;
+    use crate::domain::sensor::SensorValue;
+    use crate::domain::sensor_identifier::SensorIdentifier;
+    use crate::domain::sensor_value_type::{Humidity, SensorValueType, ValueType};
+    use std::sync::{Arc, Mutex};
+
+    struct Recorder {
+        received: Arc<Mutex<Vec<String>>>,
+        fail: bool,
+    }
+
+    impl MessageSender for Recorder {
+        fn send(&self, msg: ExternalMessage) -> Result<()> {
+            if self.fail {
+                return Err(DomainError::DataExtractionError { value: "down".to_string() });
+            }
+            self.received.lock().unwrap().push(msg.routing_key);
+            Ok(())
+        }
+    }
+
+    fn humidity(protocol: &str) -> Event {
+        Event::ValueChanged(SensorValue {
+            id: SensorIdentifier::new("1a2b", protocol, "humidity"),
+            timestamp: chrono::Utc::now(),
+            value: SensorValueType::Humidity(Humidity::create(40).unwrap()),
+        })
+    }
+
+    fn sink(name: &str, filter: MessageFilter, fail: bool, received: &Arc<Mutex<Vec<String>>>) -> FilteredSender {
+        FilteredSender {
+            name: name.to_string(),
+            filter,
+            sender: Box::new(Recorder { received: received.clone(), fail }),
+        }
+    }
+
+    #[test]
+    fn routing_key_from_template() {
+        let template = "sensor.{protocol}.{probe_id}.{value_name}";
+        assert_eq!(routing_key(template, "node", &humidity("oregon")), "sensor.oregon.1a2b.humidity");
+        let unknown = Event::UnknowDataReceived(RawFrame::new("20;01;x;", chrono::Utc::now()));
+        assert_eq!(routing_key("sensor.unknown", "node", &unknown), "sensor.unknown");
+        assert_eq!(routing_key("{node_name}.{probe_id}", "my.node", &unknown), "my_node.unknown");
+    }
+
+    #[test]
+    fn placeholders_are_checked() {
+        assert_eq!(unknown_placeholder("sensor.{protocol}.{probe_id}", SENSOR_PLACEHOLDERS), None);
+        assert_eq!(unknown_placeholder("switch.{probe_id}", SWITCH_PLACEHOLDERS), Some("probe_id"));
+    }
+
+    #[test]
+    fn filter_on_title_and_protocol() {
+        let filter = MessageFilter {
+            titles: vec!["SensorValueChanged".to_string()],
+            protocols: vec!["oregon".to_string()],
+            ..MessageFilter::default()
+        };
+        assert!(filter.matches(&humidity("oregon")));
+        assert!(!filter.matches(&humidity("lacrosse")));
+        assert!(!filter.matches(&Event::UnknowDataReceived(RawFrame::new("20;01;x;", chrono::Utc::now()))));
+        assert!(MessageFilter::default().matches(&Event::UnknowDataReceived(RawFrame::new("20;01;x;", chrono::Utc::now()))));
+    }
+
+    #[test]
+    fn failing_sink_does_not_block_the_others() {
+        let received = Arc::new(Mutex::new(vec![]));
+        let only_lacrosse = MessageFilter {
+            protocols: vec!["lacrosse".to_string()],
+            ..MessageFilter::default()
+        };
+        let composite = CompositeSender::new(vec![
+            sink("broken", MessageFilter::default(), true, &received),
+            sink("lacrosse", only_lacrosse, false, &received),
+            sink("all", MessageFilter::default(), false, &received),
+        ]);
+
+        composite.send(get_external_message("node", "first", &humidity("oregon"), chrono::Utc::now()).unwrap()).unwrap();
+        composite.send(get_external_message("node", "second", &humidity("lacrosse"), chrono::Utc::now()).unwrap()).unwrap();
+        assert_eq!(*received.lock().unwrap(), vec!["first", "second", "second"]);
+        let names = composite.sinks().into_iter().map(|s| s.name).collect::<Vec<String>>();
+        assert_eq!(names, vec!["broken", "lacrosse", "all"]);
+    }
+}
@@ -1,7 +1,9 @@
 use crate::domain::lacrosse_v3_protocol::LaCrosseData;
 use crate::domain::oregon_temp_protocol::OregonTempData;
 use crate::domain::raw_frame::RawFrame;
+use crate::domain::rflink_protocol::RfLinkData;
 use crate::domain::sensor::SensorValue;
+use crate::domain::switch_event::SwitchEvent;
 use crate::domain::errors::*;
 
 use snafu::ResultExt;
@@ -10,6 +12,7 @@ use snafu::ResultExt;
 pub enum Frame {
     LaCrosseV3(LaCrosseData),
     OregonSc(OregonTempData),
+    RfLink(RfLinkData),
     Unknow(RawFrame),
 }
 
@@ -24,17 +27,40 @@ impl Frame {
                 OregonTempData::from_raw(&r)
                 .and_then(|r| Ok(Frame::OregonSc(r)))
                 .context(InternalOregonError),
+            r if crate::domain::rflink_protocol::is_valid_raw(r) =>
+                RfLinkData::from_raw(r)
+                .map(Frame::RfLink)
+                .context(InternalRfLinkError),
             _ => Ok(Frame::Unknow(raw.clone())),
         }
     }
 
+    /// Protocol of the decoded frame, as written in the identifiers of its sensors,
+    /// `None` for an unknown frame.
+    pub fn protocol(&self) -> Option<String> {
+        match self {
+            Frame::Unknow(_) => None,
+            Frame::LaCrosseV3(_) => Some(LaCrosseData::get_protocol()),
+            Frame::OregonSc(_) => Some(OregonTempData::get_protocol()),
+            Frame::RfLink(f) => Some(f.get_protocol()),
+        }
+    }
+
     pub fn obtain_sensor_values(&self) -> Vec<SensorValue> {
         match self {
             Frame::Unknow(_) => vec![],
             Frame::LaCrosseV3(f) => f.to_sensors_values(),
-            Frame::OregonSc(f) => f.to_sensors_values() 
+            Frame::OregonSc(f) => f.to_sensors_values(),
+            Frame::RfLink(f) => f.to_sensors_values()
         }
-    } 
+    }
+
+    pub fn obtain_switch_event(&self) -> Option<SwitchEvent> {
+        match self {
+            Frame::RfLink(f) => f.to_switch_event(),
+            _ => None
+        }
+    }
 }
 
 #[cfg(test)]
@@ -43,7 +69,7 @@ mod test {
 
     #[test]
     fn create_frame_normal() {
-        let data = RawFrame::new("0;10;40");
+        let data = RawFrame::new("0;10;40", chrono::Utc::now());
         let frame = Frame::decrypt_raw(&data);
 
         match frame {
@@ -56,7 +82,7 @@ mod test {
     }
     #[test]
     fn create_frame_lacrosse() {
-        let data = RawFrame::new("test;test;DEBUG;Pulses=511");
+        let data = RawFrame::new("test;test;DEBUG;Pulses=511", chrono::Utc::now());
         let frame = Frame::decrypt_raw(&data);
 
         match frame {
@@ -67,4 +93,33 @@ mod test {
             }
         }
     }
+    #[test]
+    fn create_frame_rflink() {
+        let data = RawFrame::new("20;2D;Alecto V1;ID=0334;TEMP=00d4;HUM=53;BAT=OK;", chrono::Utc::now());
+        let frame = Frame::decrypt_raw(&data).unwrap();
+
+        match &frame {
+            Frame::RfLink(f) => assert_eq!(f.protocol, "Alecto V1"),
+            _ => panic!("frame should be rflink")
+        }
+        assert_eq!(frame.obtain_sensor_values().len(), 3);
+    }
+    #[test]
+    fn create_frame_oregon_keep_dedicated_decoder() {
+        let data = RawFrame::new("20;03;Oregon Temp;ID=0410;TEMP=0153;BAT=OK;", chrono::Utc::now());
+        let frame = Frame::decrypt_raw(&data).unwrap();
+
+        match frame {
+            Frame::OregonSc(_) => (),
+            _ => panic!("frame should be oregon")
+        }
+    }
+    #[test]
+    fn protocol_of_the_sensors() {
+        let data = RawFrame::new("20;03;Oregon Temp;ID=0410;TEMP=0153;BAT=OK;", chrono::Utc::now());
+        let frame = Frame::decrypt_raw(&data).unwrap();
+
+        let protocol = frame.protocol().unwrap();
+        assert!(frame.obtain_sensor_values().iter().all(|v| v.id.protocol == protocol));
+    }
 }
@@ -0,0 +1,210 @@
+use crate::domain::command_event::{Command, Event};
+use crate::domain::errors::*;
+use crate::domain::sensor::{SensorRepository, SensorValue};
+use crate::domain::switch_event::SwitchEvent;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::io::{BufRead, Write};
+
+/// Format version written in every journal entry and snapshot,
+/// to be increased on any incompatible change of `Event` or of the snapshot.
+pub const JOURNAL_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub version: u32,
+    /// position of the event in the journal, starting at 1
+    pub sequence: u64,
+    pub event: Event,
+}
+
+/// First line of a snapshot, followed by every value of the repository, one per line.
+/// The snapshot holds the state once every event up to `sequence` is applied.
+#[derive(Serialize, Deserialize)]
+pub struct SnapshotHeader {
+    pub version: u32,
+    pub sequence: u64,
+    pub last_switch_events: Vec<SwitchEvent>,
+}
+
+/// Durable and ordered log of every event applied to the repository.
+pub trait EventJournal: Send {
+    fn append(&mut self, events: &[Event]) -> Result<()>;
+    /// fill `repo` with the last snapshot and give the events journaled after it, oldest first
+    fn load(&mut self, repo: &mut SensorRepository) -> Result<Vec<Event>>;
+    /// sequence of the last journaled event, 0 when empty
+    fn last_sequence(&self) -> u64;
+    /// true when enough events were journaled since the last snapshot
+    fn needs_snapshot(&self) -> bool;
+    /// store the snapshot of `repo`, holding every journaled event, and forget those events
+    fn save_snapshot(&mut self, repo: &SensorRepository) -> Result<()>;
+    /// write the journaled events to disk, when they are written without sync
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub fn check_version(version: u32) -> Result<()> {
+    match version {
+        JOURNAL_VERSION => Ok(()),
+        v => Err(DomainError::JournalVersionError { version: v }),
+    }
+}
+
+/// `repo` filled from the last snapshot of `journal`, along with the replay
+/// of the events journaled since.
+pub fn restore(journal: &mut dyn EventJournal, mut repo: SensorRepository) -> Result<(SensorRepository, Command)> {
+    let events = journal.load(&mut repo)?;
+    Ok((repo, Command::Rejeu(events)))
+}
+
+/// Write the snapshot of `repo` at `sequence`: its header, then every value,
+/// sensor after sensor, oldest first. Nothing is copied, the values are written as they are read.
+pub fn write_snapshot(repo: &SensorRepository, sequence: u64, out: &mut dyn Write) -> Result<()> {
+    let header = SnapshotHeader {
+        version: JOURNAL_VERSION,
+        sequence,
+        last_switch_events: repo.last_switch_events().to_vec(),
+    };
+    write_line(&header, out)?;
+    for value in repo.sensors().iter().flat_map(|s| s.values()) {
+        write_line(value, out)?;
+    }
+    out.flush().context(StorageError)
+}
+
+fn write_line<T: Serialize>(value: &T, out: &mut dyn Write) -> Result<()> {
+    serde_json::to_writer(&mut *out, value).context(DataFormatingError)?;
+    out.write_all(b"\n").context(StorageError)
+}
+
+/// Read the first line of a snapshot, refused when written in another format version.
+pub fn read_snapshot_header(input: &mut dyn BufRead) -> Result<SnapshotHeader> {
+    let mut line = String::new();
+    input.read_line(&mut line).context(StorageError)?;
+    let header: SnapshotHeader = serde_json::from_str(&line).context(DataFormatingError)?;
+    check_version(header.version)?;
+    Ok(header)
+}
+
+/// Read a snapshot one line at a time, handing its values to `visit` in the written order.
+pub fn read_snapshot_values(
+    input: &mut dyn BufRead,
+    visit: &mut dyn FnMut(SensorValue) -> Result<()>,
+) -> Result<SnapshotHeader> {
+    let header = read_snapshot_header(input)?;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if input.read_line(&mut line).context(StorageError)? == 0 {
+            return Ok(header);
+        }
+        visit(serde_json::from_str(&line).context(DataFormatingError)?)?;
+    }
+}
+
+/// Fill `repo` with a snapshot, gives its sequence.
+pub fn read_snapshot(input: &mut dyn BufRead, repo: &mut SensorRepository) -> Result<u64> {
+    let header = read_snapshot_values(input, &mut |value| {
+        repo.add_value(value);
+        Ok(())
+    })?;
+    for event in header.last_switch_events {
+        repo.add_switch_event(event);
+    }
+    Ok(header.sequence)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::sensor::SensorValue;
+    use crate::domain::sensor_identifier::SensorIdentifier;
+    use crate::domain::sensor_value_type::{SensorValueType, Temperature, ValueType};
+    use crate::domain::clock::SystemClock;
+    use crate::domain::{apply, dispatch};
+
+    struct MemoryJournal {
+        snapshot: Option<Vec<u8>>,
+        entries: Vec<String>,
+    }
+
+    impl EventJournal for MemoryJournal {
+        fn append(&mut self, events: &[Event]) -> Result<()> {
+            for event in events {
+                self.entries.push(serde_json::to_string(event).unwrap());
+            }
+            Ok(())
+        }
+        fn load(&mut self, repo: &mut SensorRepository) -> Result<Vec<Event>> {
+            if let Some(snapshot) = &self.snapshot {
+                read_snapshot(&mut snapshot.as_slice(), repo)?;
+            }
+            Ok(self.entries.iter().map(|e| serde_json::from_str(e).unwrap()).collect())
+        }
+        fn last_sequence(&self) -> u64 {
+            self.entries.len() as u64
+        }
+        fn needs_snapshot(&self) -> bool {
+            false
+        }
+        fn save_snapshot(&mut self, repo: &SensorRepository) -> Result<()> {
+            let mut snapshot = vec![];
+            write_snapshot(repo, self.last_sequence(), &mut snapshot)?;
+            self.snapshot = Some(snapshot);
+            self.entries.clear();
+            Ok(())
+        }
+    }
+
+    fn temperature(value: f64) -> Event {
+        Event::ValueChanged(SensorValue {
+            id: SensorIdentifier::new("probeid", "oregon", "temperature"),
+            timestamp: chrono::Utc::now(),
+            value: SensorValueType::Temperature(Temperature::create(value).unwrap()),
+        })
+    }
+
+    #[test]
+    fn replay_after_snapshot() {
+        let id = SensorIdentifier::new("probeid", "oregon", "temperature");
+        let mut journal = MemoryJournal { snapshot: None, entries: vec![] };
+        let mut repo = SensorRepository::new();
+
+        journal.append(&[temperature(10.0)]).unwrap();
+        apply(vec![temperature(10.0)], &mut repo);
+        journal.save_snapshot(&repo).unwrap();
+        journal.append(&[temperature(12.0)]).unwrap();
+
+        let (mut restored, replay) = restore(&mut journal, SensorRepository::new()).unwrap();
+        let events = dispatch(replay, &restored, &SystemClock).unwrap();
+        apply(events, &mut restored);
+
+        let sensor = restored.extract_sensor(&id).unwrap();
+        assert_eq!(sensor.values().len(), 2);
+        assert_eq!(
+            sensor.get_last().unwrap().value,
+            SensorValueType::Temperature(Temperature::create(12.0).unwrap())
+        );
+    }
+
+    #[test]
+    fn snapshot_keeps_every_value() {
+        let mut repo = SensorRepository::new();
+        let values = (0..50).map(|i| temperature(i as f64 / 10.0)).collect::<Vec<Event>>();
+        apply(values, &mut repo);
+        let mut snapshot = vec![];
+        write_snapshot(&repo, 50, &mut snapshot).unwrap();
+
+        let mut restored = SensorRepository::new();
+        assert_eq!(read_snapshot(&mut snapshot.as_slice(), &mut restored).unwrap(), 50);
+        assert_eq!(restored.sensors()[0].values().len(), 50);
+    }
+
+    #[test]
+    fn other_version_is_refused() {
+        assert!(check_version(JOURNAL_VERSION).is_ok());
+        assert!(check_version(JOURNAL_VERSION + 1).is_err());
+        assert!(check_version(0).is_err());
+    }
+}
@@ -2,7 +2,7 @@ use crate::domain::raw_frame::RawFrame;
 use crate::domain::sensor::SensorValue;
 use crate::domain::sensor_identifier::SensorIdentifier;
 use crate::domain::sensor_value_type::{Humidity, SensorValueType, Temperature, ValueType};
-use chrono::NaiveDateTime;
+use chrono::{DateTime, Utc};
 use snafu::ResultExt;
 
 use snafu::Snafu;
@@ -27,14 +27,14 @@ pub struct LaCrosseData {
     pub sensor_id: String,
     pub temperature: f64,
     pub humidity: u32,
-    pub timestamp: NaiveDateTime,
+    pub timestamp: DateTime<Utc>,
 }
 
 impl LaCrosseData {
     pub fn from_raw(raw: &RawFrame) -> Result<LaCrosseData> {
         decrypt(&raw)
     }
-    fn get_protocol() -> String {
+    pub fn get_protocol() -> String {
         "lacrosse_v3".to_string()
     }
     pub fn to_sensors_values(&self) -> Vec<SensorValue> {
@@ -192,7 +192,7 @@ mod test {
     fn is_valid_raw_empty() {
         let input = RawFrame {
             data: "".to_string(),
-            timestamp: chrono::Local::now().naive_local(),
+            timestamp: chrono::Utc::now(),
         };
 
         let result = is_valid_raw(&input);
@@ -202,7 +202,7 @@ mod test {
     fn is_valid_raw_unsplitable() {
         let input = RawFrame {
             data: "I am a non sense string".to_string(),
-            timestamp: chrono::Local::now().naive_local(),
+            timestamp: chrono::Utc::now(),
         };
 
         let result = is_valid_raw(&input);
@@ -212,7 +212,7 @@ mod test {
     fn is_valid_raw_not_enought_sections() {
         let input = RawFrame {
             data: "test;test;0".to_string(),
-            timestamp: chrono::Local::now().naive_local(),
+            timestamp: chrono::Utc::now(),
         };
 
         let result = is_valid_raw(&input);
@@ -222,7 +222,7 @@ mod test {
     fn is_valid_raw_not_debug() {
         let input = RawFrame {
             data: "test;test;NOTDEBUG;Pulses=511;".to_string(),
-            timestamp: chrono::Local::now().naive_local(),
+            timestamp: chrono::Utc::now(),
         };
 
         let result = is_valid_raw(&input);
@@ -232,7 +232,7 @@ mod test {
     fn is_valid_raw_not_have_511_pulses() {
         let input = RawFrame {
             data: "test;test;DEBUG;Pulses=521;".to_string(),
-            timestamp: chrono::Local::now().naive_local(),
+            timestamp: chrono::Utc::now(),
         };
 
         let result = is_valid_raw(&input);
@@ -242,7 +242,7 @@ mod test {
     fn is_valid_raw_ok() {
         let input = RawFrame {
             data: "test;test;DEBUG;Pulses=511;".to_string(),
-            timestamp: chrono::Local::now().naive_local(),
+            timestamp: chrono::Utc::now(),
         };
 
         let result = is_valid_raw(&input);
@@ -254,7 +254,7 @@ mod test {
             sensor_id: "0".to_string(),
             temperature: 35.2,
             humidity: 35,
-            timestamp: chrono::Local::now().naive_local()
+            timestamp: chrono::Utc::now()
         };
         let result = data.to_sensors_values();
         assert_eq!(result.len(), 2);
@@ -267,7 +267,7 @@ mod test {
             sensor_id: "0".to_string(),
             temperature: -50.0,
             humidity: 35,
-            timestamp: chrono::Local::now().naive_local()
+            timestamp: chrono::Utc::now()
         };
         let result = data.to_sensors_values();
         assert_eq!(result.len(), 1);
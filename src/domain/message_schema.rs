@@ -0,0 +1,262 @@
+//! Wire format of the published messages, kept apart from the domain structs
+//! so that a refactoring of the domain never changes what consumers receive.
+//! Any incompatible change must increase `SCHEMA_VERSION` and regenerate
+//! `schema/messages.schema.json` (`ayasha_rf --print-schema`).
+use crate::domain::command_event::Event;
+use crate::domain::sensor::SensorValue;
+use crate::domain::sensor_identifier::SensorIdentifier;
+use crate::domain::sensor_value_type::SensorValueType;
+use crate::domain::switch_event::SwitchEvent;
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Message published for every event, `title` giving the type of `inner`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct WireMessage {
+    pub schema_version: u32,
+    pub node_name: String,
+    /// publication time, RFC 3339 in UTC
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub body: WireBody,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "title", content = "inner")]
+pub enum WireBody {
+    SensorValueChanged(SensorValueMessage),
+    SwitchCommandReceived(SwitchMessage),
+    SensorUnknowDataReceived(UnknownDataMessage),
+    SensorReset(SensorMessage),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum MeasureKind {
+    Temperature,
+    Humidity,
+    Pressure,
+    RainTotal,
+    RainRate,
+    WindSpeed,
+    WindDirection,
+    UvIntensity,
+    Illuminance,
+    Power,
+    Energy,
+    Voltage,
+    Current,
+    Battery,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum MeasureValue {
+    Number(f64),
+    /// battery level: OK or LOW
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct SensorValueMessage {
+    pub protocol: String,
+    pub probe_id: String,
+    pub value_name: String,
+    /// reception time of the frame, RFC 3339 in UTC
+    pub timestamp: DateTime<Utc>,
+    pub kind: MeasureKind,
+    pub value: MeasureValue,
+    /// absent for the battery level and the raw UV intensity
+    pub unit: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct SwitchMessage {
+    pub protocol: String,
+    pub device_id: String,
+    pub switch: String,
+    /// ON, OFF, ALLON, ALLOFF, SET_LEVEL, UP, DOWN, STOP or the raw RFLink command
+    pub command: String,
+    /// dim level 0..15 of SET_LEVEL
+    pub level: Option<u32>,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct UnknownDataMessage {
+    /// line received from the RFLink
+    pub data: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct SensorMessage {
+    pub protocol: String,
+    pub probe_id: String,
+    pub value_name: String,
+}
+
+impl WireMessage {
+    pub fn from_event(node_name: &str, timestamp: DateTime<Utc>, event: &Event) -> WireMessage {
+        let body = match event {
+            Event::ValueChanged(value) => WireBody::SensorValueChanged(SensorValueMessage::from(value)),
+            Event::SwitchCommandReceived(switch) => WireBody::SwitchCommandReceived(SwitchMessage::from(switch)),
+            Event::UnknowDataReceived(raw) => WireBody::SensorUnknowDataReceived(UnknownDataMessage {
+                data: raw.data.clone(),
+                timestamp: raw.timestamp,
+            }),
+            Event::SensorReset(id) => WireBody::SensorReset(SensorMessage::from(id)),
+        };
+        WireMessage {
+            schema_version: SCHEMA_VERSION,
+            node_name: node_name.to_string(),
+            timestamp,
+            body,
+        }
+    }
+}
+
+impl From<&SensorValueType> for MeasureKind {
+    fn from(value: &SensorValueType) -> MeasureKind {
+        match value {
+            SensorValueType::Temperature(_) => MeasureKind::Temperature,
+            SensorValueType::Humidity(_) => MeasureKind::Humidity,
+            SensorValueType::Pressure(_) => MeasureKind::Pressure,
+            SensorValueType::RainTotal(_) => MeasureKind::RainTotal,
+            SensorValueType::RainRate(_) => MeasureKind::RainRate,
+            SensorValueType::WindSpeed(_) => MeasureKind::WindSpeed,
+            SensorValueType::WindDirection(_) => MeasureKind::WindDirection,
+            SensorValueType::UvIntensity(_) => MeasureKind::UvIntensity,
+            SensorValueType::Illuminance(_) => MeasureKind::Illuminance,
+            SensorValueType::Power(_) => MeasureKind::Power,
+            SensorValueType::Energy(_) => MeasureKind::Energy,
+            SensorValueType::Voltage(_) => MeasureKind::Voltage,
+            SensorValueType::Current(_) => MeasureKind::Current,
+            SensorValueType::Battery(_) => MeasureKind::Battery,
+        }
+    }
+}
+
+impl From<&SensorValue> for SensorValueMessage {
+    fn from(value: &SensorValue) -> SensorValueMessage {
+        let (measure, unit) = match value.value.number() {
+            Some(n) => (MeasureValue::Number(n), Some(value.value.unit()).filter(|u| !u.is_empty()).map(str::to_string)),
+            None => (MeasureValue::Text(value.value.state()), None),
+        };
+        SensorValueMessage {
+            protocol: value.id.protocol.clone(),
+            probe_id: value.id.probe_id.clone(),
+            value_name: value.id.probe_value_name.clone(),
+            timestamp: value.timestamp,
+            kind: MeasureKind::from(&value.value),
+            value: measure,
+            unit,
+        }
+    }
+}
+
+impl From<&SwitchEvent> for SwitchMessage {
+    fn from(switch: &SwitchEvent) -> SwitchMessage {
+        let (command, level) = switch.command.to_wire();
+        SwitchMessage {
+            protocol: switch.protocol.clone(),
+            device_id: switch.device_id.clone(),
+            switch: switch.switch.clone(),
+            command,
+            level,
+            timestamp: switch.timestamp,
+        }
+    }
+}
+
+impl From<&SensorIdentifier> for SensorMessage {
+    fn from(id: &SensorIdentifier) -> SensorMessage {
+        SensorMessage {
+            protocol: id.protocol.clone(),
+            probe_id: id.probe_id.clone(),
+            value_name: id.probe_value_name.clone(),
+        }
+    }
+}
+
+/// JSON Schema document of `WireMessage`.
+pub fn json_schema() -> String {
+    let schema = schemars::schema_for!(WireMessage);
+    serde_json::to_string_pretty(&schema).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::external_message::title;
+    use crate::domain::raw_frame::RawFrame;
+    use crate::domain::sensor_value_type::{BatteryLevel, Temperature, ValueType};
+
+    fn value(value: SensorValueType) -> Event {
+        Event::ValueChanged(SensorValue {
+            id: SensorIdentifier::new("1a2b", "oregon", "temperature"),
+            timestamp: chrono::Utc::now(),
+            value,
+        })
+    }
+
+    fn round_trip(event: &Event) -> WireMessage {
+        let message = WireMessage::from_event("node", chrono::Utc::now(), event);
+        let json = serde_json::to_string(&message).unwrap();
+        let parsed: WireMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, message);
+        parsed
+    }
+
+    #[test]
+    fn sensor_value_round_trip() {
+        let message = round_trip(&value(SensorValueType::Temperature(Temperature::create(21.5).unwrap())));
+        assert_eq!(message.schema_version, SCHEMA_VERSION);
+        match message.body {
+            WireBody::SensorValueChanged(m) => {
+                assert_eq!(m.kind, MeasureKind::Temperature);
+                assert_eq!(m.value, MeasureValue::Number(21.5));
+                assert_eq!(m.unit.as_deref(), Some("°C"));
+            }
+            _ => panic!("sensor value expected"),
+        }
+        let battery = round_trip(&value(SensorValueType::Battery(BatteryLevel::Low)));
+        match battery.body {
+            WireBody::SensorValueChanged(m) => {
+                assert_eq!(m.value, MeasureValue::Text("LOW".to_string()));
+                assert_eq!(m.unit, None);
+            }
+            _ => panic!("sensor value expected"),
+        }
+    }
+
+    #[test]
+    fn unknown_data_round_trip() {
+        let event = Event::UnknowDataReceived(RawFrame::new("20;01;x;", chrono::Utc::now()));
+        match round_trip(&event).body {
+            WireBody::SensorUnknowDataReceived(m) => assert_eq!(m.data, "20;01;x;"),
+            _ => panic!("unknown data expected"),
+        }
+    }
+
+    #[test]
+    fn wire_layout() {
+        let event = value(SensorValueType::Temperature(Temperature::create(21.5).unwrap()));
+        let json = serde_json::to_value(WireMessage::from_event("node", chrono::Utc::now(), &event)).unwrap();
+        assert_eq!(json["schema_version"], 1);
+        assert_eq!(json["title"], title(&event));
+        assert_eq!(json["inner"]["probe_id"], "1a2b");
+        assert_eq!(json["inner"]["value"], 21.5);
+        let timestamp = json["timestamp"].as_str().unwrap();
+        assert!(DateTime::parse_from_rfc3339(timestamp).is_ok());
+    }
+
+    #[test]
+    fn schema_document_is_up_to_date() {
+        let document = include_str!("../../schema/messages.schema.json");
+        assert_eq!(document.trim_end(), json_schema(), "run ayasha_rf --print-schema > schema/messages.schema.json");
+    }
+}
@@ -1,80 +1,275 @@
 mod frame;
 mod lacrosse_v3_protocol;
 mod oregon_temp_protocol;
+mod rflink_field;
+mod rflink_protocol;
 
+pub mod clock;
 pub mod command_event;
 pub mod errors;
+pub mod export;
 pub mod raw_frame;
+pub mod remote_command;
+pub mod rf_command;
 pub mod sensor;
+pub mod switch_event;
 pub mod external_message;
-mod sensor_identifier;
-mod sensor_value_type;
+pub mod journal;
+pub mod query;
+pub mod message_schema;
+pub mod sensor_identifier;
+pub mod sensor_value_type;
 
 use snafu::ResultExt;
 
+use clock::Clock;
 use command_event::{Command, Event};
 use errors::*;
 use frame::Frame;
 use raw_frame::RawFrame;
 use sensor::SensorRepository;
-use external_message::MessageSender;
+use external_message::{MessageSender, MessageSettings};
+use rf_command::RfCommandSender;
+use switch_event::{SwitchEvent, SwitchFrames};
 
-pub fn dispatch(command: Command, repo: &SensorRepository) -> Result<Vec<Event>> {
+pub fn dispatch(command: Command, repo: &SensorRepository, clock: &dyn Clock) -> Result<Vec<Event>> {
     match command {
         Command::Rejeu(events) => Ok(events),
-        Command::IncomingData(input) => dispatch_input(&input, &repo),
-        Command::GetData(getter) => getter(&repo),
+        // without debounce window, a switch frame is compared to the last emitted event
+        Command::IncomingData(input) => dispatch_line(&input, repo, &SwitchFrames::default(), clock).map(|d| d.events),
+        Command::Query(query, reply) => {
+            let _ = reply.send(query::execute(&query, repo));
+            Ok(vec![])
+        }
+        Command::ResetSensor(id, reply) => match remote_command::reset_sensor(repo, id) {
+            Ok(events) => {
+                let _ = reply.send(Ok(()));
+                Ok(events)
+            }
+            Err(e) => {
+                let _ = reply.send(Err(e));
+                Ok(vec![])
+            }
+        },
+        Command::SendRf(_, reply) | Command::SetRfDebug(_, reply) => {
+            let _ = reply.send(Err(DomainError::RfLinkUnavailableError));
+            Ok(vec![])
+        }
     }
 }
 
-fn dispatch_input(data: &str, repo: &SensorRepository) -> Result<Vec<Event>> {
-    let raw = RawFrame::new(data);
-    let frame = Frame::decrypt_raw(&raw)?;
+/// Hand rf commands over to the RFLink link, every other command is dispatched.
+pub fn route(command: Command, rf_sender: &dyn RfCommandSender) -> Option<Command> {
+    match command {
+        // the line is built and checked by the sender
+        Command::SendRf(rf_command, reply) => {
+            rf_sender.send(rf_command, reply);
+            None
+        }
+        Command::SetRfDebug(enabled, reply) => {
+            rf_sender.send_line(rf_command::rf_debug_line(enabled), reply);
+            None
+        }
+        command => Some(command),
+    }
+}
+
+/// Events of an RFLink line and what the caller keeps of its frame.
+pub struct DispatchedLine {
+    /// protocol of the frame, `None` when unknown
+    pub protocol: Option<String>,
+    /// switch frame to record in the debounce window once the events are applied
+    pub switch_frame: Option<SwitchEvent>,
+    pub events: Vec<Event>,
+}
+
+/// Events of an RFLink line, switch frames being debounced against `frames`.
+pub fn dispatch_line(input: &str, repo: &SensorRepository, frames: &SwitchFrames, clock: &dyn Clock) -> Result<DispatchedLine> {
+    let frame = Frame::decrypt_raw(&RawFrame::new(input, clock.now()))?;
+    Ok(DispatchedLine {
+        protocol: frame.protocol(),
+        switch_frame: frame.obtain_switch_event(),
+        events: dispatch_input(frame, repo, frames)?,
+    })
+}
+
+fn dispatch_input(
+    frame: Frame,
+    repo: &SensorRepository,
+    frames: &SwitchFrames,
+) -> Result<Vec<Event>> {
     match frame {
         Frame::Unknow(raw) => Ok(vec![Event::UnknowDataReceived(raw)]),
         _ => {
-let sensors = frame.obtain_sensor_values();
-
-    sensors
-        .into_iter()
-        .map(|s| match repo.extract_sensor(&s.id) {
-            None => Some(Ok(Event::ValueChanged(s))),
-            Some(sensor) => sensor.get_last().and_then(|last| {
-                let to_be_insert = last
-                    .value
-                    .is_signifiant_variation(s.value.clone())
-                    .context(InvalidSensorValueError);
-                match to_be_insert {
-                    Err(e) => Some(Err(e)),
-                    Ok(b) => match b {
-                        false => None,
-                        true => Some(Ok(Event::ValueChanged(s))),
-                    },
-                }
-            }),
-        })
-        .filter_map(|e| e)
-        .collect()
+            let sensors = frame.obtain_sensor_values();
+
+            let switch_event = frame
+                .obtain_switch_event()
+                // the last emitted event stands for the previous frame when none was seen since the start
+                .filter(|ev| {
+                    match frames
+                        .previous(ev)
+                        .or_else(|| repo.get_last_switch_event(ev))
+                    {
+                        Some(previous) => !ev.is_repetition_of(previous),
+                        None => true,
+                    }
+                })
+                .map(|ev| Ok(Event::SwitchCommandReceived(ev)));
+
+            sensors
+                .into_iter()
+                .map(|s| match repo.extract_sensor(&s.id) {
+                    None => Some(Ok(Event::ValueChanged(s))),
+                    Some(sensor) => sensor.get_last().and_then(|last| {
+                        let to_be_insert = last
+                            .value
+                            .is_signifiant_variation(s.value.clone())
+                            .context(InvalidSensorValueError);
+                        match to_be_insert {
+                            Err(e) => Some(Err(e)),
+                            Ok(b) => match b {
+                                false => None,
+                                true => Some(Ok(Event::ValueChanged(s))),
+                            },
+                        }
+                    }),
+                })
+                .filter_map(|e| e)
+                .chain(switch_event)
+                .collect()
         }
     }
-    
 }
 
 pub fn apply(events: Vec<Event>, repo: &mut SensorRepository) {
     for ev in events {
         match ev {
             Event::ValueChanged(value) => repo.add_value(value),
+            Event::SwitchCommandReceived(switch) => repo.add_switch_event(switch),
             Event::UnknowDataReceived(_) => (),
+            Event::SensorReset(id) => repo.remove_sensor(&id),
         };
     }
 }
 
-pub fn send_external_message(events: Vec<Event>, sender: &dyn MessageSender) -> Result<Vec<Event>> {
- events.into_iter().map(|ev|{
-        match &ev {
-            Event::ValueChanged(value) => sender.send(external_message::get_external_message("SensorValueChanged".to_string(),&value)?)?,
-            Event::UnknowDataReceived(raw) => sender.send(external_message::get_external_message("SensorUnknowDataReceived".to_string(),&raw)?)?,
+pub fn send_external_message(
+    events: &[Event],
+    sender: &dyn MessageSender,
+    settings: &MessageSettings,
+    clock: &dyn Clock,
+) -> Result<()> {
+    for ev in events {
+        let template = match ev {
+            Event::ValueChanged(_) | Event::SensorReset(_) => &settings.sensor_routing_key,
+            Event::SwitchCommandReceived(_) => &settings.switch_routing_key,
+            Event::UnknowDataReceived(_) => &settings.unknown_routing_key,
         };
-        Ok(ev)
-    }).collect::<Result<Vec<Event>>>()
+        let routing_key = external_message::routing_key(template, &settings.node_name, ev);
+        let message = external_message::get_external_message(&settings.node_name, &routing_key, ev, clock.now())?;
+        sender.send(message)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clock::{FixedClock, SystemClock};
+
+    /// dispatch `line` as the state actor does, then apply its events
+    fn receive(line: &str, repo: &mut SensorRepository, frames: &mut SwitchFrames, clock: &dyn Clock) -> Vec<Event> {
+        let dispatched = dispatch_line(line, repo, frames, clock).unwrap();
+        apply(dispatched.events.clone(), repo);
+        if let Some(frame) = dispatched.switch_frame {
+            frames.record(frame);
+        }
+        dispatched.events
+    }
+
+    fn switch_events(events: &[Event]) -> usize {
+        events
+            .iter()
+            .filter(|e| matches!(e, Event::SwitchCommandReceived(_)))
+            .count()
+    }
+
+    #[test]
+    fn switch_repeated_frame_is_debounced() {
+        let mut repo = SensorRepository::new();
+        let mut frames = SwitchFrames::default();
+        let line = "20;06;Kaku;ID=41;SWITCH=1;CMD=ON;";
+
+        let first = receive(line, &mut repo, &mut frames, &SystemClock);
+        assert_eq!(switch_events(&first), 1);
+
+        let repeat = receive(line, &mut repo, &mut frames, &SystemClock);
+        assert_eq!(switch_events(&repeat), 0);
+    }
+
+    #[test]
+    fn switch_pressed_again_after_debounce() {
+        let mut repo = SensorRepository::new();
+        let mut frames = SwitchFrames::default();
+        let line = "20;06;Kaku;ID=41;SWITCH=1;CMD=ON;";
+        let now = chrono::Utc::now();
+
+        receive(line, &mut repo, &mut frames, &FixedClock(now));
+
+        let later = FixedClock(now + chrono::Duration::milliseconds(switch_event::SWITCH_DEBOUNCE_MILLIS));
+        let again = receive(line, &mut repo, &mut frames, &later);
+        assert_eq!(switch_events(&again), 1);
+    }
+
+    #[test]
+    fn switch_held_down_is_one_press() {
+        let mut repo = SensorRepository::new();
+        let mut frames = SwitchFrames::default();
+        let line = "20;06;Kaku;ID=41;SWITCH=1;CMD=ON;";
+        let now = chrono::Utc::now();
+
+        // the RFLink repeats the frame every 500 ms while the button is held for 4 s
+        let mut emitted = 0;
+        for step in 0..8 {
+            let clock = FixedClock(now + chrono::Duration::milliseconds(step * 500));
+            emitted += switch_events(&receive(line, &mut repo, &mut frames, &clock));
+        }
+        assert_eq!(emitted, 1);
+    }
+
+    #[test]
+    fn switch_dispatch_leaves_the_window_unchanged() {
+        let mut repo = SensorRepository::new();
+        let mut frames = SwitchFrames::default();
+        let line = "20;06;Kaku;ID=41;SWITCH=1;CMD=ON;";
+        let now = chrono::Utc::now();
+        receive(line, &mut repo, &mut frames, &FixedClock(now));
+
+        let clock = FixedClock(now + chrono::Duration::milliseconds(1000));
+        let first = dispatch_line(line, &repo, &frames, &clock).unwrap();
+        let second = dispatch_line(line, &repo, &frames, &clock).unwrap();
+        assert_eq!((switch_events(&first.events), switch_events(&second.events)), (0, 0));
+        assert!(second.switch_frame.is_some());
+    }
+
+    #[test]
+    fn switch_transition_is_emitted() {
+        let mut repo = SensorRepository::new();
+        let mut frames = SwitchFrames::default();
+
+        receive("20;06;Kaku;ID=41;SWITCH=1;CMD=ON;", &mut repo, &mut frames, &SystemClock);
+
+        let off = receive("20;07;Kaku;ID=41;SWITCH=1;CMD=OFF;", &mut repo, &mut frames, &SystemClock);
+        assert_eq!(switch_events(&off), 1);
+    }
+
+    #[test]
+    fn protocol_of_the_decoded_frame() {
+        let repo = SensorRepository::new();
+        let frames = SwitchFrames::default();
+        let dispatched = dispatch_line("20;06;NewKaku;ID=41;SWITCH=1;CMD=ON;", &repo, &frames, &SystemClock).unwrap();
+        assert_eq!((dispatched.protocol.as_deref(), switch_events(&dispatched.events)), (Some("newkaku"), 1));
+        let dispatched = dispatch_line("20;00;Nodo RadioFrequencyLink;", &repo, &frames, &SystemClock).unwrap();
+        assert_eq!(dispatched.protocol, None);
+    }
 }
@@ -1,8 +1,10 @@
 use crate::domain::raw_frame::RawFrame;
+use crate::domain::rflink_field::{decode_temperature, FieldError};
+use crate::domain::rflink_protocol::RfLinkData;
 use crate::domain::sensor::SensorValue;
 use crate::domain::sensor_identifier::SensorIdentifier;
-use crate::domain::sensor_value_type::{SensorValueType, Temperature, ValueType};
-use chrono::NaiveDateTime;
+use crate::domain::sensor_value_type::{BatteryLevel, SensorValueType, Temperature, ValueType};
+use chrono::{DateTime, Utc};
 use snafu::ResultExt;
 
 use snafu::Snafu;
@@ -13,11 +15,8 @@ pub enum OregonError {
     #[snafu(display("Invalid Frame"))]
     InvalidFrameError,
 
-    #[snafu(display("parsing failure for value {}", value))]
-    ParsingFrameError {
-        value: String,
-        source: std::num::ParseIntError,
-    },
+    #[snafu(display("invalid field : {}", source.to_string()))]
+    InvalidFieldError { source: FieldError },
 }
 
 pub type Result<T, E = OregonError> = std::result::Result<T, E>;
@@ -26,13 +25,13 @@ pub struct OregonTempData {
     pub sensor_id: String,
     pub temperature: f64,
     pub battery_state: String,
-    pub timestamp: NaiveDateTime,
+    pub timestamp: DateTime<Utc>,
 }
 
 pub fn is_valid_raw(raw: &RawFrame) -> bool {
     let splitted = raw.data.split(';').collect::<Vec<&str>>();
-    match splitted[2] {
-        x if x == "Oregon Temp" => true,
+    match splitted.get(2) {
+        Some(x) if *x == "Oregon Temp" => true,
         _ => false
     }
 }
@@ -40,19 +39,24 @@ pub fn is_valid_raw(raw: &RawFrame) -> bool {
 impl OregonTempData {
     pub fn from_raw(raw: &RawFrame) -> Result<OregonTempData> {
         //"20;03;Oregon Temp;ID=0410;TEMP=0153;BAT=OK;"
-        let splitted = raw.data.split(';').collect::<Vec<&str>>();
-        let extract_value =
-         isize::from_str_radix(&splitted[4][5..], 16)
-         .context(ParsingFrameError{value:&splitted[4][5..]})?;
+        let data = RfLinkData::from_raw(raw).map_err(|_| OregonError::InvalidFrameError)?;
+        let field = |key: &str| {
+            data.fields
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, value)| value.as_str())
+                .ok_or(OregonError::InvalidFrameError)
+        };
+        let temperature = decode_temperature("TEMP", field("TEMP")?).context(InvalidFieldError)?;
 
         Ok(OregonTempData {
-            sensor_id: splitted[3][3..].to_string(),
-            temperature: ( extract_value as f64 / 10.0),
-            battery_state: splitted[5][4..].to_string(),
-            timestamp: chrono::Local::now().naive_local(),
+            sensor_id: data.sensor_id.clone(),
+            temperature,
+            battery_state: field("BAT")?.to_string(),
+            timestamp: raw.timestamp,
         })
     }
-    fn get_protocol() -> String {
+    pub fn get_protocol() -> String {
         "oregon_temp".to_string()
     }
     pub fn to_sensors_values(&self) -> Vec<SensorValue> {
@@ -62,7 +66,7 @@ impl OregonTempData {
             "temperature",
         );
         let typed_value = Temperature::create(self.temperature);
-        match typed_value {
+        let vec_temp = match typed_value {
             Ok(t) => {
                 let temp_value = SensorValue {
                     id: temp_id,
@@ -76,7 +80,26 @@ impl OregonTempData {
                 print!("error during typing value oregon: {}", e);
                 vec![]
             }
-        }
+        };
+
+        let bat_id = SensorIdentifier::new(
+            &self.sensor_id,
+            &OregonTempData::get_protocol(),
+            "battery",
+        );
+        let vec_bat = match BatteryLevel::create(self.battery_state.as_str()) {
+            Ok(b) => vec![SensorValue {
+                id: bat_id,
+                timestamp: self.timestamp,
+                value: SensorValueType::Battery(b),
+            }],
+            Err(e) => {
+                print!("error during typing value oregon: {}", e);
+                vec![]
+            }
+        };
+
+        [vec_temp, vec_bat].concat()
     }
 }
 
@@ -85,7 +108,7 @@ mod test {
     use super::*;
     #[test]
     fn from_raw_ok() {
-        let raw = RawFrame::new("20;03;Oregon Temp;ID=0410;TEMP=0153;BAT=OK;");
+        let raw = RawFrame::new("20;03;Oregon Temp;ID=0410;TEMP=0153;BAT=OK;", chrono::Utc::now());
         let r_data = OregonTempData::from_raw(&raw);
 
         assert_eq!(r_data.is_ok(), true);
@@ -93,5 +116,36 @@ mod test {
         assert_eq!(data.sensor_id, "0410");
         assert_eq!(data.battery_state, "OK");
         assert_eq!(data.temperature, 33.9);
+        let values = data.to_sensors_values();
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[1].value, SensorValueType::Battery(BatteryLevel::Ok));
+    }
+    #[test]
+    fn from_raw_negative() {
+        let raw = RawFrame::new("20;04;Oregon Temp;ID=0410;TEMP=8024;BAT=LOW;", chrono::Utc::now());
+        let data = OregonTempData::from_raw(&raw).unwrap();
+
+        assert_eq!(data.battery_state, "LOW");
+        assert_eq!(data.temperature, -3.6);
+    }
+    #[test]
+    fn from_raw_reordered_fields() {
+        let raw = RawFrame::new("20;05;Oregon Temp;BAT=OK;TEMP=0153;ID=0410;", chrono::Utc::now());
+        let data = OregonTempData::from_raw(&raw).unwrap();
+
+        assert_eq!(data.sensor_id, "0410");
+        assert_eq!(data.temperature, 33.9);
     }
-}
\ No newline at end of file
+    #[test]
+    fn from_raw_malformed() {
+        for line in &[
+            "20;06;Oregon Temp;I;T;B;",
+            "20;07;Oregon Temp;ID=0410;TEMP=;BAT=OK;",
+            "20;08;Oregon Temp;ID=0410;BAT=OK;",
+            "20;09;Oregon Temp;ID=0410;TEMP=0153;",
+        ] {
+            let raw = RawFrame::new(line, chrono::Utc::now());
+            assert!(OregonTempData::from_raw(&raw).is_err(), "{} should be refused", line);
+        }
+    }
+}
@@ -0,0 +1,369 @@
+use crate::domain::errors::*;
+use crate::domain::sensor::{Sensor, SensorRepository, SensorValue};
+use crate::domain::sensor_identifier::SensorIdentifier;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+pub type QueryReply = tokio::sync::oneshot::Sender<Result<QueryResult>>;
+
+/// Values answered at most by a `Query::Page`
+pub const PAGE_MAX_VALUES: usize = 1000;
+
+/// Read-only question on the sensors, ex: `{"query": "statistics", "protocol": "oregon", ...}`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "query", rename_all = "snake_case")]
+pub enum Query {
+    /// every sensor with its history
+    AllSensors,
+    /// last value of every sensor
+    LastValues,
+    /// summary of every sensor
+    Sensors,
+    /// a sensor with its history
+    Sensor(SensorIdentifier),
+    /// number of values and last value of a sensor
+    SensorSummary(SensorIdentifier),
+    /// values of a sensor received between `from` and `to`, both included,
+    /// only the `limit` most recent ones when given
+    Range {
+        #[serde(flatten)]
+        id: SensorIdentifier,
+        #[serde(default)]
+        from: Option<DateTime<Utc>>,
+        #[serde(default)]
+        to: Option<DateTime<Utc>>,
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    Statistics {
+        #[serde(flatten)]
+        id: SensorIdentifier,
+        #[serde(default)]
+        from: Option<DateTime<Utc>>,
+        #[serde(default)]
+        to: Option<DateTime<Utc>>,
+    },
+    /// at most `limit` values of a sensor received between `from` and `to`, in the received
+    /// order, from the `cursor` given as `next` by the previous page. `limit` is capped to
+    /// `PAGE_MAX_VALUES`.
+    Page {
+        #[serde(flatten)]
+        id: SensorIdentifier,
+        #[serde(default)]
+        from: Option<DateTime<Utc>>,
+        #[serde(default)]
+        to: Option<DateTime<Utc>>,
+        #[serde(default)]
+        cursor: Option<u64>,
+        limit: usize,
+    },
+}
+
+/// Answer to a `Query`, serialized without its variant name.
+#[derive(Clone, Serialize)]
+#[serde(untagged)]
+pub enum QueryResult {
+    Sensors(Vec<Sensor>),
+    LastValues(Vec<Option<SensorValue>>),
+    Sensor(Sensor),
+    Summaries(Vec<SensorSummary>),
+    Summary(SensorSummary),
+    Values(Vec<SensorValue>),
+    Page(ValuesPage),
+    Statistics(Statistics),
+}
+
+/// Values of a `Query::Page`, `next` being the cursor of the following page,
+/// `None` once the end of the history is read.
+#[derive(Clone, Serialize)]
+pub struct ValuesPage {
+    pub values: Vec<SensorValue>,
+    pub next: Option<u64>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct SensorSummary {
+    #[serde(flatten)]
+    pub id: SensorIdentifier,
+    /// number of values in the history
+    pub count: usize,
+    pub last: Option<SensorValue>,
+}
+
+impl From<&Sensor> for SensorSummary {
+    fn from(sensor: &Sensor) -> SensorSummary {
+        SensorSummary {
+            id: sensor.id().clone(),
+            count: sensor.values().len(),
+            last: sensor.get_last(),
+        }
+    }
+}
+
+/// Summary of the values of a sensor, min, max and mean ignore the non numeric values.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Statistics {
+    pub id: SensorIdentifier,
+    pub count: usize,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub mean: Option<f64>,
+    pub first: Option<DateTime<Utc>>,
+    pub last: Option<DateTime<Utc>>,
+}
+
+pub fn execute(query: &Query, repo: &SensorRepository) -> Result<QueryResult> {
+    match query {
+        Query::AllSensors => Ok(QueryResult::Sensors(repo.sensors().to_vec())),
+        Query::LastValues => Ok(QueryResult::LastValues(repo.sensors().iter().map(|s| s.get_last()).collect())),
+        Query::Sensors => Ok(QueryResult::Summaries(repo.sensors().iter().map(SensorSummary::from).collect())),
+        Query::Sensor(id) => Ok(QueryResult::Sensor(find(repo, id)?.clone())),
+        Query::SensorSummary(id) => Ok(QueryResult::Summary(SensorSummary::from(find(repo, id)?))),
+        Query::Range { id, from, to, limit } => {
+            let mut values = in_range(find(repo, id)?, *from, *to).cloned().collect::<Vec<SensorValue>>();
+            if let Some(limit) = limit {
+                values.drain(..values.len().saturating_sub(*limit));
+            }
+            Ok(QueryResult::Values(values))
+        }
+        Query::Statistics { id, from, to } => {
+            let values = in_range(find(repo, id)?, *from, *to).collect::<Vec<&SensorValue>>();
+            Ok(QueryResult::Statistics(statistics(id, &values)))
+        }
+        Query::Page { id, from, to, cursor, limit } => {
+            Ok(QueryResult::Page(page(find(repo, id)?, *from, *to, *cursor, *limit)))
+        }
+    }
+}
+
+pub fn unknown_sensor(id: &SensorIdentifier) -> DomainError {
+    DomainError::UnknownSensorError {
+        value: format!("{}/{}/{}", id.protocol, id.probe_id, id.probe_value_name),
+    }
+}
+
+fn find<'a>(repo: &'a SensorRepository, id: &SensorIdentifier) -> Result<&'a Sensor> {
+    repo.sensors().iter().find(|s| s.id() == id).ok_or_else(|| unknown_sensor(id))
+}
+
+fn in_range(
+    sensor: &Sensor,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) -> impl Iterator<Item = &SensorValue> {
+    sensor.values().iter().filter(move |v| within(v, from, to))
+}
+
+fn within(value: &SensorValue, from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>) -> bool {
+    from.is_none_or(|from| value.timestamp >= from) && to.is_none_or(|to| value.timestamp <= to)
+}
+
+/// The cursor is a position in the history, the timestamps may go back with the clock.
+/// A cursor of values forgotten since starts at the oldest value held.
+fn page(
+    sensor: &Sensor,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    cursor: Option<u64>,
+    limit: usize,
+) -> ValuesPage {
+    let values = sensor.values();
+    let limit = std::cmp::min(limit, PAGE_MAX_VALUES);
+    let mut index = cursor.map_or(0, |cursor| {
+        std::cmp::min(cursor.saturating_sub(sensor.first_position()), values.len() as u64) as usize
+    });
+    let mut page = vec![];
+    while index < values.len() && page.len() < limit {
+        if within(&values[index], from, to) {
+            page.push(values[index].clone());
+        }
+        index += 1;
+    }
+    ValuesPage {
+        values: page,
+        next: match index < values.len() {
+            true => Some(sensor.first_position() + index as u64),
+            false => None,
+        },
+    }
+}
+
+fn statistics(id: &SensorIdentifier, values: &[&SensorValue]) -> Statistics {
+    let numbers = values.iter().filter_map(|v| v.value.number()).collect::<Vec<f64>>();
+    let mean = match numbers.len() {
+        0 => None,
+        n => Some(numbers.iter().sum::<f64>() / n as f64),
+    };
+    Statistics {
+        id: id.clone(),
+        count: values.len(),
+        min: numbers.iter().cloned().reduce(f64::min),
+        max: numbers.iter().cloned().reduce(f64::max),
+        mean,
+        first: values.iter().map(|v| v.timestamp).min(),
+        last: values.iter().map(|v| v.timestamp).max(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::sensor_value_type::{BatteryLevel, SensorValueType, Temperature, ValueType};
+    use chrono::TimeZone;
+
+    fn at(minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2020, 9, 12, 10, minute, 0).unwrap()
+    }
+
+    fn repo() -> SensorRepository {
+        let mut repo = SensorRepository::new();
+        for (minute, temperature) in &[(0, 18.0), (10, 20.0), (20, 25.0)] {
+            repo.add_value(SensorValue {
+                id: SensorIdentifier::new("1a2b", "oregon", "temperature"),
+                timestamp: at(*minute),
+                value: SensorValueType::Temperature(Temperature::create(*temperature).unwrap()),
+            });
+        }
+        repo.add_value(SensorValue {
+            id: SensorIdentifier::new("1a2b", "oregon", "battery"),
+            timestamp: at(20),
+            value: SensorValueType::Battery(BatteryLevel::Low),
+        });
+        repo
+    }
+
+    #[test]
+    fn parse_queries() {
+        let query: Query = serde_json::from_str(
+            r#"{"query": "range", "protocol": "oregon", "probe_id": "1a2b", "probe_value_name": "temperature", "from": "2020-09-12T10:05:00Z"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            query,
+            Query::Range {
+                id: SensorIdentifier::new("1a2b", "oregon", "temperature"),
+                from: Some(at(5)),
+                to: None,
+                limit: None,
+            }
+        );
+        assert_eq!(serde_json::from_str::<Query>(r#"{"query": "last_values"}"#).unwrap(), Query::LastValues);
+        assert!(serde_json::from_str::<Query>(r#"{"query": "everything"}"#).is_err());
+    }
+
+    #[test]
+    fn range_and_statistics() {
+        let repo = repo();
+        let id = SensorIdentifier::new("1a2b", "oregon", "temperature");
+        let range = Query::Range { id: id.clone(), from: Some(at(5)), to: Some(at(20)), limit: None };
+        match execute(&range, &repo).unwrap() {
+            QueryResult::Values(values) => assert_eq!(values.len(), 2),
+            _ => panic!("values expected"),
+        }
+        let latest = Query::Range { id: id.clone(), from: None, to: None, limit: Some(1) };
+        match execute(&latest, &repo).unwrap() {
+            QueryResult::Values(values) => assert_eq!((values.len(), values[0].timestamp), (1, at(20))),
+            _ => panic!("values expected"),
+        }
+        match execute(&Query::Statistics { id: id.clone(), from: None, to: None }, &repo).unwrap() {
+            QueryResult::Statistics(s) => {
+                assert_eq!(s.count, 3);
+                assert_eq!((s.min, s.max, s.mean), (Some(18.0), Some(25.0), Some(21.0)));
+                assert_eq!((s.first, s.last), (Some(at(0)), Some(at(20))));
+            }
+            _ => panic!("statistics expected"),
+        }
+        let page = Query::Page { id: id.clone(), from: Some(at(5)), to: None, cursor: Some(0), limit: 1 };
+        match execute(&page, &repo).unwrap() {
+            QueryResult::Page(page) => {
+                assert_eq!((page.values.len(), page.values[0].timestamp), (1, at(10)));
+                assert_eq!(page.next, Some(2));
+            }
+            _ => panic!("page expected"),
+        }
+        let unknown = SensorIdentifier::new("ffff", "oregon", "temperature");
+        assert!(execute(&Query::Sensor(unknown), &repo).is_err());
+    }
+
+    #[test]
+    fn pages_follow_the_cursor() {
+        let id = SensorIdentifier::new("1a2b", "oregon", "temperature");
+        let mut repo = SensorRepository::new();
+        for i in 0..1500 {
+            // every value at the same time, then the clock goes back
+            let timestamp = match i < 1200 {
+                true => at(30),
+                false => at(0),
+            };
+            repo.add_value(SensorValue {
+                id: id.clone(),
+                timestamp,
+                value: SensorValueType::Temperature(Temperature::create(i as f64 / 100.0).unwrap()),
+            });
+        }
+        let page = |cursor| match execute(&Query::Page { id: id.clone(), from: None, to: None, cursor, limit: usize::MAX }, &repo) {
+            Ok(QueryResult::Page(page)) => page,
+            _ => panic!("page expected"),
+        };
+        let first = page(None);
+        assert_eq!((first.values.len(), first.next), (PAGE_MAX_VALUES, Some(1000)));
+        let second = page(first.next);
+        assert_eq!((second.values.len(), second.next), (500, None));
+        let read = first.values.iter().chain(second.values.iter()).map(|v| v.value.number().unwrap());
+        assert!(read.eq((0..1500).map(|i| i as f64 / 100.0)));
+    }
+
+    #[test]
+    fn forgotten_values_are_skipped() {
+        let id = SensorIdentifier::new("1a2b", "oregon", "temperature");
+        let mut repo = SensorRepository::with_max_values(Some(2));
+        let add = |repo: &mut SensorRepository, minute| {
+            repo.add_value(SensorValue {
+                id: id.clone(),
+                timestamp: at(minute),
+                value: SensorValueType::Temperature(Temperature::create(20.0).unwrap()),
+            })
+        };
+        let page = |repo: &SensorRepository, cursor| match execute(&Query::Page { id: id.clone(), from: None, to: None, cursor, limit: 1 }, repo) {
+            Ok(QueryResult::Page(page)) => (page.values.iter().map(|v| v.timestamp).collect::<Vec<_>>(), page.next),
+            _ => panic!("page expected"),
+        };
+        add(&mut repo, 0);
+        add(&mut repo, 1);
+        assert_eq!(page(&repo, None), (vec![at(0)], Some(1)));
+        add(&mut repo, 2);
+        add(&mut repo, 3);
+        assert_eq!(page(&repo, Some(1)), (vec![at(2)], Some(3)));
+
+        // a sensor created again does not take the positions of the forgotten one
+        repo.remove_sensor(&id);
+        add(&mut repo, 4);
+        assert_eq!(page(&repo, Some(3)), (vec![at(4)], None));
+    }
+
+    #[test]
+    fn sensor_and_summary() {
+        let id = SensorIdentifier::new("1a2b", "oregon", "temperature");
+        match execute(&Query::Sensor(id.clone()), &repo()).unwrap() {
+            QueryResult::Sensor(sensor) => assert_eq!(sensor.values().len(), 3),
+            _ => panic!("sensor expected"),
+        }
+        let query = serde_json::from_str::<Query>(
+            r#"{"query": "sensor_summary", "protocol": "oregon", "probe_id": "1a2b", "probe_value_name": "temperature"}"#,
+        )
+        .unwrap();
+        match execute(&query, &repo()).unwrap() {
+            QueryResult::Summary(summary) => assert_eq!((summary.count, summary.last.unwrap().timestamp), (3, at(20))),
+            _ => panic!("summary expected"),
+        }
+    }
+
+    #[test]
+    fn statistics_of_text_values() {
+        let id = SensorIdentifier::new("1a2b", "oregon", "battery");
+        match execute(&Query::Statistics { id, from: None, to: None }, &repo()).unwrap() {
+            QueryResult::Statistics(s) => assert_eq!((s.count, s.mean), (1, None)),
+            _ => panic!("statistics expected"),
+        }
+    }
+}
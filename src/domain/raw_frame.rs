@@ -1,16 +1,19 @@
-use chrono::NaiveDateTime;
-use serde::Serialize;
-#[derive(Clone,Debug, PartialEq,Serialize)]
+use crate::domain::clock::deserialize_timestamp;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+#[derive(Clone,Debug, PartialEq,Serialize,Deserialize)]
 pub struct RawFrame {
     pub data: String,
-    pub timestamp: NaiveDateTime,
+    /// reception time
+    #[serde(deserialize_with = "deserialize_timestamp")]
+    pub timestamp: DateTime<Utc>,
 }
 
 impl RawFrame {
-    pub fn new(data: &str) -> RawFrame {
+    pub fn new(data: &str, timestamp: DateTime<Utc>) -> RawFrame {
         RawFrame {
             data: String::from(data),
-            timestamp: chrono::Local::now().naive_local(),
+            timestamp,
         }
     }
     /*fn from_string(data: String) -> RawFrame {
@@ -0,0 +1,112 @@
+use crate::domain::command_event::Event;
+use crate::domain::errors::*;
+use crate::domain::query::{unknown_sensor, Query};
+use crate::domain::rf_command::RfCommand;
+use crate::domain::sensor::SensorRepository;
+use crate::domain::sensor_identifier::SensorIdentifier;
+use serde::Deserialize;
+use snafu::ResultExt;
+
+/// Command sent by another service, ex: `{"type": "reset_sensor", "probe_id": "1a2b", ...}`
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RemoteCommand {
+    SendRf(RfCommand),
+    /// every sensor with its history, same as the `all_sensors` query
+    GetState,
+    /// ex: `{"type": "query", "query": "last_values"}`
+    Query(Query),
+    ResetSensor(SensorIdentifier),
+    SetRfDebug { enabled: bool },
+}
+
+impl RemoteCommand {
+    pub fn parse(data: &[u8]) -> Result<RemoteCommand> {
+        serde_json::from_slice(data).context(DataFormatingError)
+    }
+}
+
+/// Event forgetting the sensor `id`, refused when the sensor is unknown.
+pub fn reset_sensor(repo: &SensorRepository, id: SensorIdentifier) -> Result<Vec<Event>> {
+    match repo.extract_sensor(&id) {
+        Some(_) => Ok(vec![Event::SensorReset(id)]),
+        None => Err(unknown_sensor(&id)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::clock::SystemClock;
+    use crate::domain::switch_event::SwitchFrames;
+    use crate::domain::{apply, dispatch_line};
+    use crate::domain::sensor::SensorValue;
+    use crate::domain::sensor_value_type::{SensorValueType, Temperature, ValueType};
+    use crate::domain::message_schema::SwitchMessage;
+    use crate::domain::switch_event::SwitchCommand;
+
+    #[test]
+    fn parse_commands() {
+        let command = RemoteCommand::parse(
+            br#"{"type": "send_rf", "protocol": "NewKaku", "device_id": "00c142", "switch": "1", "command": "ON"}"#,
+        )
+        .unwrap();
+        match command {
+            RemoteCommand::SendRf(rf) => assert_eq!(rf.command, SwitchCommand::On),
+            _ => panic!("send_rf expected"),
+        }
+        assert_eq!(
+            RemoteCommand::parse(br#"{"type": "set_rf_debug", "enabled": false}"#).unwrap(),
+            RemoteCommand::SetRfDebug { enabled: false }
+        );
+        assert_eq!(
+            RemoteCommand::parse(br#"{"type": "query", "query": "all_sensors"}"#).unwrap(),
+            RemoteCommand::Query(Query::AllSensors)
+        );
+        assert!(RemoteCommand::parse(br#"{"type": "reboot"}"#).is_err());
+    }
+
+    #[test]
+    fn published_switch_sent_back() {
+        let repo = SensorRepository::new();
+        let frames = [
+            ("20;06;NewKaku;ID=00c142;SWITCH=1;CMD=ALLON;", "10;newkaku;00c142;1;ALLON;"),
+            ("20;07;NewKaku;ID=00c142;SWITCH=1;CMD=ALLOFF;", "10;newkaku;00c142;1;ALLOFF;"),
+            ("20;08;NewKaku;ID=00c142;SWITCH=2;CMD=SET_LEVEL=12;", "10;newkaku;00c142;2;12;"),
+        ];
+        for (frame, line) in frames.iter() {
+            let dispatched = dispatch_line(frame, &repo, &SwitchFrames::default(), &SystemClock).unwrap();
+            let received = match dispatched.events.as_slice() {
+                [Event::SwitchCommandReceived(received)] => received.clone(),
+                _ => panic!("one switch command expected from {}", frame),
+            };
+            let mut payload = serde_json::to_value(SwitchMessage::from(&received)).unwrap();
+            payload["type"] = serde_json::json!("send_rf");
+
+            match RemoteCommand::parse(payload.to_string().as_bytes()).unwrap() {
+                RemoteCommand::SendRf(rf) => {
+                    assert_eq!(rf.command, received.command);
+                    // the RFLink compares the protocol names without case
+                    assert_eq!(rf.to_rflink_line().unwrap(), *line);
+                }
+                _ => panic!("send_rf expected"),
+            }
+        }
+    }
+
+    #[test]
+    fn reset_known_sensor_only() {
+        let id = SensorIdentifier::new("1a2b", "oregon", "temperature");
+        let mut repo = SensorRepository::new();
+        assert!(reset_sensor(&repo, id.clone()).is_err());
+
+        repo.add_value(SensorValue {
+            id: id.clone(),
+            timestamp: chrono::Utc::now(),
+            value: SensorValueType::Temperature(Temperature::create(20.0).unwrap()),
+        });
+        let events = reset_sensor(&repo, id.clone()).unwrap();
+        apply(events, &mut repo);
+        assert!(repo.extract_sensor(&id).is_none());
+    }
+}
@@ -0,0 +1,187 @@
+use crate::domain::errors::*;
+use crate::domain::switch_event::SwitchCommand;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/// Answer of the RFLink to a command: Ok on `20;..;OK;`
+pub type RfCommandReply = tokio::sync::oneshot::Sender<Result<()>>;
+
+/// Order to send to an actuator (socket, dimmer, blind) through the RFLink.
+/// Its JSON takes the command as published in a `SwitchMessage`:
+/// `{"protocol": "NewKaku", "device_id": "00c142", "switch": "1", "command": "SET_LEVEL", "level": 12}`
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(try_from = "RfCommandMessage", into = "RfCommandMessage")]
+pub struct RfCommand {
+    /// RFLink protocol name (ex: NewKaku)
+    pub protocol: String,
+    pub device_id: String,
+    pub switch: String,
+    pub command: SwitchCommand,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RfCommandMessage {
+    protocol: String,
+    device_id: String,
+    switch: String,
+    /// ON, OFF, ALLON, ALLOFF, SET_LEVEL, UP, DOWN, STOP or a raw RFLink command
+    command: String,
+    /// dim level 0..15 of SET_LEVEL
+    #[serde(default)]
+    level: Option<u32>,
+}
+
+impl TryFrom<RfCommandMessage> for RfCommand {
+    type Error = String;
+
+    fn try_from(message: RfCommandMessage) -> std::result::Result<RfCommand, String> {
+        Ok(RfCommand {
+            command: SwitchCommand::from_wire(&message.command, message.level)?,
+            protocol: message.protocol,
+            device_id: message.device_id,
+            switch: message.switch,
+        })
+    }
+}
+
+impl From<RfCommand> for RfCommandMessage {
+    fn from(command: RfCommand) -> RfCommandMessage {
+        let (name, level) = command.command.to_wire();
+        RfCommandMessage {
+            protocol: command.protocol,
+            device_id: command.device_id,
+            switch: command.switch,
+            command: name,
+            level,
+        }
+    }
+}
+
+/// Something able to deliver a command to the RFLink and report its acknowledgement.
+pub trait RfCommandSender {
+    fn send(&self, command: RfCommand, reply: RfCommandReply);
+    /// raw line of the RFLink serial protocol
+    fn send_line(&self, line: String, reply: RfCommandReply);
+}
+
+pub fn rf_debug_line(enabled: bool) -> String {
+    match enabled {
+        true => "10;rfdebug=on;".to_string(),
+        false => "10;rfdebug=off;".to_string(),
+    }
+}
+
+fn is_valid_part(part: &str) -> bool {
+    !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == ' ')
+}
+
+impl RfCommand {
+    /// Line of the RFLink serial protocol: `10;NewKaku;00c142;1;ON;`
+    pub fn to_rflink_line(&self) -> Result<String> {
+        let cmd = match &self.command {
+            SwitchCommand::On => "ON".to_string(),
+            SwitchCommand::Off => "OFF".to_string(),
+            SwitchCommand::AllOn => "ALLON".to_string(),
+            SwitchCommand::AllOff => "ALLOFF".to_string(),
+            SwitchCommand::Up => "UP".to_string(),
+            SwitchCommand::Down => "DOWN".to_string(),
+            SwitchCommand::Stop => "STOP".to_string(),
+            SwitchCommand::SetLevel(level) if *level <= 15 => level.to_string(),
+            SwitchCommand::Other(other) => other.clone(),
+            SwitchCommand::SetLevel(level) => {
+                return Err(DomainError::InvalidRfCommandError {
+                    value: format!("level {} out of 0..15", level),
+                })
+            }
+        };
+        for part in [&self.protocol, &self.device_id, &self.switch, &cmd].iter() {
+            if !is_valid_part(part) {
+                return Err(DomainError::InvalidRfCommandError {
+                    value: format!("invalid part '{}'", part),
+                });
+            }
+        }
+        Ok(format!(
+            "10;{};{};{};{};",
+            self.protocol, self.device_id, self.switch, cmd
+        ))
+    }
+}
+
+/// Interpretation of a line received while a command is waiting for its acknowledgement,
+/// `None` when the line is not an acknowledgement.
+pub fn parse_acknowledgement(line: &str) -> Option<Result<()>> {
+    let splitted = line.trim_end().split(';').collect::<Vec<&str>>();
+    match splitted.as_slice() {
+        ["20", _, "OK", ..] => Some(Ok(())),
+        ["20", _, debug, ..] if debug.starts_with("RFDEBUG=") => Some(Ok(())),
+        ["20", _, "CMD UNKNOWN", ..] => Some(Err(DomainError::RfCommandRejectedError {
+            value: line.trim_end().to_string(),
+        })),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn command(command: SwitchCommand) -> RfCommand {
+        RfCommand {
+            protocol: "NewKaku".to_string(),
+            device_id: "00c142".to_string(),
+            switch: "1".to_string(),
+            command,
+        }
+    }
+
+    #[test]
+    fn to_rflink_line_on() {
+        assert_eq!(
+            command(SwitchCommand::On).to_rflink_line().unwrap(),
+            "10;NewKaku;00c142;1;ON;"
+        );
+    }
+
+    #[test]
+    fn to_rflink_line_dim() {
+        assert_eq!(
+            command(SwitchCommand::SetLevel(12)).to_rflink_line().unwrap(),
+            "10;NewKaku;00c142;1;12;"
+        );
+        assert!(command(SwitchCommand::SetLevel(16)).to_rflink_line().is_err());
+    }
+
+    #[test]
+    fn json_of_the_published_command() {
+        let dim = serde_json::from_str::<RfCommand>(
+            r#"{"protocol": "NewKaku", "device_id": "00c142", "switch": "1", "command": "SET_LEVEL", "level": 12}"#,
+        )
+        .unwrap();
+        assert_eq!(dim, command(SwitchCommand::SetLevel(12)));
+        let all_on = serde_json::from_str::<RfCommand>(
+            r#"{"protocol": "NewKaku", "device_id": "00c142", "switch": "1", "command": "ALLON"}"#,
+        )
+        .unwrap();
+        assert_eq!(all_on.command, SwitchCommand::AllOn);
+        assert!(serde_json::from_str::<RfCommand>(
+            r#"{"protocol": "NewKaku", "device_id": "00c142", "switch": "1", "command": "SET_LEVEL"}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn to_rflink_line_injection() {
+        let mut cmd = command(SwitchCommand::On);
+        cmd.device_id = "00c142;1;OFF".to_string();
+        assert!(cmd.to_rflink_line().is_err());
+    }
+
+    #[test]
+    fn acknowledgement() {
+        assert!(parse_acknowledgement("20;0A;OK;\r\n").unwrap().is_ok());
+        assert!(parse_acknowledgement("20;0B;CMD UNKNOWN;\r\n").unwrap().is_err());
+        assert!(parse_acknowledgement("20;0D;RFDEBUG=OFF;\r\n").unwrap().is_ok());
+        assert!(parse_acknowledgement("20;0C;Kaku;ID=41;SWITCH=1;CMD=ON;").is_none());
+    }
+}
@@ -0,0 +1,216 @@
+use snafu::{ResultExt, Snafu};
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub(crate)")]
+pub enum FieldError {
+    #[snafu(display("parsing failure for field {} value {}", field, value))]
+    ParsingFieldError {
+        field: String,
+        value: String,
+        source: std::num::ParseIntError,
+    },
+
+    #[snafu(display("value {} out of range for field {}", value, field))]
+    OutOfRangeError { field: String, value: String },
+}
+
+pub type Result<T, E = FieldError> = std::result::Result<T, E>;
+
+/// Physical value of a field of the RFLink standard protocol, once decoded
+/// following the RFLink protocol reference.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Measure {
+    /// celsius
+    Temperature(f64),
+    /// percent
+    Humidity(u32),
+    /// hPa
+    Pressure(u32),
+    /// raw UV intensity, without unit
+    Uv(f64),
+    /// mm
+    Rain(f64),
+    /// mm/h
+    RainRate(f64),
+    /// km/h
+    WindSpeed(f64),
+    /// km/h
+    WindAverageSpeed(f64),
+    /// km/h
+    WindGust(f64),
+    /// degrees from north
+    WindDirection(f64),
+    /// celsius
+    WindChill(f64),
+    /// celsius
+    WindTemperature(f64),
+    /// lux
+    Illuminance(u32),
+    /// W
+    Power(f64),
+    /// kWh
+    Energy(f64),
+    /// A, the phase number (1 to 3) as first member
+    Current(u8, f64),
+    /// V
+    Voltage(f64),
+    /// true when the sensor reports a low battery
+    BatteryLow(bool),
+}
+
+/// Decode a `KEY=VALUE` field, unknown keys are not an error and give `None`.
+pub fn decode(key: &str, value: &str) -> Result<Option<Measure>> {
+    let measure = match key {
+        "TEMP" => Measure::Temperature(decode_temperature(key, value)?),
+        "HUM" => Measure::Humidity(decode_decimal(key, value)?),
+        "BARO" => Measure::Pressure(decode_hex(key, value)?),
+        "UV" => Measure::Uv(decode_hex(key, value)? as f64),
+        "RAIN" => Measure::Rain(decode_tenths(key, value)?),
+        "RAINRATE" => Measure::RainRate(decode_tenths(key, value)?),
+        "WINSP" => Measure::WindSpeed(decode_tenths(key, value)?),
+        "AWINSP" => Measure::WindAverageSpeed(decode_tenths(key, value)?),
+        "WINGS" => Measure::WindGust(decode_hex(key, value)? as f64),
+        "WINDIR" => Measure::WindDirection(decode_wind_direction(key, value)?),
+        "WINCHL" => Measure::WindChill(decode_temperature(key, value)?),
+        "WINTMP" => Measure::WindTemperature(decode_temperature(key, value)?),
+        "LUX" => Measure::Illuminance(decode_hex(key, value)?),
+        "WATT" => Measure::Power(decode_hex(key, value)? as f64),
+        "KWATT" => Measure::Energy(decode_hex(key, value)? as f64),
+        "CURRENT" => Measure::Current(1, decode_hex(key, value)? as f64),
+        "CURRENT2" => Measure::Current(2, decode_hex(key, value)? as f64),
+        "CURRENT3" => Measure::Current(3, decode_hex(key, value)? as f64),
+        "VOLT" => Measure::Voltage(decode_hex(key, value)? as f64),
+        "BAT" => Measure::BatteryLow(decode_battery(key, value)?),
+        _ => return Ok(None),
+    };
+    Ok(Some(measure))
+}
+
+fn decode_hex(key: &str, value: &str) -> Result<u32> {
+    u32::from_str_radix(value, 16).context(ParsingFieldError {
+        field: key,
+        value,
+    })
+}
+
+fn decode_decimal(key: &str, value: &str) -> Result<u32> {
+    value.parse::<u32>().context(ParsingFieldError {
+        field: key,
+        value,
+    })
+}
+
+/// hexadecimal in tenth of unit: 0x8d = 141 = 14.1
+fn decode_tenths(key: &str, value: &str) -> Result<f64> {
+    Ok(decode_hex(key, value)? as f64 / 10.0)
+}
+
+/// hexadecimal in tenth of degree, the high bit is the sign: 0x00c0 = 19.2, 0x80c0 = -19.2
+pub fn decode_temperature(key: &str, value: &str) -> Result<f64> {
+    let raw = decode_hex(key, value)?;
+    if raw > 0xFFFF {
+        return Err(FieldError::OutOfRangeError {
+            field: key.to_string(),
+            value: value.to_string(),
+        });
+    }
+    let magnitude = (raw & 0x7FFF) as f64 / 10.0;
+    match raw & 0x8000 {
+        0 => Ok(magnitude),
+        _ => Ok(-magnitude),
+    }
+}
+
+/// sector from 0 to 15, by step of 22.5 degrees
+fn decode_wind_direction(key: &str, value: &str) -> Result<f64> {
+    match decode_decimal(key, value)? {
+        sector if sector <= 15 => Ok(sector as f64 * 22.5),
+        _ => Err(FieldError::OutOfRangeError {
+            field: key.to_string(),
+            value: value.to_string(),
+        }),
+    }
+}
+
+/// OK or LOW
+fn decode_battery(key: &str, value: &str) -> Result<bool> {
+    match value {
+        "OK" => Ok(false),
+        "LOW" => Ok(true),
+        _ => Err(FieldError::OutOfRangeError {
+            field: key.to_string(),
+            value: value.to_string(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn temperature_positive() {
+        assert_eq!(decode("TEMP", "00c0").unwrap(), Some(Measure::Temperature(19.2)));
+        assert_eq!(decode("TEMP", "0153").unwrap(), Some(Measure::Temperature(33.9)));
+    }
+
+    #[test]
+    fn temperature_negative() {
+        assert_eq!(decode("TEMP", "80c0").unwrap(), Some(Measure::Temperature(-19.2)));
+        assert_eq!(decode("TEMP", "8007").unwrap(), Some(Measure::Temperature(-0.7)));
+        assert_eq!(decode("WINCHL", "8014").unwrap(), Some(Measure::WindChill(-2.0)));
+    }
+
+    #[test]
+    fn temperature_invalid() {
+        assert!(decode("TEMP", "zz").is_err());
+        assert!(decode("TEMP", "180c0").is_err());
+    }
+
+    #[test]
+    fn humidity_is_decimal() {
+        assert_eq!(decode("HUM", "53").unwrap(), Some(Measure::Humidity(53)));
+        assert!(decode("HUM", "4F").is_err());
+    }
+
+    #[test]
+    fn rain_and_wind_tenths() {
+        assert_eq!(decode("RAIN", "8d").unwrap(), Some(Measure::Rain(14.1)));
+        assert_eq!(decode("RAINRATE", "000a").unwrap(), Some(Measure::RainRate(1.0)));
+        assert_eq!(decode("WINSP", "0046").unwrap(), Some(Measure::WindSpeed(7.0)));
+        assert_eq!(decode("AWINSP", "0032").unwrap(), Some(Measure::WindAverageSpeed(5.0)));
+        assert_eq!(decode("WINGS", "0011").unwrap(), Some(Measure::WindGust(17.0)));
+    }
+
+    #[test]
+    fn wind_direction_sectors() {
+        assert_eq!(decode("WINDIR", "0000").unwrap(), Some(Measure::WindDirection(0.0)));
+        assert_eq!(decode("WINDIR", "0004").unwrap(), Some(Measure::WindDirection(90.0)));
+        assert_eq!(decode("WINDIR", "15").unwrap(), Some(Measure::WindDirection(337.5)));
+        assert!(decode("WINDIR", "16").is_err());
+    }
+
+    #[test]
+    fn hexadecimal_values() {
+        assert_eq!(decode("BARO", "03f2").unwrap(), Some(Measure::Pressure(1010)));
+        assert_eq!(decode("LUX", "00ff").unwrap(), Some(Measure::Illuminance(255)));
+        assert_eq!(decode("WATT", "0a").unwrap(), Some(Measure::Power(10.0)));
+        assert_eq!(decode("KWATT", "02").unwrap(), Some(Measure::Energy(2.0)));
+        assert_eq!(decode("CURRENT2", "05").unwrap(), Some(Measure::Current(2, 5.0)));
+        assert_eq!(decode("VOLT", "00e6").unwrap(), Some(Measure::Voltage(230.0)));
+        assert_eq!(decode("UV", "0023").unwrap(), Some(Measure::Uv(35.0)));
+    }
+
+    #[test]
+    fn battery() {
+        assert_eq!(decode("BAT", "OK").unwrap(), Some(Measure::BatteryLow(false)));
+        assert_eq!(decode("BAT", "LOW").unwrap(), Some(Measure::BatteryLow(true)));
+        assert!(decode("BAT", "ko").is_err());
+    }
+
+    #[test]
+    fn unknown_key() {
+        assert_eq!(decode("CHIME", "01").unwrap(), None);
+        assert_eq!(decode("HSTATUS", "1").unwrap(), None);
+    }
+}
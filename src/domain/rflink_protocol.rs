@@ -0,0 +1,296 @@
+use crate::domain::raw_frame::RawFrame;
+use crate::domain::rflink_field::{self, FieldError, Measure};
+use crate::domain::sensor::SensorValue;
+use crate::domain::sensor_identifier::SensorIdentifier;
+use crate::domain::switch_event::{SwitchCommand, SwitchEvent};
+use crate::domain::sensor_value_type::{
+    BatteryLevel, Current, Energy, Humidity, Illuminance, Power, Pressure, RainRate, RainTotal,
+    SensorValueType, Temperature, UvIntensity, ValueType, Voltage, WindDirection, WindSpeed,
+};
+use chrono::{DateTime, Utc};
+use snafu::ResultExt;
+
+use snafu::Snafu;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub(crate)")]
+pub enum RfLinkError {
+    #[snafu(display("Invalid Frame"))]
+    InvalidFrameError,
+
+    #[snafu(display("invalid field : {}", source.to_string()))]
+    InvalidFieldError { source: FieldError },
+}
+
+pub type Result<T, E = RfLinkError> = std::result::Result<T, E>;
+
+/// Frame of the RFLink standard protocol: `20;seq;ProtocolName;ID=..;KEY=VAL;...;`
+#[derive(Debug, PartialEq)]
+pub struct RfLinkData {
+    pub sequence: String,
+    pub protocol: String,
+    pub sensor_id: String,
+    pub switch: Option<String>,
+    pub cmd: Option<String>,
+    pub fields: Vec<(String, String)>,
+    pub timestamp: DateTime<Utc>,
+}
+
+fn split_line(data: &str) -> Vec<&str> {
+    data.trim_end()
+        .trim_end_matches(';')
+        .split(';')
+        .collect::<Vec<&str>>()
+}
+
+fn split_field(field: &str) -> (&str, &str) {
+    match field.find('=') {
+        Some(i) => (&field[..i], &field[i + 1..]),
+        None => (field, ""),
+    }
+}
+
+pub fn is_valid_raw(raw: &RawFrame) -> bool {
+    let splitted = split_line(&raw.data);
+    splitted.len() > 3
+        && splitted[0] == "20"
+        && splitted[3..].iter().any(|f| split_field(f).0 == "ID")
+}
+
+impl RfLinkData {
+    pub fn from_raw(raw: &RawFrame) -> Result<RfLinkData> {
+        //"20;2D;Alecto V1;ID=0334;TEMP=00d4;HUM=53;BAT=OK;"
+        let splitted = split_line(&raw.data);
+        if splitted.len() < 4 || splitted[0] != "20" {
+            return Err(RfLinkError::InvalidFrameError);
+        }
+
+        let mut sensor_id = None;
+        let mut switch = None;
+        let mut cmd = None;
+        let mut fields = vec![];
+        for (key, value) in splitted[3..].iter().map(|f| split_field(f)) {
+            match key {
+                "ID" => sensor_id = Some(value.to_string()),
+                "SWITCH" => switch = Some(value.to_string()),
+                "CMD" => cmd = Some(value.to_string()),
+                _ => fields.push((key.to_string(), value.to_string())),
+            }
+        }
+
+        Ok(RfLinkData {
+            sequence: splitted[1].to_string(),
+            protocol: splitted[2].to_string(),
+            sensor_id: sensor_id.ok_or(RfLinkError::InvalidFrameError)?,
+            switch,
+            cmd,
+            fields,
+            timestamp: raw.timestamp,
+        })
+    }
+
+    /// Protocol name as used in sensor identifiers: "Oregon TempHygro" gives "oregon_temphygro"
+    pub fn get_protocol(&self) -> String {
+        self.protocol
+            .trim()
+            .to_lowercase()
+            .chars()
+            .map(|c| match c {
+                ' ' | '-' | '/' => '_',
+                c => c,
+            })
+            .collect()
+    }
+
+    /// Command sent by a remote, a PIR or a door contact, if the frame carries one
+    pub fn to_switch_event(&self) -> Option<SwitchEvent> {
+        self.cmd.as_ref().map(|cmd| SwitchEvent {
+            protocol: self.get_protocol(),
+            device_id: self.sensor_id.clone(),
+            switch: self.switch.clone().unwrap_or_default(),
+            command: SwitchCommand::from_rflink(cmd),
+            timestamp: self.timestamp,
+        })
+    }
+
+    pub fn to_sensors_values(&self) -> Vec<SensorValue> {
+        let protocol = self.get_protocol();
+        self.fields
+            .iter()
+            .filter_map(|(key, value)| match decode_field(key, value) {
+                Ok(Some((name, typed_value))) => Some(SensorValue {
+                    id: SensorIdentifier::new(&self.sensor_id, &protocol, name),
+                    timestamp: self.timestamp,
+                    value: typed_value,
+                }),
+                Ok(None) => None,
+                Err(e) => {
+                    println!("error during typing value {}: {}", protocol, e);
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+fn decode_field(key: &str, value: &str) -> Result<Option<(&'static str, SensorValueType)>> {
+    let measure = match rflink_field::decode(key, value).context(InvalidFieldError)? {
+        Some(m) => m,
+        None => return Ok(None),
+    };
+    let typed = match measure {
+        Measure::Temperature(v) => Temperature::create(v).map(|t| ("temperature", SensorValueType::Temperature(t))),
+        Measure::Humidity(v) => Humidity::create(v).map(|h| ("humidity", SensorValueType::Humidity(h))),
+        Measure::Pressure(v) => Pressure::create(v as f64).map(|p| ("pressure", SensorValueType::Pressure(p))),
+        Measure::Uv(v) => UvIntensity::create(v).map(|u| ("uv_intensity", SensorValueType::UvIntensity(u))),
+        Measure::Rain(v) => RainTotal::create(v).map(|r| ("rain_total", SensorValueType::RainTotal(r))),
+        Measure::RainRate(v) => RainRate::create(v).map(|r| ("rain_rate", SensorValueType::RainRate(r))),
+        Measure::WindSpeed(v) => WindSpeed::create(v).map(|w| ("wind_speed", SensorValueType::WindSpeed(w))),
+        Measure::WindAverageSpeed(v) => WindSpeed::create(v).map(|w| ("wind_average_speed", SensorValueType::WindSpeed(w))),
+        Measure::WindGust(v) => WindSpeed::create(v).map(|w| ("wind_gust", SensorValueType::WindSpeed(w))),
+        Measure::WindDirection(v) => WindDirection::create(v).map(|w| ("wind_direction", SensorValueType::WindDirection(w))),
+        Measure::WindChill(v) => Temperature::create(v).map(|t| ("wind_chill", SensorValueType::Temperature(t))),
+        Measure::WindTemperature(v) => Temperature::create(v).map(|t| ("wind_temperature", SensorValueType::Temperature(t))),
+        Measure::Illuminance(v) => Illuminance::create(v as f64).map(|l| ("illuminance", SensorValueType::Illuminance(l))),
+        Measure::Power(v) => Power::create(v).map(|p| ("power", SensorValueType::Power(p))),
+        Measure::Energy(v) => Energy::create(v).map(|e| ("energy", SensorValueType::Energy(e))),
+        Measure::Current(phase, v) => Current::create(v).map(|c| {
+            let name = match phase {
+                2 => "current2",
+                3 => "current3",
+                _ => "current",
+            };
+            (name, SensorValueType::Current(c))
+        }),
+        Measure::Voltage(v) => Voltage::create(v).map(|u| ("voltage", SensorValueType::Voltage(u))),
+        Measure::BatteryLow(low) => Ok(("battery", SensorValueType::Battery(match low {
+            true => BatteryLevel::Low,
+            false => BatteryLevel::Ok,
+        }))),
+    };
+    match typed {
+        Ok(v) => Ok(Some(v)),
+        Err(e) => {
+            println!("value out of range for {}={}: {}", key, value, e);
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn is_valid_raw_sensor() {
+        let raw = RawFrame::new("20;2D;Alecto V1;ID=0334;TEMP=00d4;HUM=53;BAT=OK;\r\n", chrono::Utc::now());
+        assert!(is_valid_raw(&raw));
+    }
+
+    #[test]
+    fn is_valid_raw_without_id() {
+        assert!(!is_valid_raw(&RawFrame::new("20;00;Nodo RadioFrequencyLink - RFLink Gateway V1.1 - R46;", chrono::Utc::now())));
+        assert!(!is_valid_raw(&RawFrame::new("20;01;RFDEBUG=ON;", chrono::Utc::now())));
+        assert!(!is_valid_raw(&RawFrame::new("20;02;OK;", chrono::Utc::now())));
+        assert!(!is_valid_raw(&RawFrame::new("", chrono::Utc::now())));
+    }
+
+    #[test]
+    fn from_raw_weather() {
+        let raw = RawFrame::new("20;2D;Alecto V1;ID=0334;TEMP=00d4;HUM=53;BAT=OK;\r\n", chrono::Utc::now());
+        let data = RfLinkData::from_raw(&raw).unwrap();
+
+        assert_eq!(data.sequence, "2D");
+        assert_eq!(data.protocol, "Alecto V1");
+        assert_eq!(data.get_protocol(), "alecto_v1");
+        assert_eq!(data.sensor_id, "0334");
+        assert_eq!(data.fields.last(), Some(&("BAT".to_string(), "OK".to_string())));
+        assert_eq!(data.switch, None);
+
+        let values = data.to_sensors_values();
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[0].id, SensorIdentifier::new("0334", "alecto_v1", "temperature"));
+        assert_eq!(
+            values[0].value.is_temperature().unwrap(),
+            &Temperature::create(21.2).unwrap()
+        );
+        assert_eq!(
+            values[1].value.is_humidity().unwrap(),
+            &Humidity::create(53).unwrap()
+        );
+    }
+
+    #[test]
+    fn from_raw_switch() {
+        let raw = RawFrame::new("20;06;Kaku;ID=41;SWITCH=1;CMD=SET_LEVEL=15;", chrono::Utc::now());
+        let data = RfLinkData::from_raw(&raw).unwrap();
+
+        assert_eq!(data.sensor_id, "41");
+        assert_eq!(data.switch, Some("1".to_string()));
+        assert_eq!(data.cmd, Some("SET_LEVEL=15".to_string()));
+        assert_eq!(data.to_sensors_values().len(), 0);
+
+        let event = data.to_switch_event().unwrap();
+        assert_eq!(event.protocol, "kaku");
+        assert_eq!(event.device_id, "41");
+        assert_eq!(event.switch, "1");
+        assert_eq!(event.command, SwitchCommand::SetLevel(15));
+    }
+
+    #[test]
+    fn from_raw_sensor_has_no_switch_event() {
+        let raw = RawFrame::new("20;2D;Alecto V1;ID=0334;TEMP=00d4;HUM=53;BAT=OK;", chrono::Utc::now());
+        let data = RfLinkData::from_raw(&raw).unwrap();
+
+        assert_eq!(data.to_switch_event(), None);
+    }
+
+    #[test]
+    fn from_raw_invalid_field() {
+        let raw = RawFrame::new("20;2D;Alecto V1;ID=0334;TEMP=zz;HUM=53;", chrono::Utc::now());
+        let data = RfLinkData::from_raw(&raw).unwrap();
+
+        let values = data.to_sensors_values();
+        assert_eq!(values.len(), 1);
+        assert!(values[0].value.is_humidity().is_some());
+    }
+
+    #[test]
+    fn from_raw_weather_station() {
+        let raw = RawFrame::new("20;47;Cresta;ID=8001;WINDIR=0002;WINSP=0060;WINGS=0088;WINCHL=80a0;BARO=03f4;RAIN=0123;UV=0021;", chrono::Utc::now());
+        let data = RfLinkData::from_raw(&raw).unwrap();
+
+        let values = data.to_sensors_values();
+        let names = values.iter().map(|v| v.id.probe_value_name.as_str()).collect::<Vec<&str>>();
+        assert_eq!(names, vec!["wind_direction", "wind_speed", "wind_gust", "wind_chill", "pressure", "rain_total", "uv_intensity"]);
+        assert_eq!(values[0].value, SensorValueType::WindDirection(WindDirection::create(45.0).unwrap()));
+        assert_eq!(values[3].value, SensorValueType::Temperature(Temperature::create(-16.0).unwrap()));
+        assert_eq!(values[4].value, SensorValueType::Pressure(Pressure::create(1012.0).unwrap()));
+        assert_eq!(values[5].value, SensorValueType::RainTotal(RainTotal::create(29.1).unwrap()));
+        assert_eq!(values[6].value, SensorValueType::UvIntensity(UvIntensity::create(33.0).unwrap()));
+    }
+
+    #[test]
+    fn from_raw_energy_meter() {
+        let raw = RawFrame::new("20;12;OWL CM119;ID=0012;WATT=04b0;KWATT=0019;CURRENT2=0005;", chrono::Utc::now());
+        let data = RfLinkData::from_raw(&raw).unwrap();
+
+        let values = data.to_sensors_values();
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[0].value, SensorValueType::Power(Power::create(1200.0).unwrap()));
+        assert_eq!(values[1].value, SensorValueType::Energy(Energy::create(25.0).unwrap()));
+        assert_eq!(values[2].id.probe_value_name, "current2");
+    }
+
+    #[test]
+    fn from_raw_negative_temperature() {
+        let raw = RawFrame::new("20;3A;Alecto V1;ID=0334;TEMP=8043;HUM=88;BAT=OK;", chrono::Utc::now());
+        let data = RfLinkData::from_raw(&raw).unwrap();
+
+        let values = data.to_sensors_values();
+        assert_eq!(
+            values[0].value.is_temperature().unwrap(),
+            &Temperature::create(-6.7).unwrap()
+        );
+    }
+}
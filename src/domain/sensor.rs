@@ -1,13 +1,16 @@
+use crate::domain::clock::deserialize_timestamp;
 use crate::domain::sensor_identifier::SensorIdentifier;
 use crate::domain::sensor_value_type::SensorValueType;
-use crate::domain::errors::*;
-use serde::Serialize;
-use snafu::ResultExt;
+use crate::domain::switch_event::SwitchEvent;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Serialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Sensor {
     id: SensorIdentifier,
     values: Vec<SensorValue>,
+    /// position of the oldest value held, the values of a sensor take the positions following it
+    #[serde(skip)]
+    first_position: u64,
 }
 
 impl Sensor {
@@ -19,62 +22,104 @@ impl Sensor {
                 id.probe_value_name.as_ref(),
             ),
             values: vec![],
+            first_position: 0,
         }
     }
     pub fn add_value(&mut self, value: SensorValue) {
-        match &value.value {
-            SensorValueType::Temperature(x) => println!("ajout de la valeur {}", x),
-            SensorValueType::Humidity(x) => println!("ajout de la valeur {}", x)
-        }
         self.values.push(value);
     }
+    pub fn id(&self) -> &SensorIdentifier {
+        &self.id
+    }
+    /// history, oldest value first
+    pub fn values(&self) -> &[SensorValue] {
+        &self.values
+    }
+    /// position of `values()[0]`, kept by the next values when the oldest ones are forgotten
+    pub fn first_position(&self) -> u64 {
+        self.first_position
+    }
+    /// forget the oldest values beyond the `max_values` most recent
+    fn keep_recent(&mut self, max_values: usize) {
+        let excess = self.values.len().saturating_sub(max_values);
+        self.values.drain(..excess);
+        self.first_position += excess as u64;
+    }
     pub fn get_last(&self) -> Option<SensorValue> {
         self.values.last().and_then(|s| Some(s.clone()))
     }
 }
 
-#[derive(Clone, Serialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct SensorValue {
     pub id: SensorIdentifier,
-    pub timestamp: chrono::NaiveDateTime,
+    #[serde(deserialize_with = "deserialize_timestamp")]
+    pub timestamp: chrono::DateTime<chrono::Utc>,
     pub value: SensorValueType,
 }
 
 pub struct SensorRepository {
     sensors: Vec<Sensor>,
+    last_switch_events: Vec<SwitchEvent>,
+    /// values kept per sensor, the whole history when `None`
+    max_values: Option<usize>,
+    /// number of values ever added, a sensor created again after a reset starts at it
+    /// so that its positions are never those of the forgotten values
+    added: u64,
 }
-unsafe impl Send for SensorRepository {}
-unsafe impl Sync for SensorRepository {}
 
 impl SensorRepository {
     pub fn new() -> SensorRepository {
-        SensorRepository { sensors: vec![] }
+        SensorRepository::with_max_values(None)
     }
+
+    /// Repository keeping the `max_values` most recent values of each sensor, every value when `None`.
+    pub fn with_max_values(max_values: Option<usize>) -> SensorRepository {
+        SensorRepository {
+            sensors: vec![],
+            last_switch_events: vec![],
+            max_values,
+            added: 0,
+        }
+    }
+
     pub fn add_value(&mut self, value: SensorValue) {
+        self.added += 1;
         let sensor = self.sensors.iter_mut().find(|s| s.id == value.id);
         match sensor {
             Some(s) => {
                 s.add_value(value);
+                if let Some(max_values) = self.max_values {
+                    s.keep_recent(max_values);
+                }
             }
             None => {
                 let mut nsensor = Sensor::new(&value.id);
+                nsensor.first_position = self.added - 1;
                 nsensor.add_value(value);
                 self.sensors.push(nsensor)
             }
         }
     }
+    pub fn remove_sensor(&mut self, id: &SensorIdentifier) {
+        self.sensors.retain(|s| &s.id != id);
+    }
+    pub fn add_switch_event(&mut self, event: SwitchEvent) {
+        self.last_switch_events.retain(|e| !e.is_same_switch(&event));
+        self.last_switch_events.push(event);
+    }
+    pub fn last_switch_events(&self) -> &[SwitchEvent] {
+        &self.last_switch_events
+    }
+    pub fn get_last_switch_event(&self, event: &SwitchEvent) -> Option<&SwitchEvent> {
+        self.last_switch_events.iter().find(|e| e.is_same_switch(event))
+    }
     pub fn extract_sensor(&self, id: &SensorIdentifier) -> Option<Sensor> {
         let sensor = self.sensors.iter().find(|s| &s.id == id);
         sensor.and_then(|s| Some(s.clone()))
     }
-
-    pub fn get_all_state(&self) -> Result<String> {
-        serde_json::to_string(&self.sensors).context(DataFormatingError)
-    }
-
-    pub fn get_last_values(&self) -> Result<String> {
-        let data = self.sensors.iter().map(|s| s.get_last()).collect::<Vec<Option<SensorValue>>>();
-        serde_json::to_string(&data).context(DataFormatingError)
+    pub fn sensors(&self) -> &[Sensor] {
+        &self.sensors
     }
 }
 
@@ -97,7 +142,7 @@ mod test {
 
         let value = SensorValue {
             id: id.clone(),
-            timestamp: chrono::Local::now().naive_local(),
+            timestamp: chrono::Utc::now(),
             value: SensorValueType::Temperature(Temperature::create(10.0).unwrap())
         };
         let mut repo = SensorRepository::new();
@@ -121,12 +166,12 @@ mod test {
 
         let value = SensorValue {
             id: id.clone(),
-            timestamp: chrono::Local::now().naive_local(),
+            timestamp: chrono::Utc::now(),
             value: SensorValueType::Humidity(Humidity::create(10).unwrap()),
         };
         let value2 = SensorValue {
             id: id2.clone(),
-            timestamp: chrono::Local::now().naive_local(),
+            timestamp: chrono::Utc::now(),
             value: SensorValueType::Humidity(Humidity::create(11).unwrap()),
         };
 
@@ -144,4 +189,21 @@ mod test {
             SensorValueType::Humidity(Humidity::create(10).unwrap())
         )
     }
+
+    #[test]
+    fn keeps_recent_values() {
+        let id = SensorIdentifier::new("probeid", "protocol", "name");
+        let mut repo = SensorRepository::with_max_values(Some(2));
+        for humidity in 10..15 {
+            repo.add_value(SensorValue {
+                id: id.clone(),
+                timestamp: chrono::Utc::now(),
+                value: SensorValueType::Humidity(Humidity::create(humidity).unwrap()),
+            });
+        }
+
+        let values = repo.sensors()[0].values();
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[0].value, SensorValueType::Humidity(Humidity::create(13).unwrap()));
+    }
 }
@@ -1,4 +1,4 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use snafu::Snafu;
 use std::fmt::Display;
 
@@ -11,6 +11,42 @@ pub enum ValueTypeError {
     #[snafu(display("humidity input invalid: {}", value))]
     InvalidHumidity { value: u32 },
 
+    #[snafu(display("pressure input invalid: {}", value))]
+    InvalidPressure { value: f64 },
+
+    #[snafu(display("rain total input invalid: {}", value))]
+    InvalidRainTotal { value: f64 },
+
+    #[snafu(display("rain rate input invalid: {}", value))]
+    InvalidRainRate { value: f64 },
+
+    #[snafu(display("wind speed input invalid: {}", value))]
+    InvalidWindSpeed { value: f64 },
+
+    #[snafu(display("wind direction input invalid: {}", value))]
+    InvalidWindDirection { value: f64 },
+
+    #[snafu(display("uv intensity input invalid: {}", value))]
+    InvalidUvIntensity { value: f64 },
+
+    #[snafu(display("illuminance input invalid: {}", value))]
+    InvalidIlluminance { value: f64 },
+
+    #[snafu(display("power input invalid: {}", value))]
+    InvalidPower { value: f64 },
+
+    #[snafu(display("energy input invalid: {}", value))]
+    InvalidEnergy { value: f64 },
+
+    #[snafu(display("voltage input invalid: {}", value))]
+    InvalidVoltage { value: f64 },
+
+    #[snafu(display("current input invalid: {}", value))]
+    InvalidCurrent { value: f64 },
+
+    #[snafu(display("battery input invalid: {}", value))]
+    InvalidBattery { value: String },
+
     #[snafu(display("invalid comparaison between type"))]
     InvalidComparaison,
 }
@@ -27,19 +63,99 @@ pub trait ValueType<T> {
         Self: Sized;
 }
 
-#[derive(Debug, PartialEq, Clone, Serialize)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum SensorValueType {
     Temperature(Temperature),
     Humidity(Humidity),
+    Pressure(Pressure),
+    RainTotal(RainTotal),
+    RainRate(RainRate),
+    WindSpeed(WindSpeed),
+    WindDirection(WindDirection),
+    UvIntensity(UvIntensity),
+    Illuminance(Illuminance),
+    Power(Power),
+    Energy(Energy),
+    Voltage(Voltage),
+    Current(Current),
+    Battery(BatteryLevel),
 }
 impl SensorValueType {
     pub fn is_signifiant_variation(&self, other: Self) -> Result<bool> {
         match (&self,&other) {
             (SensorValueType::Temperature(t1), SensorValueType::Temperature(t2)) => Ok(t1.is_signifiant_variation(t2)),
             (SensorValueType::Humidity(t1), SensorValueType::Humidity(t2)) => Ok(t1.is_signifiant_variation(t2)),
+            (SensorValueType::Pressure(t1), SensorValueType::Pressure(t2)) => Ok(t1.is_signifiant_variation(t2)),
+            (SensorValueType::RainTotal(t1), SensorValueType::RainTotal(t2)) => Ok(t1.is_signifiant_variation(t2)),
+            (SensorValueType::RainRate(t1), SensorValueType::RainRate(t2)) => Ok(t1.is_signifiant_variation(t2)),
+            (SensorValueType::WindSpeed(t1), SensorValueType::WindSpeed(t2)) => Ok(t1.is_signifiant_variation(t2)),
+            (SensorValueType::WindDirection(t1), SensorValueType::WindDirection(t2)) => Ok(t1.is_signifiant_variation(t2)),
+            (SensorValueType::UvIntensity(t1), SensorValueType::UvIntensity(t2)) => Ok(t1.is_signifiant_variation(t2)),
+            (SensorValueType::Illuminance(t1), SensorValueType::Illuminance(t2)) => Ok(t1.is_signifiant_variation(t2)),
+            (SensorValueType::Power(t1), SensorValueType::Power(t2)) => Ok(t1.is_signifiant_variation(t2)),
+            (SensorValueType::Energy(t1), SensorValueType::Energy(t2)) => Ok(t1.is_signifiant_variation(t2)),
+            (SensorValueType::Voltage(t1), SensorValueType::Voltage(t2)) => Ok(t1.is_signifiant_variation(t2)),
+            (SensorValueType::Current(t1), SensorValueType::Current(t2)) => Ok(t1.is_signifiant_variation(t2)),
+            (SensorValueType::Battery(t1), SensorValueType::Battery(t2)) => Ok(t1.is_signifiant_variation(t2)),
              _ => Err(ValueTypeError::InvalidComparaison)
         }
     }
+    pub fn unit(&self) -> &'static str {
+        match self {
+            SensorValueType::Temperature(_) => "°C",
+            SensorValueType::Humidity(_) => "%",
+            SensorValueType::Pressure(_) => "hPa",
+            SensorValueType::RainTotal(_) => "mm",
+            SensorValueType::RainRate(_) => "mm/h",
+            SensorValueType::WindSpeed(_) => "km/h",
+            SensorValueType::WindDirection(_) => "°",
+            SensorValueType::UvIntensity(_) => "",
+            SensorValueType::Illuminance(_) => "lx",
+            SensorValueType::Power(_) => "W",
+            SensorValueType::Energy(_) => "kWh",
+            SensorValueType::Voltage(_) => "V",
+            SensorValueType::Current(_) => "A",
+            SensorValueType::Battery(_) => "",
+        }
+    }
+    /// value without its unit
+    pub fn state(&self) -> String {
+        match self {
+            SensorValueType::Temperature(v) => v.to_string(),
+            SensorValueType::Humidity(v) => v.to_string(),
+            SensorValueType::Pressure(v) => v.to_string(),
+            SensorValueType::RainTotal(v) => v.to_string(),
+            SensorValueType::RainRate(v) => v.to_string(),
+            SensorValueType::WindSpeed(v) => v.to_string(),
+            SensorValueType::WindDirection(v) => v.to_string(),
+            SensorValueType::UvIntensity(v) => v.to_string(),
+            SensorValueType::Illuminance(v) => v.to_string(),
+            SensorValueType::Power(v) => v.to_string(),
+            SensorValueType::Energy(v) => v.to_string(),
+            SensorValueType::Voltage(v) => v.to_string(),
+            SensorValueType::Current(v) => v.to_string(),
+            SensorValueType::Battery(v) => v.to_string(),
+        }
+    }
+    /// numeric value, `None` for the battery level
+    pub fn number(&self) -> Option<f64> {
+        match self {
+            SensorValueType::Temperature(v) => Some(v.0),
+            SensorValueType::Humidity(v) => Some(v.0 as f64),
+            SensorValueType::Pressure(v) => Some(v.0),
+            SensorValueType::RainTotal(v) => Some(v.0),
+            SensorValueType::RainRate(v) => Some(v.0),
+            SensorValueType::WindSpeed(v) => Some(v.0),
+            SensorValueType::WindDirection(v) => Some(v.0),
+            SensorValueType::UvIntensity(v) => Some(v.0),
+            SensorValueType::Illuminance(v) => Some(v.0),
+            SensorValueType::Power(v) => Some(v.0),
+            SensorValueType::Energy(v) => Some(v.0),
+            SensorValueType::Voltage(v) => Some(v.0),
+            SensorValueType::Current(v) => Some(v.0),
+            SensorValueType::Battery(_) => None,
+        }
+    }
     pub fn is_temperature(&self) -> Option<&Temperature> {
         match self {
             SensorValueType::Temperature(t) => Some(t),
@@ -53,8 +169,16 @@ impl SensorValueType {
         }
     }
 }
+impl Display for SensorValueType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        match self.unit() {
+            "" => write!(f, "{}", self.state()),
+            unit => write!(f, "{} {}", self.state(), unit),
+        }
+    }
+}
 
-#[derive(Debug, PartialEq, Clone, Serialize)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Temperature(f64);
 
 impl ValueType<f64> for Temperature {
@@ -84,7 +208,7 @@ impl Display for Temperature {
     }
 }
 
-#[derive(Debug, PartialEq, Clone, Serialize)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Humidity(u32);
 
 impl ValueType<u32> for Humidity {
@@ -102,7 +226,7 @@ impl ValueType<u32> for Humidity {
         }
     }
     fn is_signifiant_variation(&self, other: &Humidity) -> bool {
-        !(self.0 != other.0)
+        self.0 != other.0
     }
 }
 impl Display for Humidity {
@@ -110,3 +234,160 @@ impl Display for Humidity {
         write!(f, "{}", self.0)
     }
 }
+
+/// Tolerance of the variation thresholds, 14.2 - 14.1 is 0.0999... in f64
+const VARIATION_EPSILON: f64 = 1e-9;
+
+/// Measure stored as a f64, valid within [min, max] and significant when it
+/// moves by at least `threshold` from the last stored value.
+macro_rules! measure_type {
+    ($name:ident, $error:ident, $min:expr, $max:expr, $threshold:expr) => {
+        #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+        pub struct $name(f64);
+
+        impl ValueType<f64> for $name {
+            fn create(value: f64) -> Result<$name> {
+                match $name::is_valid_value(value) {
+                    true => Ok($name(value)),
+                    false => Err(ValueTypeError::$error { value }),
+                }
+            }
+
+            fn is_valid_value(value: f64) -> bool {
+                ($min..=$max).contains(&value)
+            }
+            fn is_signifiant_variation(&self, other: &$name) -> bool {
+                (self.0 - other.0).abs() >= $threshold - VARIATION_EPSILON
+            }
+        }
+        impl Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+                write!(f, "{}", self.0)
+            }
+        }
+    };
+}
+
+// hPa, records of sea level pressure are around 870 and 1085
+measure_type!(Pressure, InvalidPressure, 800.0, 1100.0, 1.0);
+// mm, cumulated by the station since its reset
+measure_type!(RainTotal, InvalidRainTotal, 0.0, 100_000.0, 0.1);
+// mm/h
+measure_type!(RainRate, InvalidRainRate, 0.0, 1000.0, 0.1);
+// km/h, used for average speed and gusts
+measure_type!(WindSpeed, InvalidWindSpeed, 0.0, 400.0, 1.0);
+// degrees from north, RFLink reports 16 sectors of 22.5 degrees
+measure_type!(WindDirection, InvalidWindDirection, 0.0, 360.0, 22.5);
+// raw UV intensity of the sensor, an integer sent as 16 bits hexadecimal by the RFLink,
+// not scaled to an UV index: the scale depends on the sensor model
+measure_type!(UvIntensity, InvalidUvIntensity, 0.0, 65535.0, 1.0);
+// lux, full daylight is about 100 000
+measure_type!(Illuminance, InvalidIlluminance, 0.0, 200_000.0, 10.0);
+// W
+measure_type!(Power, InvalidPower, 0.0, 100_000.0, 10.0);
+// kWh, cumulated by the meter
+measure_type!(Energy, InvalidEnergy, 0.0, 1_000_000_000.0, 1.0);
+// V
+measure_type!(Voltage, InvalidVoltage, 0.0, 1000.0, 2.0);
+// A
+measure_type!(Current, InvalidCurrent, 0.0, 1000.0, 0.1);
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum BatteryLevel {
+    Ok,
+    Low,
+}
+
+impl ValueType<&str> for BatteryLevel {
+    fn create(value: &str) -> Result<BatteryLevel> {
+        match value {
+            "OK" => Ok(BatteryLevel::Ok),
+            "LOW" => Ok(BatteryLevel::Low),
+            _ => Err(ValueTypeError::InvalidBattery {
+                value: value.to_string(),
+            }),
+        }
+    }
+
+    fn is_valid_value(value: &str) -> bool {
+        value == "OK" || value == "LOW"
+    }
+    fn is_signifiant_variation(&self, other: &BatteryLevel) -> bool {
+        self != other
+    }
+}
+impl Display for BatteryLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        match self {
+            BatteryLevel::Ok => write!(f, "OK"),
+            BatteryLevel::Low => write!(f, "LOW"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pressure_range() {
+        assert!(Pressure::create(1013.0).is_ok());
+        assert!(Pressure::create(0.0).is_err());
+        assert!(Pressure::create(1500.0).is_err());
+    }
+
+    #[test]
+    fn wind_direction_variation() {
+        let north = WindDirection::create(0.0).unwrap();
+        assert!(!north.is_signifiant_variation(&WindDirection::create(0.0).unwrap()));
+        assert!(north.is_signifiant_variation(&WindDirection::create(22.5).unwrap()));
+    }
+
+    #[test]
+    fn power_variation() {
+        let power = Power::create(1200.0).unwrap();
+        assert!(!power.is_signifiant_variation(&Power::create(1205.0).unwrap()));
+        assert!(power.is_signifiant_variation(&Power::create(1250.0).unwrap()));
+        assert!(Power::create(-1.0).is_err());
+    }
+
+    #[test]
+    fn one_step_variation() {
+        let rain = RainTotal::create(14.1).unwrap();
+        assert!(rain.is_signifiant_variation(&RainTotal::create(14.2).unwrap()));
+        assert!(!rain.is_signifiant_variation(&RainTotal::create(14.15).unwrap()));
+        let rate = RainRate::create(0.3).unwrap();
+        assert!(rate.is_signifiant_variation(&RainRate::create(0.2).unwrap()));
+        let current = Current::create(5.1).unwrap();
+        assert!(current.is_signifiant_variation(&Current::create(5.2).unwrap()));
+    }
+
+    #[test]
+    fn humidity_variation() {
+        let humidity = Humidity::create(40).unwrap();
+        assert!(!humidity.is_signifiant_variation(&Humidity::create(40).unwrap()));
+        assert!(humidity.is_signifiant_variation(&Humidity::create(41).unwrap()));
+    }
+
+    #[test]
+    fn uv_intensity_without_unit() {
+        let value = SensorValueType::UvIntensity(UvIntensity::create(1500.0).unwrap());
+        assert_eq!(value.to_string(), "1500");
+    }
+
+    #[test]
+    fn battery_level() {
+        let ok = BatteryLevel::create("OK").unwrap();
+        assert!(ok.is_signifiant_variation(&BatteryLevel::create("LOW").unwrap()));
+        assert!(!ok.is_signifiant_variation(&BatteryLevel::Ok));
+        assert!(BatteryLevel::create("MAYBE").is_err());
+    }
+
+    #[test]
+    fn sensor_value_type_comparaison() {
+        let rain = SensorValueType::RainTotal(RainTotal::create(14.1).unwrap());
+        let rate = SensorValueType::RainRate(RainRate::create(14.1).unwrap());
+        assert!(rain.is_signifiant_variation(rate).is_err());
+        assert_eq!(rain.to_string(), "14.1 mm");
+    }
+}
@@ -0,0 +1,175 @@
+use crate::domain::clock::deserialize_timestamp;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// RFLink repeats the frame of a remote as long as the button is pressed, a frame
+/// of the same command inside this window of the previous frame is the same press.
+pub const SWITCH_DEBOUNCE_MILLIS: i64 = 1500;
+
+/// Names of the RFLink protocol.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum SwitchCommand {
+    On,
+    Off,
+    #[serde(rename = "ALLON")]
+    AllOn,
+    #[serde(rename = "ALLOFF")]
+    AllOff,
+    SetLevel(u32),
+    Up,
+    Down,
+    Stop,
+    Other(String),
+}
+
+impl SwitchCommand {
+    pub fn from_rflink(cmd: &str) -> SwitchCommand {
+        match cmd {
+            "ON" => SwitchCommand::On,
+            "OFF" => SwitchCommand::Off,
+            "ALLON" => SwitchCommand::AllOn,
+            "ALLOFF" => SwitchCommand::AllOff,
+            "UP" => SwitchCommand::Up,
+            "DOWN" => SwitchCommand::Down,
+            "STOP" => SwitchCommand::Stop,
+            c if c.starts_with("SET_LEVEL=") => match c["SET_LEVEL=".len()..].parse::<u32>() {
+                Ok(level) => SwitchCommand::SetLevel(level),
+                Err(_) => SwitchCommand::Other(c.to_string()),
+            },
+            c => SwitchCommand::Other(c.to_string()),
+        }
+    }
+
+    /// Command name and dim level as published: `("SET_LEVEL", Some(12))`
+    pub fn to_wire(&self) -> (String, Option<u32>) {
+        match self {
+            SwitchCommand::On => ("ON".to_string(), None),
+            SwitchCommand::Off => ("OFF".to_string(), None),
+            SwitchCommand::AllOn => ("ALLON".to_string(), None),
+            SwitchCommand::AllOff => ("ALLOFF".to_string(), None),
+            SwitchCommand::SetLevel(level) => ("SET_LEVEL".to_string(), Some(*level)),
+            SwitchCommand::Up => ("UP".to_string(), None),
+            SwitchCommand::Down => ("DOWN".to_string(), None),
+            SwitchCommand::Stop => ("STOP".to_string(), None),
+            SwitchCommand::Other(other) => (other.clone(), None),
+        }
+    }
+
+    /// Command read back from its published name and dim level, `SET_LEVEL` requires the level.
+    pub fn from_wire(command: &str, level: Option<u32>) -> Result<SwitchCommand, String> {
+        match (command, level) {
+            ("SET_LEVEL", Some(level)) => Ok(SwitchCommand::SetLevel(level)),
+            ("SET_LEVEL", None) => Err("SET_LEVEL without level".to_string()),
+            (command, None) => Ok(SwitchCommand::from_rflink(command)),
+            (command, Some(_)) => Err(format!("level given to {}", command)),
+        }
+    }
+}
+
+/// Press of a remote button or transition of a PIR / door contact.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SwitchEvent {
+    pub protocol: String,
+    pub device_id: String,
+    pub switch: String,
+    pub command: SwitchCommand,
+    #[serde(deserialize_with = "deserialize_timestamp")]
+    pub timestamp: DateTime<Utc>,
+}
+
+impl SwitchEvent {
+    pub fn is_same_switch(&self, other: &SwitchEvent) -> bool {
+        self.protocol == other.protocol
+            && self.device_id == other.device_id
+            && self.switch == other.switch
+    }
+
+    /// true when `self` is only a repetition of `last`, the previous frame seen of the switch
+    pub fn is_repetition_of(&self, last: &SwitchEvent) -> bool {
+        self.is_same_switch(last)
+            && self.command == last.command
+            && (self.timestamp - last.timestamp).num_milliseconds() < SWITCH_DEBOUNCE_MILLIS
+    }
+}
+
+/// Last frame seen of every switch, repetitions included: the sliding window of the debounce.
+/// Kept by the state actor beside the repository, it is neither journaled nor replayed.
+#[derive(Default)]
+pub struct SwitchFrames(Vec<SwitchEvent>);
+
+impl SwitchFrames {
+    /// previous frame seen of the switch of `frame`
+    pub fn previous(&self, frame: &SwitchEvent) -> Option<&SwitchEvent> {
+        self.0.iter().find(|f| f.is_same_switch(frame))
+    }
+
+    /// Record `frame` as the last one seen of its switch, whether it was emitted or not.
+    pub fn record(&mut self, frame: SwitchEvent) {
+        self.0.retain(|f| !f.is_same_switch(&frame));
+        self.0.push(frame);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::Duration;
+
+    fn event(command: SwitchCommand, timestamp: DateTime<Utc>) -> SwitchEvent {
+        SwitchEvent {
+            protocol: "kaku".to_string(),
+            device_id: "41".to_string(),
+            switch: "1".to_string(),
+            command,
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn command_from_rflink() {
+        assert_eq!(SwitchCommand::from_rflink("ON"), SwitchCommand::On);
+        assert_eq!(SwitchCommand::from_rflink("ALLOFF"), SwitchCommand::AllOff);
+        assert_eq!(SwitchCommand::from_rflink("SET_LEVEL=15"), SwitchCommand::SetLevel(15));
+        assert_eq!(
+            SwitchCommand::from_rflink("PAIR"),
+            SwitchCommand::Other("PAIR".to_string())
+        );
+    }
+
+    #[test]
+    fn command_names() {
+        assert_eq!(serde_json::to_string(&SwitchCommand::AllOn).unwrap(), r#""ALLON""#);
+        assert_eq!(serde_json::from_str::<SwitchCommand>(r#""ALLOFF""#).unwrap(), SwitchCommand::AllOff);
+        for command in &[SwitchCommand::AllOff, SwitchCommand::SetLevel(7), SwitchCommand::Other("PAIR".to_string())] {
+            let (name, level) = command.to_wire();
+            assert_eq!(&SwitchCommand::from_wire(&name, level).unwrap(), command);
+        }
+        assert!(SwitchCommand::from_wire("SET_LEVEL", None).is_err());
+        assert!(SwitchCommand::from_wire("ON", Some(3)).is_err());
+    }
+
+    #[test]
+    fn repetition_inside_window() {
+        let now = chrono::Utc::now();
+        let first = event(SwitchCommand::On, now);
+        let repeat = event(SwitchCommand::On, now + Duration::milliseconds(300));
+        assert!(repeat.is_repetition_of(&first));
+    }
+
+    #[test]
+    fn new_press_after_window() {
+        let now = chrono::Utc::now();
+        let first = event(SwitchCommand::On, now);
+        let second = event(SwitchCommand::On, now + Duration::seconds(5));
+        assert!(!second.is_repetition_of(&first));
+    }
+
+    #[test]
+    fn transition_is_never_a_repetition() {
+        let now = chrono::Utc::now();
+        let on = event(SwitchCommand::On, now);
+        let off = event(SwitchCommand::Off, now + Duration::milliseconds(100));
+        assert!(!off.is_repetition_of(&on));
+    }
+}
@@ -13,8 +13,17 @@ pub enum RfError {
     #[snafu(display("error during serial configuration : {}", source.to_string()))]
     ConfigurationError { source: serial_error },
 
+    #[snafu(display("unable to open {} : {}", device, source.to_string()))]
+    OpenPortError { device: String, source: io_error },
+
     #[snafu(display("error during serial read : {}", source.to_string()))]
     ReadError { source: io_error },
+
+    #[snafu(display("error during serial write : {}", source.to_string()))]
+    WriteError { source: io_error },
+
+    #[snafu(display("connection closed by the RFLink"))]
+    ConnectionClosed,
     #[snafu(display("Unable to engage debug mode"))]
     DebugNotEngage,
 
@@ -42,6 +51,23 @@ pub enum RfError {
     #[snafu(display("Domain error : {}", source.to_string()))]
     InternalDomainError { source: DomainError },
 
+    #[snafu(display("unable to read configuration file {} : {}", path, source.to_string()))]
+    ConfigFileReadError { path: String, source: io_error },
+
+    #[snafu(display("invalid configuration file : {}", source.to_string()))]
+    ConfigParseError { source: toml::de::Error },
+
+    #[snafu(display("invalid configuration for {} : {}", field, reason))]
+    InvalidConfigError { field: String, reason: String },
+
+    #[snafu(display("unable to open storage {} : {}", path, source.to_string()))]
+    StorageOpenError { path: String, source: io_error },
+
+    #[snafu(display("invalid query : {}", source.to_string()))]
+    QueryParseError { source: serde_json::Error },
+
+    #[snafu(display("unable to write the export to {} : {}", path, source.to_string()))]
+    ExportWriteError { path: String, source: io_error },
 
 }
 
@@ -0,0 +1,461 @@
+use crate::config::StorageConfig;
+use crate::domain::apply;
+use crate::domain::command_event::Event;
+use crate::domain::errors::*;
+use crate::domain::export::Export;
+use crate::domain::journal::{
+    check_version, read_snapshot, read_snapshot_header, read_snapshot_values, write_snapshot, EventJournal, JournalEntry,
+    JOURNAL_VERSION,
+};
+use crate::domain::sensor::{SensorRepository, SensorValue};
+use crate::domain::sensor_identifier::SensorIdentifier;
+use crate::domain::switch_event::SwitchEvent;
+use crate::segment_log::{self, SegmentLog};
+use serde::Serialize;
+use snafu::ResultExt;
+use std::fs;
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+const JOURNAL_PREFIX: &str = "journal";
+const SNAPSHOT_FILE: &str = "snapshot.ndjson";
+
+/// Events kept as JSON records in a segment log, next to the last snapshot.
+pub struct FileJournal {
+    log: SegmentLog,
+    snapshot_path: PathBuf,
+    snapshot_every: u64,
+    last_sequence: u64,
+    snapshot_sequence: u64,
+}
+
+impl FileJournal {
+    pub fn open(config: &StorageConfig) -> std::io::Result<FileJournal> {
+        let log = SegmentLog::open(
+            &config.path,
+            JOURNAL_PREFIX,
+            config.segment_max_bytes,
+            config.sync_writes,
+        )?;
+        Ok(FileJournal {
+            log,
+            snapshot_path: config.path.join(SNAPSHOT_FILE),
+            snapshot_every: config.snapshot_every,
+            last_sequence: 0,
+            snapshot_sequence: 0,
+        })
+    }
+
+    /// Fill `repo` with the snapshot, gives its sequence, 0 without snapshot.
+    fn read_snapshot(&self, repo: &mut SensorRepository) -> Result<u64> {
+        let file = match fs::File::open(&self.snapshot_path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e).context(StorageError),
+        };
+        read_snapshot(&mut BufReader::new(file), repo)
+    }
+
+    /// write then rename, a crash leaves either the old or the new snapshot
+    fn write_snapshot(&self, repo: &SensorRepository) -> Result<()> {
+        let tmp_path = self.snapshot_path.with_extension("ndjson.tmp");
+        let mut out = BufWriter::new(fs::File::create(&tmp_path).context(StorageError)?);
+        write_snapshot(repo, self.last_sequence, &mut out)?;
+        let file = out.into_inner().map_err(|e| e.into_error()).context(StorageError)?;
+        file.sync_all().context(StorageError)?;
+        fs::rename(&tmp_path, &self.snapshot_path).context(StorageError)
+    }
+}
+
+#[derive(Serialize)]
+struct EntryRef<'a> {
+    version: u32,
+    sequence: u64,
+    event: &'a Event,
+}
+
+impl EventJournal for FileJournal {
+    fn flush(&mut self) -> Result<()> {
+        self.log.sync().context(StorageError)
+    }
+
+    fn append(&mut self, events: &[Event]) -> Result<()> {
+        for event in events {
+            let entry = EntryRef {
+                version: JOURNAL_VERSION,
+                sequence: self.last_sequence + 1,
+                event,
+            };
+            let record = serde_json::to_vec(&entry).context(DataFormatingError)?;
+            self.log.append(&record).context(StorageError)?;
+            self.last_sequence += 1;
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, repo: &mut SensorRepository) -> Result<Vec<Event>> {
+        self.snapshot_sequence = self.read_snapshot(repo)?;
+        self.last_sequence = self.snapshot_sequence;
+
+        let mut events = vec![];
+        for record in self.log.read_all().context(StorageError)? {
+            let entry = match serde_json::from_slice::<JournalEntry>(&record) {
+                Ok(e) => e,
+                Err(e) => {
+                    eprintln!("unreadable journal entry ignored: {}", e);
+                    continue;
+                }
+            };
+            check_version(entry.version)?;
+            // already in the snapshot when a crash happened before the old segments were removed
+            if entry.sequence <= self.last_sequence {
+                continue;
+            }
+            self.last_sequence = entry.sequence;
+            events.push(entry.event);
+        }
+        Ok(events)
+    }
+
+    fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    fn needs_snapshot(&self) -> bool {
+        self.last_sequence - self.snapshot_sequence >= self.snapshot_every
+    }
+
+    fn save_snapshot(&mut self, repo: &SensorRepository) -> Result<()> {
+        self.log.roll().context(StorageError)?;
+        self.write_snapshot(repo)?;
+        self.snapshot_sequence = self.last_sequence;
+        self.log.remove_previous_segments().context(StorageError)
+    }
+}
+
+/// Read-only access to the storage of a gateway, possibly running: nothing is created,
+/// truncated nor deleted. The snapshot is kept open, a newer snapshot written
+/// meanwhile replaces the file without changing what is read.
+pub struct JournalReader {
+    dir: PathBuf,
+    snapshot_path: PathBuf,
+    snapshot: Option<fs::File>,
+    snapshot_sequence: u64,
+    last_switch_events: Vec<SwitchEvent>,
+    max_values: Option<usize>,
+}
+
+impl JournalReader {
+    pub fn open(config: &StorageConfig) -> Result<JournalReader> {
+        let snapshot_path = config.path.join(SNAPSHOT_FILE);
+        let (snapshot, snapshot_sequence, last_switch_events) = match fs::File::open(&snapshot_path) {
+            Ok(file) => {
+                let header = read_snapshot_header(&mut BufReader::new(&file))?;
+                (Some(file), header.sequence, header.last_switch_events)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (None, 0, vec![]),
+            Err(e) => return Err(e).context(StorageError),
+        };
+        Ok(JournalReader {
+            dir: config.path.clone(),
+            snapshot_path,
+            snapshot,
+            snapshot_sequence,
+            last_switch_events,
+            max_values: config.max_values_per_sensor,
+        })
+    }
+
+    /// Repository rebuilt from the snapshot and the journal, as the gateway holds it.
+    pub fn load(&self) -> Result<SensorRepository> {
+        let mut repo = SensorRepository::with_max_values(self.max_values);
+        self.snapshot_values(&mut |value| {
+            repo.add_value(value);
+            Ok(())
+        })?;
+        for event in self.last_switch_events.iter() {
+            repo.add_switch_event(event.clone());
+        }
+        self.entries(&mut |_, event| {
+            apply(vec![event], &mut repo);
+            Ok(())
+        })?;
+        Ok(repo)
+    }
+
+    /// Write the export of the stored values to `out`, one value at a time: those of
+    /// the snapshot sensor after sensor, then those of the journal in the received order.
+    /// The values are read twice, first to count the values of each sensor still held
+    /// by the gateway, after its resets and within `max_values_per_sensor`.
+    pub fn export(&self, export: &Export, out: &mut dyn Write) -> Result<()> {
+        let mut sensors: Vec<StoredSensor> = vec![];
+        self.snapshot_values(&mut |value| {
+            if export.selects(&value.id) {
+                stored(&mut sensors, &value.id).count += 1;
+            }
+            Ok(())
+        })?;
+        let last_sequence = self.entries(&mut |sequence, event| {
+            match event {
+                Event::ValueChanged(value) if export.selects(&value.id) => stored(&mut sensors, &value.id).count += 1,
+                Event::SensorReset(id) if export.selects(&id) => {
+                    let sensor = stored(&mut sensors, &id);
+                    sensor.reset = sequence;
+                    sensor.count = 0;
+                }
+                _ => (),
+            }
+            Ok(())
+        })?;
+
+        out.write_all(export.header().as_bytes()).context(StorageError)?;
+        let max_values = self.max_values;
+        let mut write = |sequence: u64, value: SensorValue| -> Result<()> {
+            if export.selects(&value.id)
+                && stored(&mut sensors, &value.id).keeps(sequence, max_values)
+                && export.accepts(&value)
+            {
+                out.write_all(export.rows(&[value]).as_bytes()).context(StorageError)?;
+            }
+            Ok(())
+        };
+        let snapshot_sequence = self.snapshot_sequence;
+        self.snapshot_values(&mut |value| write(snapshot_sequence, value))?;
+        // the events journaled since the first read are left out
+        let mut read_sequence = snapshot_sequence;
+        self.entries(&mut |sequence, event| {
+            if sequence > last_sequence {
+                return Ok(());
+            }
+            read_sequence = sequence;
+            match event {
+                Event::ValueChanged(value) => write(sequence, value),
+                _ => Ok(()),
+            }
+        })?;
+        if read_sequence < last_sequence {
+            return Err(journal_compacted());
+        }
+        out.flush().context(StorageError)
+    }
+
+    fn snapshot_values(&self, visit: &mut dyn FnMut(SensorValue) -> Result<()>) -> Result<()> {
+        if let Some(file) = &self.snapshot {
+            let mut file = file.try_clone().context(StorageError)?;
+            file.seek(SeekFrom::Start(0)).context(StorageError)?;
+            read_snapshot_values(&mut BufReader::new(file), visit)?;
+        }
+        Ok(())
+    }
+
+    /// Hand the events journaled after the snapshot to `visit` with their sequence, one
+    /// segment in memory at a time. Gives the sequence of the last event.
+    fn entries(&self, visit: &mut dyn FnMut(u64, Event) -> Result<()>) -> Result<u64> {
+        let mut last_sequence = self.snapshot_sequence;
+        for path in segment_log::segments(&self.dir, JOURNAL_PREFIX).context(StorageError)? {
+            let records = match segment_log::read_segment(&path) {
+                Ok(records) => records,
+                // removed by a snapshot of the gateway since the list was read
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(journal_compacted()),
+                Err(e) => return Err(e).context(StorageError),
+            };
+            for record in records {
+                let entry = match serde_json::from_slice::<JournalEntry>(&record) {
+                    Ok(e) => e,
+                    Err(e) => {
+                        eprintln!("unreadable journal entry ignored: {}", e);
+                        continue;
+                    }
+                };
+                check_version(entry.version)?;
+                if entry.sequence <= last_sequence {
+                    continue;
+                }
+                if entry.sequence > last_sequence + 1 && self.snapshot_replaced()? {
+                    return Err(journal_compacted());
+                }
+                last_sequence = entry.sequence;
+                visit(entry.sequence, entry.event)?;
+            }
+        }
+        Ok(last_sequence)
+    }
+
+    /// true when the gateway wrote a snapshot since this reader was opened
+    fn snapshot_replaced(&self) -> Result<bool> {
+        match fs::File::open(&self.snapshot_path) {
+            Ok(file) => Ok(read_snapshot_header(&mut BufReader::new(file))?.sequence != self.snapshot_sequence),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(self.snapshot.is_some()),
+            Err(e) => Err(e).context(StorageError),
+        }
+    }
+}
+
+fn journal_compacted() -> DomainError {
+    DomainError::StorageError {
+        source: std::io::Error::other("journal compacted by the gateway during the read, to be run again"),
+    }
+}
+
+/// Values of a sensor stored in the snapshot and the journal, counted before an export
+struct StoredSensor {
+    id: SensorIdentifier,
+    /// sequence of its last reset, the values stored before are forgotten
+    reset: u64,
+    /// values stored since its last reset
+    count: usize,
+    /// values already met by the export
+    seen: usize,
+}
+
+impl StoredSensor {
+    /// true for a value still held by the gateway: stored after the last reset
+    /// and among the `max_values` most recent
+    fn keeps(&mut self, sequence: u64, max_values: Option<usize>) -> bool {
+        if sequence < self.reset {
+            return false;
+        }
+        self.seen += 1;
+        self.seen > max_values.map_or(0, |max_values| self.count.saturating_sub(max_values))
+    }
+}
+
+fn stored<'a>(sensors: &'a mut Vec<StoredSensor>, id: &SensorIdentifier) -> &'a mut StoredSensor {
+    let index = match sensors.iter().position(|s| &s.id == id) {
+        Some(index) => index,
+        None => {
+            sensors.push(StoredSensor {
+                id: id.clone(),
+                reset: 0,
+                count: 0,
+                seen: 0,
+            });
+            sensors.len() - 1
+        }
+    };
+    &mut sensors[index]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::journal::restore;
+    use crate::domain::sensor::{SensorRepository, SensorValue};
+    use crate::domain::sensor_identifier::SensorIdentifier;
+    use crate::domain::sensor_value_type::*;
+    use crate::domain::clock::SystemClock;
+    use crate::domain::export::parse_columns;
+    use crate::domain::dispatch;
+
+    fn pressure(value: f64) -> Event {
+        Event::ValueChanged(SensorValue {
+            id: SensorIdentifier::new("probeid", "rflink", "pressure"),
+            timestamp: chrono::Utc::now(),
+            value: SensorValueType::Pressure(Pressure::create(value).unwrap()),
+        })
+    }
+
+    fn restart(config: &StorageConfig) -> (FileJournal, SensorRepository) {
+        let mut journal = FileJournal::open(config).unwrap();
+        let (mut repo, replay) = restore(&mut journal, SensorRepository::new()).unwrap();
+        let events = dispatch(replay, &repo, &SystemClock).unwrap();
+        apply(events, &mut repo);
+        (journal, repo)
+    }
+
+    #[test]
+    fn repository_survives_restart() {
+        let dir = std::env::temp_dir().join(format!("ayasha_file_journal_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config = StorageConfig {
+            path: dir.clone(),
+            snapshot_every: 2,
+            ..StorageConfig::default()
+        };
+        let id = SensorIdentifier::new("probeid", "rflink", "pressure");
+
+        let (mut journal, mut repo) = restart(&config);
+        for value in [1010.0, 1012.0, 1015.0].iter() {
+            journal.append(&[pressure(*value)]).unwrap();
+            apply(vec![pressure(*value)], &mut repo);
+            if journal.needs_snapshot() {
+                journal.save_snapshot(&repo).unwrap();
+            }
+        }
+        drop(journal);
+
+        let (journal, repo) = restart(&config);
+        assert_eq!(journal.last_sequence(), 3);
+        assert!(!journal.needs_snapshot());
+        let sensor = repo.extract_sensor(&id).unwrap();
+        assert_eq!(sensor.values().len(), 3);
+        assert_eq!(
+            sensor.get_last().unwrap().value,
+            SensorValueType::Pressure(Pressure::create(1015.0).unwrap())
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reader_leaves_the_storage_untouched() {
+        let dir = std::env::temp_dir().join(format!("ayasha_journal_reader_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config = StorageConfig {
+            path: dir.clone(),
+            max_values_per_sensor: Some(2),
+            ..StorageConfig::default()
+        };
+        let temperature_id = SensorIdentifier::new("probeid", "oregon", "temperature");
+        let temperature = |value: f64| {
+            Event::ValueChanged(SensorValue {
+                id: temperature_id.clone(),
+                timestamp: chrono::Utc::now(),
+                value: SensorValueType::Temperature(Temperature::create(value).unwrap()),
+            })
+        };
+
+        let mut journal = FileJournal::open(&config).unwrap();
+        let mut repo = SensorRepository::with_max_values(config.max_values_per_sensor);
+        let events = vec![pressure(1010.0), temperature(20.0), pressure(1012.0)];
+        journal.append(&events).unwrap();
+        apply(events, &mut repo);
+        journal.save_snapshot(&repo).unwrap();
+        journal
+            .append(&[Event::SensorReset(temperature_id.clone()), pressure(1015.0), temperature(21.0)])
+            .unwrap();
+        drop(journal);
+        let segment = segment_log::segments(&dir, JOURNAL_PREFIX).unwrap().pop().unwrap();
+        let mut torn = fs::OpenOptions::new().append(true).open(&segment).unwrap();
+        torn.write_all(&[20, 0, 0, 0, 1, 2]).unwrap();
+        drop(torn);
+        let files = || {
+            let mut files = fs::read_dir(&dir)
+                .unwrap()
+                .map(|e| e.unwrap())
+                .map(|e| (e.file_name(), e.metadata().unwrap().len()))
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        };
+        let before = files();
+
+        let reader = JournalReader::open(&config).unwrap();
+        let repo = reader.load().unwrap();
+        let export = Export {
+            columns: parse_columns("value_name,value").unwrap(),
+            ..Export::default()
+        };
+        let mut out = vec![];
+        reader.export(&export, &mut out).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "value_name,value\npressure,1012\npressure,1015\ntemperature,21\n"
+        );
+        let pressure_id = SensorIdentifier::new("probeid", "rflink", "pressure");
+        assert_eq!(repo.extract_sensor(&pressure_id).unwrap().values().len(), 2);
+        assert_eq!(repo.extract_sensor(&temperature_id).unwrap().values().len(), 1);
+        assert_eq!(files(), before);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,129 @@
+use crate::domain::errors::*;
+use crate::domain::external_message::{ExternalMessage, MessageSender};
+use snafu::ResultExt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+enum Request {
+    Line(String),
+    Sync(Sender<io::Result<()>>),
+}
+
+/// Append every message as a JSON line to a file.
+///
+/// The file is written by its own thread, a slow disk never holds the caller.
+pub struct FileSender {
+    requests: Mutex<Sender<Request>>,
+    pending: Arc<AtomicUsize>,
+}
+
+impl FileSender {
+    pub fn open(path: &Path) -> io::Result<FileSender> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (requests, receiver) = channel::<Request>();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let writer = Writer {
+            path: path.to_path_buf(),
+            file,
+            pending: pending.clone(),
+        };
+        thread::Builder::new()
+            .name("file sink".to_string())
+            .spawn(move || {
+                let mut writer = writer;
+                for request in receiver {
+                    writer.handle(request);
+                }
+            })?;
+        Ok(FileSender {
+            requests: Mutex::new(requests),
+            pending,
+        })
+    }
+
+    fn request(&self, request: Request) -> Result<()> {
+        self.requests
+            .lock()
+            .unwrap()
+            .send(request)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "file sink stopped"))
+            .context(StorageError)
+    }
+}
+
+struct Writer {
+    path: PathBuf,
+    file: File,
+    pending: Arc<AtomicUsize>,
+}
+
+impl Writer {
+    fn handle(&mut self, request: Request) {
+        match request {
+            Request::Line(line) => {
+                if let Err(e) = self.file.write_all(line.as_bytes()) {
+                    println!("unable to write to {}: {}", self.path.display(), e);
+                }
+                self.pending.fetch_sub(1, Ordering::Relaxed);
+            }
+            Request::Sync(reply) => {
+                let _ = reply.send(self.file.sync_data());
+            }
+        }
+    }
+}
+
+impl MessageSender for FileSender {
+    fn send(&self, msg: ExternalMessage) -> Result<()> {
+        let mut line = msg.inner_message;
+        line.push('\n');
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        self.request(Request::Line(line))
+    }
+
+    fn pending(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    /// waits for the lines already sent to be written
+    fn flush(&self) -> Result<()> {
+        let (reply, synced) = channel();
+        self.request(Request::Sync(reply))?;
+        synced
+            .recv()
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::BrokenPipe, "file sink stopped")))
+            .context(StorageError)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::command_event::Event;
+    use crate::domain::raw_frame::RawFrame;
+
+    #[test]
+    fn lines_written_in_background() {
+        let path = std::env::temp_dir().join(format!("ayasha_file_sink_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let sender = FileSender::open(&path).unwrap();
+        for payload in &["{\"a\":1}", "{\"a\":2}"] {
+            sender
+                .send(ExternalMessage {
+                    routing_key: "sensor.unknown".to_string(),
+                    event: Event::UnknowDataReceived(RawFrame::new("20;01;x;", chrono::Utc::now())),
+                    inner_message: payload.to_string(),
+                })
+                .unwrap();
+        }
+        sender.flush().unwrap();
+        assert_eq!(sender.pending(), 0);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{\"a\":1}\n{\"a\":2}\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,172 @@
+use crate::domain::external_message::SinkState;
+use crate::listener::{LinkState, LinkStatus};
+use crate::state_actor::{ActorStatus, StorageStatus};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::time::Duration;
+
+/// Thresholds of the health endpoints.
+#[derive(Clone)]
+pub struct HealthCheck {
+    /// time without any line from the RFLink before ingestion is seen as stalled
+    pub stall_threshold: Duration,
+    /// start of the gateway, the reference until a first line is received
+    pub started: DateTime<Utc>,
+}
+
+/// State of every component, `live` is false when ingestion stalled and
+/// `ready` is false as soon as one problem is found.
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    pub live: bool,
+    pub ready: bool,
+    pub problems: Vec<String>,
+    pub serial: SerialHealth,
+    /// pending messages are buffered by the sinks, only a lost connection makes the gateway unready
+    pub sinks: Vec<SinkState>,
+    pub storage: StorageStatus,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SerialHealth {
+    #[serde(flatten)]
+    pub link: LinkState,
+    pub rf_debug_engaged: bool,
+    pub ingestion_stalled: bool,
+}
+
+impl HealthCheck {
+    pub fn report(&self, link: LinkState, actor: ActorStatus, stopping: bool, now: DateTime<Utc>) -> HealthReport {
+        let mut problems = vec![];
+        let last_line = link.last_line.unwrap_or(self.started);
+        let stalled = now.signed_duration_since(last_line).to_std().unwrap_or_default() > self.stall_threshold;
+        if stalled {
+            problems.push(format!("no line received from the RFLink since {}", last_line.to_rfc3339()));
+        }
+        // the link is only connected once the RF debug is engaged
+        let rf_debug_engaged = link.status == LinkStatus::Connected;
+        if !rf_debug_engaged {
+            let error = link.last_error.clone().unwrap_or_else(|| "connecting".to_string());
+            problems.push(format!("RFLink link unavailable: {}", error));
+        }
+        for sink in actor.sinks.iter().filter(|s| !s.connection.connected) {
+            let error = sink.connection.last_error.as_deref().unwrap_or("connecting");
+            problems.push(format!("sink {} unavailable: {}", sink.name, error));
+        }
+        if let Some(error) = &actor.storage.last_error {
+            problems.push(format!("storage: {}", error));
+        }
+        if stopping {
+            problems.push("shutting down".to_string());
+        }
+        HealthReport {
+            live: !stalled,
+            ready: problems.is_empty(),
+            problems,
+            serial: SerialHealth {
+                link,
+                rf_debug_engaged,
+                ingestion_stalled: stalled,
+            },
+            sinks: actor.sinks,
+            storage: actor.storage,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::external_message::SinkConnection;
+    use crate::listener::LinkMonitor;
+    use chrono::TimeZone;
+
+    fn at(minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2020, 9, 12, 10, minute, 0).unwrap()
+    }
+
+    fn check() -> HealthCheck {
+        HealthCheck {
+            stall_threshold: Duration::from_secs(300),
+            started: at(0),
+        }
+    }
+
+    fn connected(last_line: Option<DateTime<Utc>>) -> LinkState {
+        LinkState {
+            status: LinkStatus::Connected,
+            last_line,
+            ..LinkMonitor::new().state()
+        }
+    }
+
+    #[test]
+    fn ready_while_lines_arrive() {
+        let report = check().report(connected(Some(at(8))), ActorStatus::default(), false, at(10));
+        assert!(report.live && report.ready);
+        assert!(report.problems.is_empty());
+        assert!(report.serial.rf_debug_engaged);
+    }
+
+    #[test]
+    fn stalled_ingestion_fails_both_checks() {
+        let report = check().report(connected(Some(at(1))), ActorStatus::default(), false, at(10));
+        assert!(!report.live && !report.ready);
+        assert!(report.serial.ingestion_stalled);
+
+        // no line at all since the start
+        let report = check().report(connected(None), ActorStatus::default(), false, at(10));
+        assert!(!report.live);
+    }
+
+    #[test]
+    fn unready_without_link_or_storage() {
+        let mut link = connected(Some(at(9)));
+        link.status = LinkStatus::Retrying;
+        link.last_error = Some("RF debug not engaged".to_string());
+        let mut actor = ActorStatus::default();
+        actor.storage.last_error = Some("unable to journal events: disk full".to_string());
+
+        let report = check().report(link, actor, false, at(10));
+        assert!(report.live && !report.ready);
+        assert_eq!(
+            report.problems,
+            vec![
+                "RFLink link unavailable: RF debug not engaged",
+                "storage: unable to journal events: disk full"
+            ]
+        );
+        assert!(!report.serial.rf_debug_engaged);
+    }
+
+    #[test]
+    fn unready_without_sink_connection() {
+        let sink = |name: &str, connected: bool, last_error: Option<&str>| SinkState {
+            name: name.to_string(),
+            pending: 3,
+            connection: SinkConnection {
+                connected,
+                last_error: last_error.map(str::to_string),
+            },
+        };
+        let actor = ActorStatus {
+            sinks: vec![
+                sink("amqp ayasha", false, None),
+                sink("mqtt localhost", false, Some("connection refused")),
+                // connected again, the last error is only kept for diagnostics
+                sink("webhook http://localhost/hook", true, Some("timeout")),
+            ],
+            ..ActorStatus::default()
+        };
+
+        let report = check().report(connected(Some(at(9))), actor, false, at(10));
+        assert!(report.live && !report.ready);
+        assert_eq!(
+            report.problems,
+            vec![
+                "sink amqp ayasha unavailable: connecting",
+                "sink mqtt localhost unavailable: connection refused"
+            ]
+        );
+    }
+}
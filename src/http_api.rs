@@ -0,0 +1,537 @@
+use crate::domain::command_event::Command;
+use crate::domain::errors::DomainError;
+use crate::domain::export::{self, Export};
+use crate::domain::external_message::MessageFilter;
+use crate::domain::query::{Query, QueryResult};
+use crate::domain::rf_command::RfCommand;
+use crate::domain::sensor_identifier::SensorIdentifier;
+use crate::health::{HealthCheck, HealthReport};
+use crate::listener::{self, LinkMonitor, LinkStatus};
+use crate::live_stream::{self, LiveStream, Subscription, STREAMED_TITLES};
+use crate::metrics::METRICS;
+use crate::shutdown::Shutdown;
+use crate::state_actor::MessageSender;
+use chrono::{DateTime, Utc};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use std::time::Duration;
+
+/// Handles shared by every HTTP request.
+#[derive(Clone)]
+pub struct HttpContext {
+    pub sender: MessageSender,
+    pub link_monitor: LinkMonitor,
+    pub live_stream: LiveStream,
+    /// idle time before a heartbeat is sent to a streaming client
+    pub heartbeat: Duration,
+    pub shutdown: Shutdown,
+    pub health: HealthCheck,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+pub async fn handle(req: Request<Body>, context: HttpContext) -> Response<Body> {
+    let (parts, body) = req.into_parts();
+    let segments = parts
+        .uri
+        .path()
+        .trim_matches('/')
+        .split('/')
+        .map(|s| percent_decode_str(s).decode_utf8_lossy().to_string())
+        .collect::<Vec<String>>();
+    let segments = segments.iter().map(|s| s.as_str()).collect::<Vec<&str>>();
+    match (&parts.method, segments.as_slice()) {
+        (&Method::GET, ["alive"]) => Response::new(Body::from("yes")),
+        (&Method::POST, ["rf_command"]) => send_rf_command(body, &context).await,
+        (&Method::GET, ["link_state"]) => json_response(StatusCode::OK, &context.link_monitor.state()),
+        (&Method::GET, ["metrics"]) => metrics(&context).await,
+        (&Method::GET, ["health", "live"]) => health(&context, |report| report.live),
+        (&Method::GET, ["health", "ready"]) => health(&context, |report| report.ready),
+        (&Method::GET, ["all_sensors"]) => query_json(&context.sender, Query::AllSensors).await,
+        (&Method::GET, ["all_sensors_last_value"]) => query_json(&context.sender, Query::LastValues).await,
+        (&Method::POST, ["query"]) => post_query(body, &context.sender).await,
+        (&Method::GET, ["sensors"]) => query_json(&context.sender, Query::Sensors).await,
+        (&Method::GET, ["sensors", protocol, probe_id, value_name]) => {
+            let id = SensorIdentifier::new(probe_id, protocol, value_name);
+            query_json(&context.sender, Query::SensorSummary(id)).await
+        }
+        (&Method::GET, ["sensors", protocol, probe_id, value_name, "values"]) => {
+            let id = SensorIdentifier::new(probe_id, protocol, value_name);
+            match range_query(id, parts.uri.query().unwrap_or_default()) {
+                Ok(query) => query_json(&context.sender, query).await,
+                Err(e) => json_error(StatusCode::BAD_REQUEST, e),
+            }
+        }
+        (&Method::GET, ["export"]) => match export_parameters(parts.uri.query().unwrap_or_default()) {
+            Ok(export) => export_history(export, &context.sender).await,
+            Err(e) => json_error(StatusCode::BAD_REQUEST, e),
+        },
+        (&Method::GET, ["events"]) => {
+            let filter = match stream_filter(parts.uri.query().unwrap_or_default()) {
+                Ok(f) => f,
+                Err(e) => return json_error(StatusCode::BAD_REQUEST, e),
+            };
+            let subscription = Subscription {
+                filter,
+                heartbeat: context.heartbeat,
+                shutdown: context.shutdown.clone(),
+            };
+            let header = |name: &str| parts.headers.get(name).and_then(|v| v.to_str().ok());
+            match header("upgrade") {
+                Some(upgrade) if upgrade.eq_ignore_ascii_case("websocket") => {
+                    // handshake of RFC 6455, section 4.2.1
+                    let connection_upgrade = header("connection")
+                        .is_some_and(|c| c.split(',').any(|t| t.trim().eq_ignore_ascii_case("upgrade")));
+                    match (connection_upgrade, header("sec-websocket-version"), header("sec-websocket-key")) {
+                        (false, _, _) => json_error(StatusCode::BAD_REQUEST, "connection: upgrade expected".to_string()),
+                        (true, Some("13"), Some(key)) => live_stream::websocket(&context.live_stream, key, body, subscription),
+                        (true, Some("13"), None) => json_error(StatusCode::BAD_REQUEST, "sec-websocket-key expected".to_string()),
+                        (true, _, _) => json_error(StatusCode::BAD_REQUEST, "sec-websocket-version 13 expected".to_string()),
+                    }
+                }
+                _ => live_stream::server_sent_events(&context.live_stream, subscription),
+            }
+        }
+        _ => json_error(StatusCode::NOT_FOUND, format!("no route for {} {}", parts.method, parts.uri.path())),
+    }
+}
+
+fn json_response<T: Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+    match serde_json::to_string(value) {
+        Ok(data) => Response::builder()
+            .status(status)
+            .header("content-type", "application/json")
+            .header("charset", "UTF-8")
+            .body(Body::from(data))
+            .unwrap(),
+        Err(e) => {
+            println!("error in hyper: {}", e);
+            let mut response = Response::new(Body::from(e.to_string()));
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            response
+        }
+    }
+}
+
+/// `{"error": "..."}` answered with `status`.
+fn json_error(status: StatusCode, error: String) -> Response<Body> {
+    json_response(status, &ErrorBody { error })
+}
+
+fn error_status(error: &DomainError) -> StatusCode {
+    match error {
+        DomainError::UnknownSensorError { .. } => StatusCode::NOT_FOUND,
+        DomainError::InvalidRfCommandError { .. } => StatusCode::BAD_REQUEST,
+        DomainError::RfCommandRejectedError { .. } => StatusCode::BAD_GATEWAY,
+        DomainError::DataExtractionError { .. } | DomainError::RfLinkUnavailableError => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Answer with the result of `query` in JSON.
+async fn query_json(sender: &MessageSender, query: Query) -> Response<Body> {
+    match sender.query(query).await {
+        Ok(result) => json_response(StatusCode::OK, &result),
+        Err(e) => json_error(error_status(&e), e.to_string()),
+    }
+}
+
+/// Report of every component, 503 when `passed` is false.
+fn health(context: &HttpContext, passed: fn(&HealthReport) -> bool) -> Response<Body> {
+    let report = context.health.report(
+        context.link_monitor.state(),
+        context.sender.status(),
+        context.shutdown.is_requested(),
+        Utc::now(),
+    );
+    let status = match passed(&report) {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    json_response(status, &report)
+}
+
+/// Prometheus text exposition, without the sensor gauges when the actor does not answer.
+async fn metrics(context: &HttpContext) -> Response<Body> {
+    let last_values = match context.sender.query(Query::LastValues).await {
+        Ok(QueryResult::LastValues(values)) => values,
+        Ok(_) => vec![],
+        Err(e) => {
+            println!("sensors missing from the metrics: {}", e);
+            vec![]
+        }
+    };
+    Response::builder()
+        .header("content-type", "text/plain; version=0.0.4")
+        .body(Body::from(METRICS.render(&context.link_monitor.state(), &last_values)))
+        .unwrap()
+}
+
+async fn post_query(body: Body, sender: &MessageSender) -> Response<Body> {
+    let query = match hyper::body::to_bytes(body).await {
+        Ok(bytes) => match serde_json::from_slice::<Query>(&bytes) {
+            Ok(q) => q,
+            Err(e) => return json_error(StatusCode::BAD_REQUEST, e.to_string()),
+        },
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    query_json(sender, query).await
+}
+
+/// decoded `name=value` pairs of a query string
+fn parameters(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|parameter| {
+            let (name, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+            (name.to_string(), percent_decode_str(value).decode_utf8_lossy().to_string())
+        })
+        .collect()
+}
+
+/// Range query of `id` from the `from`, `to` (RFC 3339) and `limit` parameters.
+fn range_query(id: SensorIdentifier, query: &str) -> Result<Query, String> {
+    let mut from = None;
+    let mut to = None;
+    let mut limit = None;
+    for (name, value) in parameters(query) {
+        match name.as_str() {
+            "from" => from = Some(parse_timestamp(&name, &value)?),
+            "to" => to = Some(parse_timestamp(&name, &value)?),
+            "limit" => limit = Some(value.parse::<usize>().map_err(|e| format!("limit: {}", e))?),
+            _ => return Err(format!("unknown parameter {}", name)),
+        }
+    }
+    Ok(Query::Range { id, from, to, limit })
+}
+
+/// Streamed events matching the `protocol`, `sensor` and `value_name` parameters, each repeatable.
+fn stream_filter(query: &str) -> Result<MessageFilter, String> {
+    let mut filter = MessageFilter {
+        titles: STREAMED_TITLES.iter().map(|t| t.to_string()).collect(),
+        ..MessageFilter::default()
+    };
+    for (name, value) in parameters(query) {
+        match name.as_str() {
+            "protocol" => filter.protocols.push(value),
+            "sensor" => filter.sensors.push(value),
+            "value_name" => filter.value_names.push(value),
+            _ => return Err(format!("unknown parameter {}", name)),
+        }
+    }
+    Ok(filter)
+}
+
+/// Export of the `protocol`, `sensor` and `value_name` (repeatable), `from`, `to`, `format`,
+/// `columns` and `units` parameters.
+fn export_parameters(query: &str) -> Result<Export, String> {
+    let mut export = Export::default();
+    for (name, value) in parameters(query) {
+        match name.as_str() {
+            "protocol" => export.protocols.push(value),
+            "sensor" => export.sensors.push(value),
+            "value_name" => export.value_names.push(value),
+            "from" => export.from = Some(parse_timestamp(&name, &value)?),
+            "to" => export.to = Some(parse_timestamp(&name, &value)?),
+            "format" => export.format = value.parse()?,
+            "columns" => export.columns = export::parse_columns(&value)?,
+            "units" => export.units = value.parse()?,
+            _ => return Err(format!("unknown parameter {}", name)),
+        }
+    }
+    Ok(export)
+}
+
+/// Chunked response, each page of values is asked to the actor once the previous one is sent.
+/// The transfer is aborted when a sensor is reset meanwhile.
+async fn export_history(export: Export, sender: &MessageSender) -> Response<Body> {
+    let ids = match sender.query(Query::Sensors).await {
+        Ok(QueryResult::Summaries(sensors)) => sensors.into_iter().map(|s| s.id).filter(|id| export.selects(id)),
+        Ok(_) => return json_error(StatusCode::INTERNAL_SERVER_ERROR, "unexpected query result".to_string()),
+        Err(e) => return json_error(error_status(&e), e.to_string()),
+    };
+    let ids = ids.collect::<Vec<SensorIdentifier>>();
+    let (mut body, response) = Body::channel();
+    let content_type = export.format.content_type();
+    let filename = format!("attachment; filename=\"sensors.{}\"", export.format.extension());
+    let sender = sender.clone();
+    tokio::spawn(async move {
+        let header = export.header();
+        // the client is gone
+        if !header.is_empty() && body.send_data(header.into()).await.is_err() {
+            return;
+        }
+        for id in ids {
+            let mut cursor = None;
+            loop {
+                let page = match sender.query(export.page(&id, cursor)).await {
+                    Ok(QueryResult::Page(page)) => page,
+                    Ok(_) => break,
+                    Err(e) => {
+                        println!("export interrupted: {}", e);
+                        body.abort();
+                        return;
+                    }
+                };
+                if !page.values.is_empty() && body.send_data(export.rows(&page.values).into()).await.is_err() {
+                    return;
+                }
+                cursor = match page.next {
+                    Some(next) => Some(next),
+                    None => break,
+                };
+            }
+        }
+    });
+    Response::builder()
+        .header("content-type", content_type)
+        .header("content-disposition", filename)
+        .body(response)
+        .unwrap()
+}
+
+fn parse_timestamp(name: &str, value: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|e| format!("{}: {}", name, e))
+}
+
+async fn send_rf_command(body: Body, context: &HttpContext) -> Response<Body> {
+    if context.link_monitor.state().status != LinkStatus::Connected {
+        return json_error(StatusCode::SERVICE_UNAVAILABLE, "RFLink not connected".to_string());
+    }
+    let command = match hyper::body::to_bytes(body).await {
+        Ok(bytes) => match serde_json::from_slice::<RfCommand>(&bytes) {
+            Ok(c) => c,
+            Err(e) => return json_error(StatusCode::BAD_REQUEST, e.to_string()),
+        },
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+
+    let (reply, receiver) = tokio::sync::oneshot::channel();
+    if let Err(e) = context.sender.send(Command::SendRf(command, reply)).await {
+        return json_error(StatusCode::SERVICE_UNAVAILABLE, e.to_string());
+    }
+    match tokio::time::timeout(listener::RF_COMMAND_TIMEOUT, receiver).await {
+        Ok(Ok(Ok(_))) => json_response(StatusCode::OK, &serde_json::json!({ "ok": true })),
+        Ok(Ok(Err(e))) => json_error(error_status(&e), e.to_string()),
+        Ok(Err(_)) => json_error(StatusCode::SERVICE_UNAVAILABLE, "RFLink link lost".to_string()),
+        Err(_) => json_error(StatusCode::GATEWAY_TIMEOUT, "no acknowledgement from the RFLink".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::command_event::Event;
+    use crate::domain::query::execute;
+    use crate::domain::sensor::{SensorRepository, SensorValue};
+    use crate::domain::sensor_value_type::{SensorValueType, Temperature, ValueType};
+
+    /// context answering the queries from a repository holding one oregon temperature
+    fn context() -> HttpContext {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel::<Command>(8);
+        let mut repo = SensorRepository::new();
+        repo.add_value(SensorValue {
+            id: SensorIdentifier::new("1a2b", "oregon", "temperature"),
+            timestamp: chrono::Utc::now(),
+            value: SensorValueType::Temperature(Temperature::create(21.5).unwrap()),
+        });
+        tokio::spawn(async move {
+            while let Some(command) = receiver.recv().await {
+                if let Command::Query(query, reply) = command {
+                    let _ = reply.send(execute(&query, &repo));
+                }
+            }
+        });
+        let (_, shutdown) = crate::shutdown::channel();
+        HttpContext {
+            sender: MessageSender::from_sender(sender),
+            link_monitor: LinkMonitor::new(),
+            live_stream: LiveStream::new(),
+            heartbeat: Duration::from_secs(15),
+            shutdown,
+            health: HealthCheck {
+                stall_threshold: Duration::from_secs(300),
+                started: chrono::Utc::now(),
+            },
+        }
+    }
+
+    async fn get(uri: &str) -> (StatusCode, serde_json::Value) {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = handle(request, context()).await;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn sensor_resources() {
+        let (status, sensors) = get("/sensors").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(sensors[0]["probe_id"], "1a2b");
+        assert_eq!(sensors[0]["count"], 1);
+
+        let (status, values) = get("/sensors/oregon/1a2b/temperature/values?limit=5").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(values.as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn json_errors() {
+        let (status, error) = get("/sensors/oregon/ffff/temperature").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(error["error"], "unknown sensor oregon/ffff/temperature");
+
+        let (status, error) = get("/sensors/oregon/1a2b/temperature/values?from=yesterday").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(error["error"].as_str().unwrap().starts_with("from:"));
+
+        let (status, _) = get("/nowhere").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn health_checks() {
+        let (status, report) = get("/health/live").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(report["serial"]["status"], "connecting");
+
+        // the RFLink link is not connected in the test context
+        let (status, report) = get("/health/ready").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(report["ready"], false);
+        assert_eq!(report["storage"]["enabled"], false);
+    }
+
+    #[tokio::test]
+    async fn export_chunks() {
+        let request = Request::get("/export?format=ndjson&columns=probe_id,value&protocol=oregon")
+            .body(Body::empty())
+            .unwrap();
+        let response = handle(request, context()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "application/x-ndjson");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&body), "{\"probe_id\":\"1a2b\",\"value\":21.5}\n");
+
+        let (status, error) = get("/export?columns=color").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error["error"], "unknown column color");
+    }
+
+    #[tokio::test]
+    async fn websocket_handshake_checks() {
+        let upgrade = |connection: &str, version: &str| {
+            Request::get("/events")
+                .header("upgrade", "websocket")
+                .header("connection", connection)
+                .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+                .header("sec-websocket-version", version)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = handle(upgrade("keep-alive, Upgrade", "13"), context()).await;
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(response.headers()["sec-websocket-accept"], "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(handle(upgrade("keep-alive", "13"), context()).await.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(handle(upgrade("upgrade", "8"), context()).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn websocket_events_and_heartbeats() {
+        use crate::domain::external_message::{get_external_message, MessageSender as _};
+        use crate::domain::raw_frame::RawFrame;
+        use futures::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message;
+
+        let mut context = context();
+        context.heartbeat = Duration::from_millis(300);
+        let (_trigger, shutdown) = crate::shutdown::channel();
+        context.shutdown = shutdown;
+        let live_stream = context.live_stream.clone();
+        let make_service = hyper::service::make_service_fn(move |_| {
+            let context = context.clone();
+            async move {
+                Ok::<_, hyper::Error>(hyper::service::service_fn(move |request| {
+                    let context = context.clone();
+                    async move { Ok::<_, hyper::Error>(handle(request, context).await) }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        let tcp = tokio::net::TcpStream::connect(address).await.unwrap();
+        let (socket, response) = tokio_tungstenite::client_async(format!("ws://{}/events", address), tcp)
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 101);
+        let (mut writer, mut reader) = socket.split();
+
+        // the frames of the client arrive faster than the heartbeat period without postponing it
+        let started = tokio::time::Instant::now();
+        let chatter = tokio::spawn(async move {
+            for _ in 0..10 {
+                writer.send(Message::Text("hello".to_string())).await.unwrap();
+                tokio::time::delay_for(Duration::from_millis(50)).await;
+            }
+            writer
+        });
+        let heartbeat = tokio::time::timeout(Duration::from_secs(1), reader.next()).await.unwrap();
+        assert!(heartbeat.unwrap().unwrap().to_text().unwrap().contains("Heartbeat"));
+        assert!(started.elapsed() < Duration::from_millis(600));
+        let _ = chatter.await.unwrap();
+
+        let event = Event::UnknowDataReceived(RawFrame::new("20;01;x;", Utc::now()));
+        live_stream.send(get_external_message("node", "key", &event, Utc::now()).unwrap()).unwrap();
+        loop {
+            let message = tokio::time::timeout(Duration::from_secs(1), reader.next()).await.unwrap();
+            let text = message.unwrap().unwrap().to_text().unwrap().to_string();
+            if !text.contains("Heartbeat") {
+                assert!(text.contains("SensorUnknowDataReceived"));
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn prometheus_metrics() {
+        let request = Request::get("/metrics").body(Body::empty()).unwrap();
+        let response = handle(request, context()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let text = String::from_utf8_lossy(&body);
+        assert!(text.contains("# TYPE ayasha_raw_lines_total counter"));
+        assert!(text.contains("ayasha_sensor_value{protocol=\"oregon\",probe_id=\"1a2b\",value_name=\"temperature\"} 21.5"));
+    }
+
+    #[test]
+    fn range_parameters() {
+        let id = SensorIdentifier::new("1a2b", "oregon", "temperature");
+        match range_query(id.clone(), "from=2020-09-12T10%3A00%3A00%2B02%3A00&limit=10").unwrap() {
+            Query::Range { from, to, limit, .. } => {
+                assert_eq!(from, Some("2020-09-12T08:00:00Z".parse::<DateTime<Utc>>().unwrap()));
+                assert_eq!((to, limit), (None, Some(10)));
+            }
+            _ => panic!("range expected"),
+        }
+        assert!(range_query(id, "page=2").is_err());
+    }
+
+    #[test]
+    fn stream_parameters() {
+        let filter = stream_filter("protocol=oregon&sensor=1a2b&sensor=3c4d").unwrap();
+        assert_eq!(filter.protocols, vec!["oregon"]);
+        assert_eq!(filter.sensors, vec!["1a2b", "3c4d"]);
+        assert!(filter.titles.contains(&"SensorValueChanged".to_string()));
+        assert!(stream_filter("title=SensorReset").is_err());
+    }
+}
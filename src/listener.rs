@@ -1,16 +1,32 @@
+use crate::config::SerialConfig;
 use crate::errors::*;
 use crate::domain::command_event::Command;
+use crate::domain::errors::DomainError;
+use crate::domain::rf_command::{parse_acknowledgement, rf_debug_line, RfCommand, RfCommandReply, RfCommandSender};
+use crate::metrics::METRICS;
+use crate::shutdown::Shutdown;
 use crate::state_actor::MessageSender;
+use crate::transport;
 
 use bytes::{BufMut, BytesMut};
+use chrono::{DateTime, Utc};
 use futures::{sink::SinkExt, stream::StreamExt};
-use std::{env, io, str};
-use tokio_util::codec::{Decoder, Encoder};
+use serde::Serialize;
+use snafu::ResultExt;
+use std::collections::VecDeque;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use std::{io, str};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio_util::codec::{Decoder, Encoder, Framed};
 
-#[cfg(unix)]
-const DEFAULT_TTY: &str = "/dev/ttyACM0";
-#[cfg(windows)]
-const DEFAULT_TTY: &str = "COM1";
+const DEBUG_ENGAGE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time given to the RFLink to acknowledge a command
+pub const RF_COMMAND_TIMEOUT: Duration = Duration::from_secs(3);
+/// Text preceding the firmware version in the RFLink banner
+const RFLINK_BANNER: &str = "RFLink Gateway ";
 
 struct LineCodec;
 
@@ -43,74 +59,507 @@ impl Encoder<String> for LineCodec {
     }
 }
 
-pub fn start_listening(messager: MessageSender) {
-    println!("Start listening");
-    let result = listen(messager.clone());
-    match result {
-        Ok(_) => (),
-        Err(e) => {
-            println!("error during read: {}", e);
-            start_listening(messager);
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkStatus {
+    Connecting,
+    Connected,
+    Retrying,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LinkState {
+    pub status: LinkStatus,
+    pub last_error: Option<String>,
+    pub reconnect_count: u64,
+    pub last_change: DateTime<Utc>,
+    /// reception time of the last line read from the RFLink
+    pub last_line: Option<DateTime<Utc>>,
+    /// version announced by the RFLink banner, ex: `V1.1 - R46`
+    pub firmware: Option<String>,
+}
+
+/// Shared view on the state of the RFLink connection, updated by the listener task.
+#[derive(Clone)]
+pub struct LinkMonitor {
+    inner: Arc<RwLock<LinkState>>,
+}
+
+impl LinkMonitor {
+    pub fn new() -> LinkMonitor {
+        LinkMonitor {
+            inner: Arc::new(RwLock::new(LinkState {
+                status: LinkStatus::Connecting,
+                last_error: None,
+                reconnect_count: 0,
+                last_change: chrono::Utc::now(),
+                last_line: None,
+                firmware: None,
+            })),
         }
-    };
+    }
+
+    pub fn state(&self) -> LinkState {
+        self.inner.read().expect("link state lock poisoned").clone()
+    }
+
+    fn update<F>(&self, change: F)
+    where
+        F: FnOnce(&mut LinkState),
+    {
+        let mut state = self.inner.write().expect("link state lock poisoned");
+        change(&mut state);
+        state.last_change = chrono::Utc::now();
+    }
+
+    fn set_connected(&self) {
+        self.update(|s| s.status = LinkStatus::Connected);
+    }
+
+    /// a line is not a change of the link state, `last_change` is kept
+    fn line_received(&self, line: &str) {
+        let firmware = parse_firmware(line);
+        let mut state = self.inner.write().expect("link state lock poisoned");
+        state.last_line = Some(chrono::Utc::now());
+        if firmware.is_some() {
+            state.firmware = firmware;
+        }
+    }
+
+    fn set_retrying(&self, error: &RfError) {
+        let message = error.to_string();
+        self.update(move |s| {
+            s.status = LinkStatus::Retrying;
+            s.last_error = Some(message);
+            s.reconnect_count += 1;
+        });
+    }
+}
+
+/// Version of the banner sent by the RFLink at startup,
+/// ex: `20;00;Nodo RadioFrequencyLink - RFLink Gateway V1.1 - R46;`
+fn parse_firmware(line: &str) -> Option<String> {
+    let start = line.find(RFLINK_BANNER)? + RFLINK_BANNER.len();
+    let version = line[start..].trim().trim_end_matches(';').trim();
+    match version.is_empty() {
+        true => None,
+        false => Some(version.to_string()),
+    }
+}
+
+struct PendingCommand {
+    line: String,
+    reply: RfCommandReply,
+}
+
+/// Command written to the RFLink, its acknowledgement expected before `deadline`
+struct WaitingAck {
+    reply: RfCommandReply,
+    deadline: Instant,
 }
 
-fn listen(messager: MessageSender) -> Result<()> {
-
-    let mut settings = tokio_serial::SerialPortSettings::default();
-    settings.baud_rate = 57600;
-    settings.data_bits = tokio_serial::DataBits::Eight;
-    settings.flow_control = tokio_serial::FlowControl::None;
-    settings.parity = tokio_serial::Parity::None;
-    settings.stop_bits = tokio_serial::StopBits::One;
-    let mut port = tokio_serial::Serial::from_path(DEFAULT_TTY, &settings).unwrap();
-
-    #[cfg(unix)]
-    port.set_exclusive(false)
-        .expect("Unable to set serial port exclusive to false");
-
-    let (sender, receiver) = std::sync::mpsc::channel::<bool>();
-
-    tokio::spawn(async move {
-        let mut io = LineCodec.framed(port);
-        let a = io.next().await;
-        let data = a.unwrap().expect("Failed to read line");
-        println!("{}", data);
-
-        io.send("10;rfdebug=on;\r\n".to_string())
-            .await
-            .expect("rf link comm error");
-        let response_result = io.next().await;
-        let is_debug = match response_result {
-            None => {
-                println!("debug is None");
-                false
+/// Forget the commands whose caller gave up or whose acknowledgement is late,
+/// so that the next acknowledgement goes to a command still waiting.
+fn expire(waiting_ack: &mut VecDeque<WaitingAck>) {
+    let now = Instant::now();
+    waiting_ack.retain(|w| !w.reply.is_closed() && w.deadline > now);
+}
+
+/// Writer half of the RFLink link, commands are queued until the listener task writes them.
+#[derive(Clone)]
+pub struct LinkWriter {
+    inner: UnboundedSender<PendingCommand>,
+}
+
+pub struct LinkCommands {
+    inner: UnboundedReceiver<PendingCommand>,
+}
+
+pub fn command_channel() -> (LinkWriter, LinkCommands) {
+    let (sender, receiver) = unbounded_channel::<PendingCommand>();
+    (LinkWriter { inner: sender }, LinkCommands { inner: receiver })
+}
+
+impl RfCommandSender for LinkWriter {
+    fn send(&self, command: RfCommand, reply: RfCommandReply) {
+        match command.to_rflink_line() {
+            Ok(line) => self.send_line(line, reply),
+            Err(e) => {
+                let _ = reply.send(Err(e));
             }
-            Some(result) => match result {
-                Ok(data) => data.contains("RFDEBUG=ON"),
-                Err(e) => {
-                    println!("debug engage error: {}", e);
-                    false
-                }
-            },
+        }
+    }
+
+    fn send_line(&self, line: String, reply: RfCommandReply) {
+        if let Err(e) = self.inner.send(PendingCommand { line, reply }) {
+            let _ = (e.0).reply.send(Err(DomainError::RfLinkUnavailableError));
+        }
+    }
+}
+
+/// Spawn the listener task, it ends once the shutdown is requested and every `LinkWriter` dropped.
+pub fn start_listening(
+    messager: MessageSender,
+    commands: LinkCommands,
+    config: &SerialConfig,
+    shutdown: Shutdown,
+) -> (LinkMonitor, JoinHandle<()>) {
+    let monitor = LinkMonitor::new();
+    let task = tokio::spawn(supervise(messager, commands, config.clone(), monitor.clone(), shutdown));
+    (monitor, task)
+}
+
+async fn supervise(
+    messager: MessageSender,
+    mut commands: LinkCommands,
+    config: SerialConfig,
+    monitor: LinkMonitor,
+    mut shutdown: Shutdown,
+) {
+    let initial_delay = Duration::from_secs(config.reconnect_initial_delay_secs);
+    let max_delay = Duration::from_secs(config.reconnect_max_delay_secs);
+    let mut delay = initial_delay;
+    loop {
+        println!("Start listening on {}", config.device);
+        let error = match listen(&messager, &mut commands, &config, &monitor, &mut shutdown).await {
+            Ok(_) if shutdown.is_requested() => return,
+            Ok(_) => RfError::ConnectionClosed,
+            Err(e) => e,
         };
+        if monitor.state().status == LinkStatus::Connected {
+            delay = initial_delay;
+        }
+        println!("rflink link lost: {}, retry in {:?}", error, delay);
+        monitor.set_retrying(&error);
+        tokio::select! {
+            _ = tokio::time::delay_for(delay) => (),
+            _ = shutdown.requested() => return,
+        }
+        delay = std::cmp::min(delay * 2, max_delay);
+    }
+}
+
+async fn listen(
+    messager: &MessageSender,
+    commands: &mut LinkCommands,
+    config: &SerialConfig,
+    monitor: &LinkMonitor,
+    shutdown: &mut Shutdown,
+) -> Result<()> {
+    let stream = transport::connect(config).await?;
+
+    let mut io = LineCodec.framed(stream);
+    engage_debug(&mut io, monitor).await?;
+    monitor.set_connected();
+    println!("rflink debug engaged on {}", config.device);
 
-        if is_debug {
-            sender.send(true).expect("inter task communication error");
-            while let Some(line_result) = io.next().await {
-                let line = line_result.expect("Failed to read line");
+    let (mut writer, mut lines) = io.split();
+    // replies of the commands written to the RFLink, in sending order
+    let mut waiting_ack: VecDeque<WaitingAck> = VecDeque::new();
+    // once the shutdown is requested, frames are no longer handed to the actor but
+    // the commands it still routes are written until it drops the last LinkWriter
+    let mut stopping = false;
+    loop {
+        tokio::select! {
+            line_result = lines.next() => {
+                let line = match line_result {
+                    Some(result) => result.context(ReadError)?,
+                    None => return Ok(()),
+                };
                 println!("{}", line);
-                messager.send(Command::IncomingData(line));
+                METRICS.raw_line();
+                monitor.line_received(&line);
+                expire(&mut waiting_ack);
+                let ack = match waiting_ack.is_empty() {
+                    true => None,
+                    false => parse_acknowledgement(&line),
+                };
+                match ack {
+                    Some(result) => {
+                        if let Some(waiting) = waiting_ack.pop_front() {
+                            let _ = waiting.reply.send(result);
+                        }
+                    }
+                    None if stopping => println!("frame ignored during shutdown"),
+                    // waits when the actor lags behind, the RFLink buffers meanwhile
+                    None => {
+                        if let Err(e) = messager.send(Command::IncomingData(line)).await {
+                            // the actor closed its queue for the shutdown before this loop saw it,
+                            // the RF debug is still turned off once its last commands are written
+                            if !shutdown.is_requested() {
+                                return Err(e);
+                            }
+                            stopping = true;
+                        }
+                    }
+                }
             }
+            pending = commands.inner.recv() => match pending {
+                // the caller already gave up, a late command would surprise everyone
+                Some(pending) if pending.reply.is_closed() => (),
+                Some(pending) => {
+                    println!("send {}", pending.line);
+                    writer.send(pending.line).await.context(WriteError)?;
+                    waiting_ack.push_back(WaitingAck {
+                        reply: pending.reply,
+                        deadline: Instant::now() + RF_COMMAND_TIMEOUT,
+                    });
+                }
+                None => {
+                    writer.send(rf_debug_line(false)).await.context(WriteError)?;
+                    println!("rflink debug disengaged on {}", config.device);
+                    return Ok(());
+                }
+            },
+            _ = shutdown.requested(), if !stopping => stopping = true,
         }
-        sender.send(false).expect("inter task communication error");
-    });
-
-    match receiver.recv() {
-        Ok(engage) => match engage {
-            true => Ok(()),
-            false => Err(RfError::DebugNotEngage),
-        },
+    }
+}
+
+/// Ask the RFLink to switch to debug mode and wait for its confirmation.
+/// Lines received before the confirmation (banner, pending frames) are ignored.
+async fn engage_debug<T>(io: &mut Framed<T, LineCodec>, monitor: &LinkMonitor) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    io.send("10;rfdebug=on;\r\n".to_string())
+        .await
+        .context(WriteError)?;
+
+    let confirmation = async {
+        while let Some(line_result) = io.next().await {
+            let line = line_result.context(ReadError)?;
+            println!("{}", line);
+            METRICS.raw_line();
+            monitor.line_received(&line);
+            if line.contains("RFDEBUG=ON") {
+                return Ok(());
+            }
+        }
+        Err(RfError::ConnectionClosed)
+    };
+
+    match tokio::time::timeout(DEBUG_ENGAGE_TIMEOUT, confirmation).await {
+        Ok(result) => result,
         Err(_) => Err(RfError::DebugNotEngage),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn listen_over_tcp() {
+        let mut bridge = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = bridge.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = bridge.accept().await.unwrap();
+            let (reader, mut writer) = tokio::io::split(socket);
+            let mut reader = BufReader::new(reader);
+            writer
+                .write_all(b"20;00;Nodo RadioFrequencyLink - RFLink Gateway V1.1 - R46;\r\n")
+                .await
+                .unwrap();
+            let mut request = String::new();
+            reader.read_line(&mut request).await.unwrap();
+            assert!(request.starts_with("10;rfdebug=on;"));
+            writer.write_all(b"20;01;RFDEBUG=ON;\r\n").await.unwrap();
+            writer
+                .write_all(b"20;02;Oregon Temp;ID=0410;TEMP=0153;BAT=OK;\r\n")
+                .await
+                .unwrap();
+        });
+
+        let (sender, mut receiver) = tokio::sync::mpsc::channel::<Command>(8);
+        let config = SerialConfig {
+            device: format!("tcp://{}", address),
+            ..SerialConfig::default()
+        };
+        let monitor = LinkMonitor::new();
+
+        let (_writer, mut commands) = command_channel();
+        let (_trigger, mut shutdown) = crate::shutdown::channel();
+        let result = listen(&MessageSender::from_sender(sender), &mut commands, &config, &monitor, &mut shutdown).await;
+
+        assert!(result.is_ok());
+        let state = monitor.state();
+        assert_eq!(state.status, LinkStatus::Connected);
+        assert_eq!(state.firmware, Some("V1.1 - R46".to_string()));
+        assert!(state.last_line.is_some());
+        match receiver.try_recv() {
+            Ok(Command::IncomingData(line)) => assert!(line.contains("Oregon Temp")),
+            _ => panic!("the frame should be forwarded to the state actor"),
+        }
+    }
+
+    #[tokio::test]
+    async fn send_command_over_tcp() {
+        let mut bridge = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = bridge.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = bridge.accept().await.unwrap();
+            let (reader, mut writer) = tokio::io::split(socket);
+            let mut reader = BufReader::new(reader);
+            let mut request = String::new();
+            reader.read_line(&mut request).await.unwrap();
+            writer.write_all(b"20;01;RFDEBUG=ON;\r\n").await.unwrap();
+            request.clear();
+            while request.trim().is_empty() {
+                request.clear();
+                reader.read_line(&mut request).await.unwrap();
+            }
+            assert_eq!(request, "10;NewKaku;00c142;1;ON;\n");
+            writer.write_all(b"20;02;OK;\r\n").await.unwrap();
+            // keep the link open until the test ends
+            reader.read_line(&mut request).await.unwrap();
+        });
+
+        let (sender, _receiver) = tokio::sync::mpsc::channel::<Command>(8);
+        let config = SerialConfig {
+            device: format!("tcp://{}", address),
+            ..SerialConfig::default()
+        };
+        let monitor = LinkMonitor::new();
+        let (writer, mut commands) = command_channel();
+        let (reply, ack) = tokio::sync::oneshot::channel();
+        writer.send(
+            RfCommand {
+                protocol: "NewKaku".to_string(),
+                device_id: "00c142".to_string(),
+                switch: "1".to_string(),
+                command: crate::domain::switch_event::SwitchCommand::On,
+            },
+            reply,
+        );
+
+        let (_trigger, mut shutdown) = crate::shutdown::channel();
+        tokio::spawn(async move {
+            let _ = listen(&MessageSender::from_sender(sender), &mut commands, &config, &monitor, &mut shutdown).await;
+        });
+
+        let result = tokio::time::timeout(RF_COMMAND_TIMEOUT, ack).await;
+        assert!(result.unwrap().unwrap().is_ok());
+    }
+
+    #[test]
+    fn abandoned_commands_expire() {
+        let (gave_up, receiver) = tokio::sync::oneshot::channel();
+        drop(receiver);
+        let (late, _late_receiver) = tokio::sync::oneshot::channel();
+        let (waiting, _waiting_receiver) = tokio::sync::oneshot::channel();
+        let mut waiting_ack = VecDeque::new();
+        waiting_ack.push_back(WaitingAck { reply: gave_up, deadline: Instant::now() + RF_COMMAND_TIMEOUT });
+        waiting_ack.push_back(WaitingAck { reply: late, deadline: Instant::now() });
+        waiting_ack.push_back(WaitingAck { reply: waiting, deadline: Instant::now() + RF_COMMAND_TIMEOUT });
+
+        expire(&mut waiting_ack);
+        assert_eq!(waiting_ack.len(), 1);
+        assert!(!waiting_ack[0].reply.is_closed());
+    }
+
+    #[tokio::test]
+    async fn shutdown_disengages_debug() {
+        let mut bridge = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = bridge.local_addr().unwrap();
+        let rflink = tokio::spawn(async move {
+            let (socket, _) = bridge.accept().await.unwrap();
+            let (reader, mut writer) = tokio::io::split(socket);
+            let mut reader = BufReader::new(reader);
+            let mut request = String::new();
+            reader.read_line(&mut request).await.unwrap();
+            writer.write_all(b"20;01;RFDEBUG=ON;\r\n").await.unwrap();
+            request.clear();
+            while request.trim().is_empty() {
+                request.clear();
+                reader.read_line(&mut request).await.unwrap();
+            }
+            request
+        });
+
+        let (sender, _receiver) = tokio::sync::mpsc::channel::<Command>(8);
+        let config = SerialConfig {
+            device: format!("tcp://{}", address),
+            ..SerialConfig::default()
+        };
+        let monitor = LinkMonitor::new();
+        let (writer, mut commands) = command_channel();
+        let (trigger, mut shutdown) = crate::shutdown::channel();
+        trigger.trigger();
+        drop(writer);
+
+        let result = listen(&MessageSender::from_sender(sender), &mut commands, &config, &monitor, &mut shutdown).await;
+        assert!(result.is_ok());
+        assert_eq!(rflink.await.unwrap(), "10;rfdebug=off;\n");
+    }
+
+    #[tokio::test]
+    async fn frame_after_actor_stopped() {
+        let mut bridge = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = bridge.local_addr().unwrap();
+        let rflink = tokio::spawn(async move {
+            let (socket, _) = bridge.accept().await.unwrap();
+            let (reader, mut writer) = tokio::io::split(socket);
+            let mut reader = BufReader::new(reader);
+            let mut request = String::new();
+            reader.read_line(&mut request).await.unwrap();
+            writer.write_all(b"20;01;RFDEBUG=ON;\r\n").await.unwrap();
+            writer
+                .write_all(b"20;02;Oregon Temp;ID=0410;TEMP=0153;BAT=OK;\r\n")
+                .await
+                .unwrap();
+            request.clear();
+            while request.trim().is_empty() {
+                request.clear();
+                reader.read_line(&mut request).await.unwrap();
+            }
+            request
+        });
+
+        // the actor already closed its queue
+        let (sender, receiver) = tokio::sync::mpsc::channel::<Command>(8);
+        drop(receiver);
+        let config = SerialConfig {
+            device: format!("tcp://{}", address),
+            ..SerialConfig::default()
+        };
+        let monitor = LinkMonitor::new();
+        let (writer, mut commands) = command_channel();
+        let (trigger, mut shutdown) = crate::shutdown::channel();
+        trigger.trigger();
+        tokio::spawn(async move {
+            tokio::time::delay_for(Duration::from_millis(200)).await;
+            drop(writer);
+        });
+
+        let result = listen(&MessageSender::from_sender(sender), &mut commands, &config, &monitor, &mut shutdown).await;
+        assert!(result.is_ok());
+        assert_eq!(rflink.await.unwrap(), "10;rfdebug=off;\n");
+    }
+
+    #[tokio::test]
+    async fn listen_tcp_refused() {
+        let bridge = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = bridge.local_addr().unwrap();
+        drop(bridge);
+
+        let (sender, _receiver) = tokio::sync::mpsc::channel::<Command>(8);
+        let config = SerialConfig {
+            device: format!("tcp://{}", address),
+            ..SerialConfig::default()
+        };
+        let monitor = LinkMonitor::new();
+
+        let (_writer, mut commands) = command_channel();
+        let (_trigger, mut shutdown) = crate::shutdown::channel();
+        let result = listen(&MessageSender::from_sender(sender), &mut commands, &config, &monitor, &mut shutdown).await;
+
+        match result {
+            Err(RfError::OpenPortError { .. }) => (),
+            _ => panic!("connection should be refused"),
+        }
+        assert_eq!(monitor.state().status, LinkStatus::Connecting);
+    }
+}
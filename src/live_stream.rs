@@ -0,0 +1,191 @@
+use crate::domain::errors::Result;
+use crate::domain::external_message::{ExternalMessage, MessageFilter, MessageSender};
+use crate::shutdown::Shutdown;
+use chrono::Utc;
+use futures::{sink::SinkExt, stream::StreamExt};
+use hyper::{Body, Response, StatusCode};
+use sha1::{Digest, Sha1};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, RecvError};
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::protocol::{Message, Role};
+use tokio_tungstenite::WebSocketStream;
+
+/// Messages kept for a client reading slower than the events arrive
+const CLIENT_BUFFER: usize = 256;
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Titles pushed to the streaming clients
+pub const STREAMED_TITLES: &[&str] = &["SensorValueChanged", "SensorUnknowDataReceived"];
+
+/// Sink fanning the published messages out to the SSE and WebSocket clients.
+#[derive(Clone)]
+pub struct LiveStream {
+    sender: broadcast::Sender<ExternalMessage>,
+}
+
+impl LiveStream {
+    pub fn new() -> LiveStream {
+        let (sender, _) = broadcast::channel(CLIENT_BUFFER);
+        LiveStream { sender }
+    }
+}
+
+impl MessageSender for LiveStream {
+    fn send(&self, msg: ExternalMessage) -> Result<()> {
+        // no client listening is not an error
+        let _ = self.sender.send(msg);
+        Ok(())
+    }
+}
+
+/// Settings of one client connection.
+pub struct Subscription {
+    pub filter: MessageFilter,
+    pub heartbeat: Duration,
+    pub shutdown: Shutdown,
+}
+
+enum Push {
+    Message(String),
+    Heartbeat(String),
+    End,
+}
+
+/// Next message for the client, a heartbeat at `heartbeat_at` without any.
+async fn next_push(
+    receiver: &mut broadcast::Receiver<ExternalMessage>,
+    subscription: &mut Subscription,
+    heartbeat_at: Instant,
+) -> Push {
+    loop {
+        tokio::select! {
+            received = receiver.recv() => match received {
+                Ok(msg) if subscription.filter.matches(&msg.event) => return Push::Message(msg.inner_message),
+                Ok(_) => (),
+                Err(RecvError::Lagged(count)) => println!("live stream client too slow, {} messages skipped", count),
+                Err(RecvError::Closed) => return Push::End,
+            },
+            // messages filtered out or skipped never postpone the heartbeat
+            _ = tokio::time::delay_until(heartbeat_at) => {
+                let heartbeat = serde_json::json!({"title": "Heartbeat", "timestamp": Utc::now()});
+                return Push::Heartbeat(heartbeat.to_string());
+            }
+            _ = subscription.shutdown.requested() => return Push::End,
+        }
+    }
+}
+
+/// `text/event-stream` response, the events as `data` and the heartbeats as `event: heartbeat`.
+pub fn server_sent_events(stream: &LiveStream, mut subscription: Subscription) -> Response<Body> {
+    let mut receiver = stream.sender.subscribe();
+    let (mut body, response) = Body::channel();
+    tokio::spawn(async move {
+        loop {
+            let heartbeat_at = Instant::now() + subscription.heartbeat;
+            let chunk = match next_push(&mut receiver, &mut subscription, heartbeat_at).await {
+                Push::Message(data) => format!("data: {}\n\n", data),
+                Push::Heartbeat(data) => format!("event: heartbeat\ndata: {}\n\n", data),
+                Push::End => return,
+            };
+            // the client is gone
+            if body.send_data(chunk.into()).await.is_err() {
+                return;
+            }
+        }
+    });
+    Response::builder()
+        .header("content-type", "text/event-stream")
+        .header("cache-control", "no-cache")
+        .body(response)
+        .unwrap()
+}
+
+/// Accept the WebSocket upgrade of a request, the events and heartbeats being sent as text messages.
+pub fn websocket(stream: &LiveStream, key: &str, body: Body, mut subscription: Subscription) -> Response<Body> {
+    let mut receiver = stream.sender.subscribe();
+    tokio::spawn(async move {
+        let upgraded = match body.on_upgrade().await {
+            Ok(u) => u,
+            Err(e) => {
+                println!("websocket upgrade failed: {}", e);
+                return;
+            }
+        };
+        let socket = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+        let (mut writer, mut reader) = socket.split();
+        // the frames read from the client do not postpone the heartbeat either
+        let mut heartbeat_at = Instant::now() + subscription.heartbeat;
+        loop {
+            let message = tokio::select! {
+                push = next_push(&mut receiver, &mut subscription, heartbeat_at) => match push {
+                    Push::Message(text) | Push::Heartbeat(text) => Message::Text(text),
+                    Push::End => Message::Close(None),
+                },
+                // anything read is ignored, pings are answered by tungstenite
+                incoming = reader.next() => match incoming {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                    Some(Ok(_)) => continue,
+                },
+            };
+            let closing = message.is_close();
+            if writer.send(message).await.is_err() || closing {
+                return;
+            }
+            heartbeat_at = Instant::now() + subscription.heartbeat;
+        }
+    });
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header("upgrade", "websocket")
+        .header("connection", "upgrade")
+        .header("sec-websocket-accept", accept_key(key))
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// `Sec-WebSocket-Accept` answering the `Sec-WebSocket-Key` of the client (RFC 6455)
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(WEBSOCKET_GUID.as_bytes());
+    base64::encode(sha1.finalize())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::command_event::Event;
+    use crate::domain::external_message::get_external_message;
+    use crate::domain::raw_frame::RawFrame;
+
+    #[test]
+    fn websocket_accept_key() {
+        // example of RFC 6455
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[tokio::test]
+    async fn push_events_and_heartbeats() {
+        let stream = LiveStream::new();
+        let (_trigger, shutdown) = crate::shutdown::channel();
+        let subscription = Subscription {
+            filter: MessageFilter::default(),
+            heartbeat: Duration::from_millis(50),
+            shutdown,
+        };
+        let response = server_sent_events(&stream, subscription);
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        let mut body = response.into_body();
+
+        let event = Event::UnknowDataReceived(RawFrame::new("20;01;x;", chrono::Utc::now()));
+        stream.send(get_external_message("node", "key", &event, chrono::Utc::now()).unwrap()).unwrap();
+        let chunk = body.next().await.unwrap().unwrap();
+        let chunk = String::from_utf8_lossy(&chunk).to_string();
+        assert!(chunk.starts_with("data: {") && chunk.contains("SensorUnknowDataReceived"));
+
+        let chunk = body.next().await.unwrap().unwrap();
+        let chunk = String::from_utf8_lossy(&chunk).to_string();
+        assert!(chunk.starts_with("event: heartbeat\ndata: {") && chunk.contains("Heartbeat"));
+    }
+}
@@ -1,98 +1,204 @@
+mod config;
 mod domain;
 mod errors;
+mod file_journal;
+mod file_sender;
+mod health;
+mod http_api;
 mod listener;
+mod live_stream;
+mod message_queue;
+mod metrics;
+mod mqtt;
+mod mqtt_sender;
+mod outbox;
 mod state_actor;
+mod rabbit_consumer;
 mod rabbit_sender;
+mod segment_log;
+mod shutdown;
+mod sinks;
+mod transport;
+mod webhook_sender;
 
 extern crate lazy_static;
 extern crate serde;
 
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Error, Method, Response, Server, StatusCode};
-use std::net::SocketAddr;
+use hyper::{Error, Server};
 
+use crate::config::{CliOptions, Config, ExportOptions, Subcommand};
+use crate::domain::clock::SystemClock;
+use crate::domain::query::Query;
+use crate::errors::*;
 use crate::domain::sensor::SensorRepository;
-use crate::domain::errors::DomainError;
-use crate::domain::command_event::Command;
+use crate::domain::external_message::{FilteredSender, MessageFilter};
+use crate::file_journal::JournalReader;
+use crate::health::HealthCheck;
+use crate::http_api::HttpContext;
+use crate::live_stream::LiveStream;
+use snafu::ResultExt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::Duration;
+use structopt::StructOpt;
 
 #[tokio::main]
 async fn main() {
-    let message_sender = state_actor::init_actor();
+    let mut options = CliOptions::from_args();
+    if options.print_schema {
+        println!("{}", domain::message_schema::json_schema());
+        return;
+    }
+    let query = options.query.clone();
+    let command = options.command.take();
+    let config = match Config::load(options) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(query) = query {
+        if let Err(e) = print_query(&config, &query) {
+            eprintln!("query error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(Subcommand::Export(export)) = command {
+        if let Err(e) = export_history(&config, &export) {
+            eprintln!("export error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let (link_writer, link_commands) = listener::command_channel();
+    let (repo, journal) = match state_actor::load_repository(&config.storage) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("storage error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let live_stream = LiveStream::new();
+    let mut sinks = match sinks::start(&config) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("sink error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    sinks.add(FilteredSender {
+        name: "live stream".to_string(),
+        filter: MessageFilter::default(),
+        sender: Box::new(live_stream.clone()),
+    });
+    let (shutdown_trigger, shutdown) = shutdown::channel();
+    let (message_sender, actor) = state_actor::init_actor(&config, link_writer, repo, journal, Box::new(sinks), Box::new(SystemClock), shutdown.clone());
 
-    listener::start_listening(message_sender.clone());
-    let addr = SocketAddr::from(([0, 0, 0, 0], 7000));
+    let (link_monitor, listener) = listener::start_listening(message_sender.clone(), link_commands, &config.serial, shutdown.clone());
+    if let Some(queue) = &config.amqp.command_queue {
+        rabbit_consumer::start(&config.amqp, queue, &config.node_name, message_sender.clone(), link_monitor.clone());
+    }
+    let addr = config.http.bind;
 
+    let context = HttpContext {
+        sender: message_sender,
+        link_monitor,
+        live_stream,
+        heartbeat: Duration::from_secs(config.http.heartbeat_secs),
+        shutdown,
+        health: HealthCheck {
+            stall_threshold: Duration::from_secs(config.http.ingestion_stall_secs),
+            started: chrono::Utc::now(),
+        },
+    };
     let make_service = make_service_fn(move |_| {
-        let sender_read = message_sender.clone();
-
+        let context = context.clone();
         async move {
-            let sender_read = sender_read.clone();
             Ok::<_, Error>(service_fn(move |req| {
-                let sender_read = sender_read.clone();
-                async move {
-                    let sender_read = sender_read.clone();
-                    match (req.method(), req.uri().path()) {
-                (&Method::GET, "/alive") => Ok::<_,Error>(Response::new(Body::from("yes"))),
-                (&Method::GET, "/all_sensors") => {
-                    let (sender, receiver) = std::sync::mpsc::channel::<Box<String>>();
-                    let mess = Command::GetData(Box::new(move |state:&SensorRepository| {
-                        let json = state.get_all_state()?;
-                        match sender.send(Box::new(json)) {
-                            Ok(_) => Ok(()),
-                            Err(e) => Err(DomainError::DataExtractionError{value: e.to_string()})
-                        }?;
-                        Ok(vec![])
+                let context = context.clone();
+                async move { Ok::<_, Error>(http_api::handle(req, context).await) }
+            }))
+        }
+    });
 
-                    }));
-                    sender_read.send(mess);
-                    match receiver.recv_timeout(std::time::Duration::from_secs(1)) {
-                        Ok(data) => {
-                            Ok::<_,Error>(Response::builder().header("content-type", "application/json").header("charset", "UTF-8").body(Body::from(*data)).unwrap())
-                        },
-                        Err(e) => {
-                            println!("error in hyper: {}", e);
-                            Ok::<_,Error>(Response::new(Body::from(e.to_string())))
-                        }
-                    }
-                },
-                (&Method::GET, "/all_sensors_last_value") => {
-                    let (sender, receiver) = std::sync::mpsc::channel::<Box<String>>();
-                    let mess = Command::GetData(Box::new(move |state:&SensorRepository| {
-                        let json = state.get_last_values()?;
-                        match sender.send(Box::new(json)) {
-                            Ok(_) => Ok(()),
-                            Err(e) => Err(DomainError::DataExtractionError{value: e.to_string()})
-                        }?;
-                        Ok(vec![])
+    let (stop_http, http_stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = Server::bind(&addr).serve(make_service).with_graceful_shutdown(async {
+        let _ = http_stopped.await;
+    });
+    let mut server = tokio::spawn(server);
 
-                    }));
-                    sender_read.send(mess);
-                    match receiver.recv_timeout(std::time::Duration::from_secs(1)) {
-                        Ok(data) => {
-                            Ok::<_,Error>(Response::builder().header("content-type", "application/json").header("charset", "UTF-8").body(Body::from(*data)).unwrap())
-                        },
-                        Err(e) => {
-                            println!("error in hyper: {}", e);
-                            Ok::<_,Error>(Response::new(Body::from(e.to_string())))
-                        }
-                    }
-                },
-                _ => {
-                        let mut not_found = Response::default();
-                        *not_found.status_mut() = StatusCode::NOT_FOUND;
-                        Ok::<_,Error>(not_found)
+    let server_stopped = tokio::select! {
+        _ = shutdown::signal() => false,
+        result = &mut server => {
+            if let Ok(Err(e)) = result {
+                eprintln!("server error: {}", e);
+            }
+            true
         }
+    };
+
+    // serial frames are no longer read, the actor handles its queue, flushes the sinks
+    // and the storage, then the listener turns the RF debug off
+    println!("shutting down");
+    shutdown_trigger.trigger();
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout_secs);
+    // the actor gets the sinks delay, then as much again to write the storage
+    if tokio::time::timeout(shutdown_timeout * 2, actor).await.is_err() {
+        println!("state actor not stopped after {:?}", shutdown_timeout * 2);
     }
-                }
-            }))
+    if tokio::time::timeout(shutdown_timeout, listener).await.is_err() {
+        println!("rflink link not closed after {:?}", shutdown_timeout);
+    }
+    if !server_stopped {
+        let _ = stop_http.send(());
+        if tokio::time::timeout(shutdown_timeout, server).await.is_err() {
+            println!("http connections still open after {:?}, closed", shutdown_timeout);
         }
-    });
+    }
+
+    println!("end");
+}
 
-    let server = Server::bind(&addr).serve(make_service);
+/// Answer `query` from the stored history, without starting the gateway.
+fn print_query(config: &Config, query: &str) -> Result<()> {
+    let query = serde_json::from_str::<Query>(query).context(QueryParseError)?;
+    let repo = match open_history(config)? {
+        Some(reader) => reader.load().context(InternalDomainError)?,
+        None => SensorRepository::new(),
+    };
+    let result = domain::query::execute(&query, &repo).context(InternalDomainError)?;
+    println!("{}", serde_json::to_string_pretty(&result).context(SerialisationError)?);
+    Ok(())
+}
 
-    if let Err(e) = server.await {
-        eprintln!("server error: {}", e);
+/// Write the stored history to the export output, without starting the gateway.
+fn export_history(config: &Config, options: &ExportOptions) -> Result<()> {
+    let export = options.export()?;
+    let reader = open_history(config)?;
+    let path = match &options.output {
+        Some(path) => path.display().to_string(),
+        None => "stdout".to_string(),
+    };
+    let mut out: BufWriter<Box<dyn Write>> = match &options.output {
+        Some(output) => BufWriter::new(Box::new(File::create(output).context(ExportWriteError { path: &path })?)),
+        None => BufWriter::new(Box::new(std::io::stdout())),
+    };
+    match reader {
+        Some(reader) => reader.export(&export, &mut out).context(InternalDomainError),
+        None => out
+            .write_all(export.header().as_bytes())
+            .and_then(|_| out.flush())
+            .context(ExportWriteError { path }),
     }
+}
 
-    println!("end");
-}
\ No newline at end of file
+/// Storage read-only, it may be in use by a running gateway. `None` when storage is disabled.
+fn open_history(config: &Config) -> Result<Option<JournalReader>> {
+    if !config.storage.enabled {
+        return Ok(None);
+    }
+    JournalReader::open(&config.storage).map(Some).context(InternalDomainError)
+}
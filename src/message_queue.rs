@@ -0,0 +1,53 @@
+use crate::domain::external_message::ExternalMessage;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// Bounded in-memory queue between the state actor and a publishing task,
+/// the oldest message is dropped when full.
+#[derive(Clone)]
+pub struct MessageQueue {
+    name: String,
+    messages: Arc<Mutex<VecDeque<ExternalMessage>>>,
+    wake: Arc<Notify>,
+    max_messages: usize,
+}
+
+impl MessageQueue {
+    pub fn new(name: &str, max_messages: usize) -> MessageQueue {
+        MessageQueue {
+            name: name.to_string(),
+            messages: Arc::new(Mutex::new(VecDeque::new())),
+            wake: Arc::new(Notify::new()),
+            max_messages,
+        }
+    }
+
+    pub fn push(&self, msg: ExternalMessage) {
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() >= self.max_messages {
+            messages.pop_front();
+            println!("{} queue full, oldest message dropped", self.name);
+        }
+        messages.push_back(msg);
+        self.wake.notify();
+    }
+
+    /// oldest message, left in the queue until `pop_front`
+    pub fn front(&self) -> Option<ExternalMessage> {
+        self.messages.lock().unwrap().front().cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.lock().unwrap().len()
+    }
+
+    pub fn pop_front(&self) {
+        self.messages.lock().unwrap().pop_front();
+    }
+
+    /// resolves when a message is pushed, immediately if one was pushed since the last call
+    pub async fn pushed(&self) {
+        self.wake.notified().await
+    }
+}
@@ -0,0 +1,211 @@
+use crate::domain::errors::DomainError;
+use crate::domain::sensor::SensorValue;
+use crate::listener::LinkState;
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+lazy_static! {
+    /// Metrics of the running gateway, rendered by `GET /metrics`
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+/// Counters and gauges of the gateway, in the Prometheus text format once rendered.
+#[derive(Default)]
+pub struct Metrics {
+    raw_lines: AtomicU64,
+    frames: Mutex<BTreeMap<String, u64>>,
+    unknown_frames: AtomicU64,
+    decode_errors: Mutex<BTreeMap<&'static str, u64>>,
+    published: AtomicU64,
+    publish_failures: AtomicU64,
+    /// sum of the publish to confirmation delays, in microseconds
+    confirm_latency_micros: AtomicU64,
+    confirmations: AtomicU64,
+    queue_depth: AtomicUsize,
+}
+
+impl Metrics {
+    pub fn raw_line(&self) {
+        self.raw_lines.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `protocol` given by `domain::dispatch_line`, `None` for an unknown frame
+    pub fn frame_decoded(&self, protocol: Option<String>) {
+        match protocol {
+            Some(protocol) => *self.frames.lock().unwrap().entry(protocol).or_insert(0) += 1,
+            None => {
+                self.unknown_frames.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn decode_error(&self, error: &DomainError) {
+        *self.decode_errors.lock().unwrap().entry(error.kind()).or_insert(0) += 1;
+    }
+
+    /// Confirmation received from the broker `latency` after the publication.
+    pub fn publish_confirmed(&self, acknowledged: bool, latency: Duration) {
+        self.confirm_latency_micros.fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        self.confirmations.fetch_add(1, Ordering::Relaxed);
+        match acknowledged {
+            true => self.published.fetch_add(1, Ordering::Relaxed),
+            false => self.publish_failures.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    pub fn publish_failed(&self) {
+        self.publish_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn command_queued(&self) {
+        self.queue_depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// to be called once for every `command_queued`, when the command leaves the queue or is refused
+    pub fn command_dequeued(&self) {
+        self.queue_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Text exposition of the gateway metrics, followed by the gauges of `last_values`.
+    pub fn render(&self, link: &LinkState, last_values: &[Option<SensorValue>]) -> String {
+        let mut out = String::new();
+        let counter = |value: &AtomicU64| value.load(Ordering::Relaxed);
+
+        header(&mut out, "ayasha_raw_lines_total", "counter", "Lines received from the RFLink");
+        sample(&mut out, "ayasha_raw_lines_total", &[], counter(&self.raw_lines) as f64);
+        header(&mut out, "ayasha_frames_decoded_total", "counter", "Frames decoded, by protocol");
+        for (protocol, count) in self.frames.lock().unwrap().iter() {
+            sample(&mut out, "ayasha_frames_decoded_total", &[("protocol", protocol)], *count as f64);
+        }
+        header(&mut out, "ayasha_unknown_frames_total", "counter", "Lines of no known protocol");
+        sample(&mut out, "ayasha_unknown_frames_total", &[], counter(&self.unknown_frames) as f64);
+        header(&mut out, "ayasha_decode_errors_total", "counter", "Lines refused by the decoding, by error");
+        for (error, count) in self.decode_errors.lock().unwrap().iter() {
+            sample(&mut out, "ayasha_decode_errors_total", &[("error", error)], *count as f64);
+        }
+
+        header(&mut out, "ayasha_amqp_published_total", "counter", "Messages confirmed by the broker");
+        sample(&mut out, "ayasha_amqp_published_total", &[], counter(&self.published) as f64);
+        header(&mut out, "ayasha_amqp_publish_failures_total", "counter", "Messages refused by the broker or lost with the connection");
+        sample(&mut out, "ayasha_amqp_publish_failures_total", &[], counter(&self.publish_failures) as f64);
+        header(&mut out, "ayasha_amqp_publish_latency_seconds", "summary", "Delay between a publication and its confirmation");
+        let latency = counter(&self.confirm_latency_micros) as f64 / 1_000_000.0;
+        sample(&mut out, "ayasha_amqp_publish_latency_seconds_sum", &[], latency);
+        sample(&mut out, "ayasha_amqp_publish_latency_seconds_count", &[], counter(&self.confirmations) as f64);
+
+        header(&mut out, "ayasha_serial_reconnects_total", "counter", "Reconnections to the RFLink");
+        sample(&mut out, "ayasha_serial_reconnects_total", &[], link.reconnect_count as f64);
+        header(&mut out, "ayasha_actor_queue_depth", "gauge", "Commands waiting for the state actor");
+        sample(&mut out, "ayasha_actor_queue_depth", &[], self.queue_depth.load(Ordering::Relaxed) as f64);
+
+        let last_values = last_values.iter().flatten().collect::<Vec<&SensorValue>>();
+        header(&mut out, "ayasha_sensor_value", "gauge", "Last value of the numeric sensors");
+        for value in &last_values {
+            if let Some(number) = value.value.number() {
+                sample(&mut out, "ayasha_sensor_value", &sensor_labels(value), number);
+            }
+        }
+        header(&mut out, "ayasha_sensor_last_seen_timestamp_seconds", "gauge", "Reception time of the last value of the sensors");
+        for value in &last_values {
+            let seen = value.timestamp.timestamp_millis() as f64 / 1000.0;
+            sample(&mut out, "ayasha_sensor_last_seen_timestamp_seconds", &sensor_labels(value), seen);
+        }
+        out
+    }
+}
+
+fn sensor_labels(value: &SensorValue) -> [(&str, &str); 3] {
+    [
+        ("protocol", &value.id.protocol),
+        ("probe_id", &value.id.probe_id),
+        ("value_name", &value.id.probe_value_name),
+    ]
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: f64) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        let labels = labels
+            .iter()
+            .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+            .collect::<Vec<String>>();
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(out, " {}", value);
+}
+
+/// label values escape `\`, `"` and new lines
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::sensor_identifier::SensorIdentifier;
+    use crate::domain::sensor_value_type::{BatteryLevel, SensorValueType, Temperature, ValueType};
+    use crate::listener::LinkMonitor;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn text_exposition() {
+        let metrics = Metrics::default();
+        metrics.raw_line();
+        metrics.raw_line();
+        metrics.frame_decoded(Some("oregon".to_string()));
+        metrics.frame_decoded(None);
+        metrics.decode_error(&DomainError::RfLinkUnavailableError);
+        metrics.publish_confirmed(true, Duration::from_millis(250));
+        metrics.publish_failed();
+        metrics.command_queued();
+        let timestamp = Utc.with_ymd_and_hms(2020, 9, 12, 10, 0, 0).unwrap();
+        let values = vec![
+            Some(SensorValue {
+                id: SensorIdentifier::new("1a2b", "oregon", "temperature"),
+                timestamp,
+                value: SensorValueType::Temperature(Temperature::create(20.5).unwrap()),
+            }),
+            Some(SensorValue {
+                id: SensorIdentifier::new("1a2b", "oregon", "battery"),
+                timestamp,
+                value: SensorValueType::Battery(BatteryLevel::Low),
+            }),
+            None,
+        ];
+        let text = metrics.render(&LinkMonitor::new().state(), &values);
+
+        let lines = text.lines().collect::<Vec<&str>>();
+        assert!(lines.contains(&"# TYPE ayasha_raw_lines_total counter"));
+        assert!(lines.contains(&"ayasha_raw_lines_total 2"));
+        assert!(lines.contains(&"ayasha_frames_decoded_total{protocol=\"oregon\"} 1"));
+        assert!(lines.contains(&"ayasha_unknown_frames_total 1"));
+        assert!(lines.contains(&"ayasha_decode_errors_total{error=\"RfLinkUnavailableError\"} 1"));
+        assert!(lines.contains(&"ayasha_amqp_published_total 1"));
+        assert!(lines.contains(&"ayasha_amqp_publish_failures_total 1"));
+        assert!(lines.contains(&"ayasha_amqp_publish_latency_seconds_sum 0.25"));
+        assert!(lines.contains(&"ayasha_serial_reconnects_total 0"));
+        assert!(lines.contains(&"ayasha_actor_queue_depth 1"));
+        assert!(lines.contains(
+            &"ayasha_sensor_value{protocol=\"oregon\",probe_id=\"1a2b\",value_name=\"temperature\"} 20.5"
+        ));
+        // the battery level has no numeric value, but a reception time
+        assert!(!text.contains("ayasha_sensor_value{protocol=\"oregon\",probe_id=\"1a2b\",value_name=\"battery\"}"));
+        assert!(lines.contains(
+            &"ayasha_sensor_last_seen_timestamp_seconds{protocol=\"oregon\",probe_id=\"1a2b\",value_name=\"battery\"} 1599904800"
+        ));
+    }
+
+    #[test]
+    fn escaped_labels() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -0,0 +1,409 @@
+//! Minimal MQTT 3.1.1 client packets: enough to connect with a last will,
+//! publish at QoS 0 and keep the connection alive.
+use bytes::{Buf, BufMut, BytesMut};
+use std::io;
+use tokio_util::codec::{Decoder, Encoder};
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PINGREQ: u8 = 0xC0;
+const PINGRESP: u8 = 0xD0;
+const DISCONNECT: u8 = 0xE0;
+
+/// remaining length is encoded on 4 bytes at most
+const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Will {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Connect {
+    pub client_id: String,
+    pub keep_alive_secs: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub will: Option<Will>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Packet {
+    Connect(Connect),
+    ConnAck { return_code: u8 },
+    Publish { topic: String, payload: Vec<u8>, retain: bool },
+    PingReq,
+    PingResp,
+    Disconnect,
+    /// packet type not used by this client
+    Other(u8),
+}
+
+pub struct MqttCodec;
+
+impl Encoder<Packet> for MqttCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, packet: Packet, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let mut body = BytesMut::new();
+        let header = match packet {
+            Packet::Connect(connect) => {
+                put_string(&mut body, "MQTT");
+                body.put_u8(4);
+                let mut flags = 0x02; // clean session
+                if let Some(will) = &connect.will {
+                    flags |= 0x04;
+                    if will.retain {
+                        flags |= 0x20;
+                    }
+                }
+                if connect.password.is_some() {
+                    flags |= 0x40;
+                }
+                if connect.username.is_some() {
+                    flags |= 0x80;
+                }
+                body.put_u8(flags);
+                body.put_u16(connect.keep_alive_secs);
+                put_string(&mut body, &connect.client_id);
+                if let Some(will) = &connect.will {
+                    put_string(&mut body, &will.topic);
+                    put_bytes(&mut body, &will.payload);
+                }
+                if let Some(username) = &connect.username {
+                    put_string(&mut body, username);
+                }
+                if let Some(password) = &connect.password {
+                    put_string(&mut body, password);
+                }
+                CONNECT
+            }
+            Packet::Publish { topic, payload, retain } => {
+                put_string(&mut body, &topic);
+                body.put(payload.as_slice());
+                match retain {
+                    true => PUBLISH | 0x01,
+                    false => PUBLISH,
+                }
+            }
+            Packet::ConnAck { return_code } => {
+                body.put_u8(0);
+                body.put_u8(return_code);
+                CONNACK
+            }
+            Packet::PingReq => PINGREQ,
+            Packet::PingResp => PINGRESP,
+            Packet::Disconnect => DISCONNECT,
+            Packet::Other(kind) => kind,
+        };
+        if body.len() > MAX_REMAINING_LENGTH {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "mqtt packet too large"));
+        }
+        dst.reserve(body.len() + 5);
+        dst.put_u8(header);
+        put_remaining_length(dst, body.len());
+        dst.put(body);
+        Ok(())
+    }
+}
+
+impl Decoder for MqttCodec {
+    type Item = Packet;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let (length, header_len) = match read_remaining_length(src)? {
+            Some(l) => l,
+            None => return Ok(None),
+        };
+        if src.len() < header_len + length {
+            return Ok(None);
+        }
+        let header = src[0];
+        src.advance(header_len);
+        let mut body = src.split_to(length);
+
+        let packet = match header & 0xF0 {
+            CONNACK if length == 2 => Packet::ConnAck { return_code: body[1] },
+            PUBLISH => {
+                let topic = get_string(&mut body)?;
+                // QoS 1 and 2 carry a packet identifier
+                if header & 0x06 != 0 {
+                    if body.len() < 2 {
+                        return Err(invalid("publish packet too short"));
+                    }
+                    body.advance(2);
+                }
+                Packet::Publish {
+                    topic,
+                    payload: body.to_vec(),
+                    retain: header & 0x01 != 0,
+                }
+            }
+            PINGREQ => Packet::PingReq,
+            PINGRESP => Packet::PingResp,
+            DISCONNECT => Packet::Disconnect,
+            kind => Packet::Other(kind),
+        };
+        Ok(Some(packet))
+    }
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+fn put_string(dst: &mut BytesMut, value: &str) {
+    put_bytes(dst, value.as_bytes());
+}
+
+fn put_bytes(dst: &mut BytesMut, value: &[u8]) {
+    dst.put_u16(value.len() as u16);
+    dst.put(value);
+}
+
+fn get_string(src: &mut BytesMut) -> io::Result<String> {
+    if src.len() < 2 {
+        return Err(invalid("string length missing"));
+    }
+    let len = src.get_u16() as usize;
+    if src.len() < len {
+        return Err(invalid("string truncated"));
+    }
+    let value = src.split_to(len);
+    String::from_utf8(value.to_vec()).map_err(|_| invalid("string is not utf8"))
+}
+
+fn put_remaining_length(dst: &mut BytesMut, mut length: usize) {
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        dst.put_u8(byte);
+        if length == 0 {
+            break;
+        }
+    }
+}
+
+/// remaining length and size of the fixed header, `None` when incomplete
+fn read_remaining_length(src: &BytesMut) -> io::Result<Option<(usize, usize)>> {
+    let mut length = 0;
+    let mut multiplier = 1;
+    for position in 1..=4 {
+        let byte = match src.get(position) {
+            Some(b) => *b,
+            None => return Ok(None),
+        };
+        length += (byte & 0x7F) as usize * multiplier;
+        if byte & 0x80 == 0 {
+            return Ok(Some((length, position + 1)));
+        }
+        multiplier *= 128;
+    }
+    Err(invalid("remaining length longer than 4 bytes"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(packet: Packet) -> Packet {
+        let mut buffer = BytesMut::new();
+        MqttCodec.encode(packet, &mut buffer).unwrap();
+        MqttCodec.decode(&mut buffer).unwrap().unwrap()
+    }
+
+    #[test]
+    fn encode_connect_with_will() {
+        let mut buffer = BytesMut::new();
+        let connect = Connect {
+            client_id: "ay".to_string(),
+            keep_alive_secs: 30,
+            username: Some("u".to_string()),
+            password: Some("p".to_string()),
+            will: Some(Will {
+                topic: "a/b".to_string(),
+                payload: b"offline".to_vec(),
+                retain: true,
+            }),
+        };
+        MqttCodec.encode(Packet::Connect(connect), &mut buffer).unwrap();
+        assert_eq!(buffer[0], CONNECT);
+        assert_eq!(buffer[1] as usize, buffer.len() - 2);
+        assert_eq!(&buffer[2..8], b"\x00\x04MQTT");
+        assert_eq!(buffer[8], 4);
+        assert_eq!(buffer[9], 0x02 | 0x04 | 0x20 | 0x40 | 0x80);
+        assert_eq!(&buffer[10..12], &[0, 30]);
+    }
+
+    #[test]
+    fn publish_round_trip() {
+        let publish = Packet::Publish {
+            topic: "ayasha/oregon/1a2b/temperature".to_string(),
+            payload: b"21.5".to_vec(),
+            retain: true,
+        };
+        assert_eq!(round_trip(publish.clone()), publish);
+    }
+
+    #[test]
+    fn long_remaining_length() {
+        let publish = Packet::Publish {
+            topic: "t".to_string(),
+            payload: vec![b'x'; 300],
+            retain: false,
+        };
+        let mut buffer = BytesMut::new();
+        MqttCodec.encode(publish.clone(), &mut buffer).unwrap();
+        assert_eq!(&buffer[1..3], &[0xAF, 0x02]);
+        assert_eq!(MqttCodec.decode(&mut buffer).unwrap().unwrap(), publish);
+    }
+
+    #[test]
+    fn partial_packet_waits() {
+        let mut buffer = BytesMut::from(&[CONNACK, 2, 0][..]);
+        assert_eq!(MqttCodec.decode(&mut buffer).unwrap(), None);
+        buffer.put_u8(5);
+        assert_eq!(
+            MqttCodec.decode(&mut buffer).unwrap(),
+            Some(Packet::ConnAck { return_code: 5 })
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn ping() {
+        assert_eq!(round_trip(Packet::PingResp), Packet::PingResp);
+    }
+
+    fn encoded(packet: Packet) -> Vec<u8> {
+        let mut buffer = BytesMut::new();
+        MqttCodec.encode(packet, &mut buffer).unwrap();
+        buffer.to_vec()
+    }
+
+    fn decoded(bytes: &[u8]) -> Packet {
+        MqttCodec.decode(&mut BytesMut::from(bytes)).unwrap().unwrap()
+    }
+
+    // byte vectors of the MQTT 3.1.1 specification, sections 2.2.3 and 3
+
+    #[test]
+    fn spec_remaining_length() {
+        let cases: &[(usize, &[u8])] = &[
+            (0, &[0x00]),
+            (127, &[0x7F]),
+            (128, &[0x80, 0x01]),
+            (16_383, &[0xFF, 0x7F]),
+            (16_384, &[0x80, 0x80, 0x01]),
+            (2_097_151, &[0xFF, 0xFF, 0x7F]),
+            (2_097_152, &[0x80, 0x80, 0x80, 0x01]),
+            (268_435_455, &[0xFF, 0xFF, 0xFF, 0x7F]),
+        ];
+        for (length, bytes) in cases {
+            let mut buffer = BytesMut::new();
+            put_remaining_length(&mut buffer, *length);
+            assert_eq!(&buffer[..], *bytes);
+
+            let mut packet = BytesMut::from(&[PUBLISH][..]);
+            packet.put(*bytes);
+            assert_eq!(read_remaining_length(&packet).unwrap(), Some((*length, bytes.len() + 1)));
+        }
+        let too_long = BytesMut::from(&[PUBLISH, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F][..]);
+        assert!(read_remaining_length(&too_long).is_err());
+    }
+
+    #[test]
+    fn spec_connect() {
+        let connect = Connect {
+            client_id: "ay".to_string(),
+            keep_alive_secs: 30,
+            username: None,
+            password: None,
+            will: None,
+        };
+        assert_eq!(
+            encoded(Packet::Connect(connect)),
+            vec![
+                0x10, 0x0E, // CONNECT, remaining length 14
+                0x00, 0x04, b'M', b'Q', b'T', b'T', // protocol name
+                0x04, // protocol level 3.1.1
+                0x02, // clean session
+                0x00, 0x1E, // keep alive 30s
+                0x00, 0x02, b'a', b'y', // client identifier
+            ]
+        );
+    }
+
+    #[test]
+    fn spec_connect_with_will_and_credentials() {
+        let connect = Connect {
+            client_id: "ay".to_string(),
+            keep_alive_secs: 30,
+            username: Some("u".to_string()),
+            password: Some("p".to_string()),
+            will: Some(Will {
+                topic: "a/b".to_string(),
+                payload: b"off".to_vec(),
+                retain: true,
+            }),
+        };
+        assert_eq!(
+            encoded(Packet::Connect(connect)),
+            vec![
+                0x10, 0x1E, // CONNECT, remaining length 30
+                0x00, 0x04, b'M', b'Q', b'T', b'T',
+                0x04,
+                0xE6, // user name, password, will retain, will QoS 0, will, clean session
+                0x00, 0x1E,
+                0x00, 0x02, b'a', b'y',
+                0x00, 0x03, b'a', b'/', b'b', // will topic
+                0x00, 0x03, b'o', b'f', b'f', // will message
+                0x00, 0x01, b'u',
+                0x00, 0x01, b'p',
+            ]
+        );
+    }
+
+    #[test]
+    fn spec_publish() {
+        let publish = Packet::Publish {
+            topic: "a/b".to_string(),
+            payload: b"hi".to_vec(),
+            retain: true,
+        };
+        // QoS 0: no packet identifier
+        assert_eq!(
+            encoded(publish),
+            vec![0x31, 0x07, 0x00, 0x03, b'a', b'/', b'b', b'h', b'i']
+        );
+        // QoS 1 from a broker, packet identifier 10 skipped
+        assert_eq!(
+            decoded(&[0x32, 0x09, 0x00, 0x03, b'a', b'/', b'b', 0x00, 0x0A, b'h', b'i']),
+            Packet::Publish {
+                topic: "a/b".to_string(),
+                payload: b"hi".to_vec(),
+                retain: false,
+            }
+        );
+    }
+
+    #[test]
+    fn spec_short_packets() {
+        // session present flag, connection accepted
+        assert_eq!(decoded(&[0x20, 0x02, 0x01, 0x00]), Packet::ConnAck { return_code: 0 });
+        // not authorized
+        assert_eq!(decoded(&[0x20, 0x02, 0x00, 0x05]), Packet::ConnAck { return_code: 5 });
+        assert_eq!(decoded(&[0xD0, 0x00]), Packet::PingResp);
+        assert_eq!(encoded(Packet::PingReq), vec![0xC0, 0x00]);
+        assert_eq!(encoded(Packet::Disconnect), vec![0xE0, 0x00]);
+        // SUBACK is not used by the client
+        assert_eq!(decoded(&[0x90, 0x03, 0x00, 0x01, 0x00]), Packet::Other(0x90));
+    }
+}
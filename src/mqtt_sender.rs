@@ -0,0 +1,451 @@
+use crate::config::MqttConfig;
+use crate::domain::command_event::Event;
+use crate::domain::errors::Result;
+use crate::domain::external_message::{ExternalMessage, MessageSender, SinkConnection};
+use crate::domain::sensor_identifier::SensorIdentifier;
+use crate::domain::sensor_value_type::SensorValueType;
+use crate::message_queue::MessageQueue;
+use crate::mqtt::{Connect, MqttCodec, Packet, Will};
+use crate::sinks::SinkMonitor;
+
+use futures::{sink::SinkExt, stream::StreamExt};
+use serde_json::json;
+use std::io;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_util::codec::{Decoder, Framed};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const ONLINE: &[u8] = b"online";
+const OFFLINE: &[u8] = b"offline";
+
+/// Publish sensor values to one topic per sensor, with Home Assistant discovery.
+/// Messages are queued in memory while the broker is unavailable.
+#[derive(Clone)]
+pub struct MqttSender {
+    queue: MessageQueue,
+    monitor: SinkMonitor,
+}
+
+impl MqttSender {
+    /// Spawn the publishing task, to be called inside the tokio runtime.
+    pub fn start(config: &MqttConfig, node_name: &str) -> MqttSender {
+        let sender = MqttSender {
+            queue: MessageQueue::new("mqtt", config.queue_max_messages),
+            monitor: SinkMonitor::new(false),
+        };
+        let topics = Topics::new(config, node_name);
+        tokio::spawn(supervise(config.clone(), topics, sender.queue.clone(), sender.monitor.clone()));
+        sender
+    }
+}
+
+impl MessageSender for MqttSender {
+    fn send(&self, msg: ExternalMessage) -> Result<()> {
+        self.queue.push(msg);
+        Ok(())
+    }
+
+    fn pending(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn connection(&self) -> SinkConnection {
+        self.monitor.connection()
+    }
+}
+
+/// Topic levels can not hold '/', '+' or '#'
+fn topic_level(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '/' | '+' | '#' | ' ' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+struct Topics {
+    prefix: String,
+    discovery_prefix: String,
+    node_name: String,
+}
+
+impl Topics {
+    fn new(config: &MqttConfig, node_name: &str) -> Topics {
+        Topics {
+            prefix: config.topic_prefix.clone(),
+            discovery_prefix: config.discovery_prefix.clone(),
+            node_name: topic_level(node_name),
+        }
+    }
+
+    fn availability(&self) -> String {
+        format!("{}/{}/availability", self.prefix, self.node_name)
+    }
+
+    /// `ayasha/<protocol>/<probe_id>/<value_name>`
+    fn state(&self, id: &SensorIdentifier) -> String {
+        format!(
+            "{}/{}/{}/{}",
+            self.prefix,
+            topic_level(&id.protocol),
+            topic_level(&id.probe_id),
+            topic_level(&id.probe_value_name)
+        )
+    }
+
+    fn switch(&self, protocol: &str, device_id: &str, switch: &str) -> String {
+        format!(
+            "{}/{}/{}/{}",
+            self.prefix,
+            topic_level(protocol),
+            topic_level(device_id),
+            topic_level(switch)
+        )
+    }
+
+    fn device_id(&self, id: &SensorIdentifier) -> String {
+        format!("{}_{}_{}", self.node_name, topic_level(&id.protocol), topic_level(&id.probe_id))
+    }
+
+    /// `homeassistant/<component>/<node>_<protocol>_<probe_id>_<value_name>/config`
+    fn discovery_topic(&self, component: &str, id: &SensorIdentifier) -> String {
+        let object_id = format!("{}_{}", self.device_id(id), topic_level(&id.probe_value_name));
+        format!("{}/{}/{}/config", self.discovery_prefix, component, object_id)
+    }
+
+    /// retained Home Assistant configuration of a sensor
+    fn discovery(&self, id: &SensorIdentifier, value: &SensorValueType) -> Packet {
+        let device_id = self.device_id(id);
+        let object_id = format!("{}_{}", device_id, topic_level(&id.probe_value_name));
+        let mut config = json!({
+            "name": format!("{} {} {}", id.protocol, id.probe_id, id.probe_value_name),
+            "unique_id": object_id,
+            "state_topic": self.state(id),
+            "availability_topic": self.availability(),
+            "device": {
+                "identifiers": [device_id],
+                "name": format!("{} {}", id.protocol, id.probe_id),
+                "model": id.protocol,
+                "manufacturer": "RFLink",
+            },
+        });
+        match value {
+            SensorValueType::Battery(_) => {
+                config["device_class"] = json!("battery");
+                config["payload_on"] = json!("LOW");
+                config["payload_off"] = json!("OK");
+            }
+            value => {
+                if !value.unit().is_empty() {
+                    config["unit_of_measurement"] = json!(value.unit());
+                }
+                if let Some(class) = device_class(value) {
+                    config["device_class"] = json!(class);
+                }
+            }
+        }
+        Packet::Publish {
+            topic: self.discovery_topic(component(value), id),
+            payload: config.to_string().into_bytes(),
+            retain: true,
+        }
+    }
+}
+
+/// Home Assistant component of a sensor
+fn component(value: &SensorValueType) -> &'static str {
+    match value {
+        SensorValueType::Battery(_) => "binary_sensor",
+        _ => "sensor",
+    }
+}
+
+fn device_class(value: &SensorValueType) -> Option<&'static str> {
+    match value {
+        SensorValueType::Temperature(_) => Some("temperature"),
+        SensorValueType::Humidity(_) => Some("humidity"),
+        SensorValueType::Pressure(_) => Some("pressure"),
+        SensorValueType::Illuminance(_) => Some("illuminance"),
+        SensorValueType::Power(_) => Some("power"),
+        SensorValueType::Energy(_) => Some("energy"),
+        SensorValueType::Voltage(_) => Some("voltage"),
+        SensorValueType::Current(_) => Some("current"),
+        _ => None,
+    }
+}
+
+/// Packets publishing `msg`, preceded by the discovery of a sensor seen for the first time.
+fn to_publications(msg: &ExternalMessage, topics: &Topics, seen: &mut Vec<(SensorIdentifier, SensorValueType)>) -> Vec<Packet> {
+    match &msg.event {
+        Event::ValueChanged(value) => {
+            let mut packets = vec![];
+            if !seen.iter().any(|(id, _)| id == &value.id) {
+                packets.push(topics.discovery(&value.id, &value.value));
+                seen.push((value.id.clone(), value.value.clone()));
+            }
+            packets.push(Packet::Publish {
+                topic: topics.state(&value.id),
+                payload: value.value.state().into_bytes(),
+                retain: true,
+            });
+            packets
+        }
+        Event::SwitchCommandReceived(switch) => {
+            let command = match serde_json::to_value(&switch.command) {
+                Ok(serde_json::Value::String(c)) => c,
+                Ok(other) => other.to_string(),
+                Err(_) => return vec![],
+            };
+            vec![Packet::Publish {
+                topic: topics.switch(&switch.protocol, &switch.device_id, &switch.switch),
+                payload: command.into_bytes(),
+                retain: false,
+            }]
+        }
+        Event::UnknowDataReceived(_) => vec![],
+        // an empty retained message clears the last state and the discovery kept by the broker,
+        // both components are cleared for a sensor not seen since the start
+        Event::SensorReset(id) => {
+            let components = match seen.iter().find(|(seen_id, _)| seen_id == id) {
+                Some((_, value)) => vec![component(value)],
+                None => vec!["sensor", "binary_sensor"],
+            };
+            seen.retain(|(seen_id, _)| seen_id != id);
+            let clear = |topic| Packet::Publish {
+                topic,
+                payload: vec![],
+                retain: true,
+            };
+            let mut packets = vec![clear(topics.state(id))];
+            packets.extend(components.into_iter().map(|c| clear(topics.discovery_topic(c, id))));
+            packets
+        }
+    }
+}
+
+async fn supervise(config: MqttConfig, topics: Topics, queue: MessageQueue, monitor: SinkMonitor) {
+    let max_delay = Duration::from_secs(config.reconnect_max_delay_secs);
+    let mut delay = RECONNECT_INITIAL_DELAY;
+    // sensors already announced, announced again on every connection
+    let mut seen = vec![];
+    loop {
+        let error = match session(&config, &topics, &queue, &mut seen, &monitor).await {
+            Ok(_) => io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed by the broker"),
+            Err(e) => e,
+        };
+        if monitor.connection().connected {
+            delay = RECONNECT_INITIAL_DELAY;
+        }
+        println!("mqtt unavailable: {}, retry in {:?}", error, delay);
+        monitor.set_failed(&error);
+        tokio::time::delay_for(delay).await;
+        delay = std::cmp::min(delay * 2, max_delay);
+    }
+}
+
+async fn connect(config: &MqttConfig, topics: &Topics) -> io::Result<Framed<TcpStream, MqttCodec>> {
+    let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect((config.host.as_str(), config.port)))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connection timeout"))??;
+    let mut framed = MqttCodec.framed(stream);
+    framed
+        .send(Packet::Connect(Connect {
+            client_id: config.client_id.clone(),
+            keep_alive_secs: config.keep_alive_secs,
+            username: config.username.clone(),
+            password: config.password.clone(),
+            will: Some(Will {
+                topic: topics.availability(),
+                payload: OFFLINE.to_vec(),
+                retain: true,
+            }),
+        }))
+        .await?;
+    match tokio::time::timeout(CONNECT_TIMEOUT, framed.next()).await {
+        Ok(Some(Ok(Packet::ConnAck { return_code: 0 }))) => Ok(framed),
+        Ok(Some(Ok(Packet::ConnAck { return_code }))) => Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("connection refused by the broker, code {}", return_code),
+        )),
+        Ok(Some(Err(e))) => Err(e),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "no connection acknowledgement")),
+    }
+}
+
+async fn session(
+    config: &MqttConfig,
+    topics: &Topics,
+    queue: &MessageQueue,
+    seen: &mut Vec<(SensorIdentifier, SensorValueType)>,
+    monitor: &SinkMonitor,
+) -> io::Result<()> {
+    let mut framed = connect(config, topics).await?;
+    monitor.set_connected();
+    println!("mqtt connected to {}:{}", config.host, config.port);
+
+    framed
+        .send(Packet::Publish {
+            topic: topics.availability(),
+            payload: ONLINE.to_vec(),
+            retain: true,
+        })
+        .await?;
+    for (id, value) in seen.iter() {
+        framed.send(topics.discovery(id, value)).await?;
+    }
+
+    let mut ping = tokio::time::interval(Duration::from_secs(std::cmp::max(config.keep_alive_secs as u64 / 2, 1)));
+    let mut waiting_pong = false;
+    loop {
+        while let Some(message) = queue.front() {
+            for packet in to_publications(&message, topics, seen) {
+                framed.send(packet).await?;
+            }
+            queue.pop_front();
+        }
+        tokio::select! {
+            _ = queue.pushed() => (),
+            _ = ping.tick() => {
+                if waiting_pong {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "no ping response"));
+                }
+                framed.send(Packet::PingReq).await?;
+                waiting_pong = true;
+            },
+            packet = framed.next() => match packet {
+                None => return Ok(()),
+                Some(Err(e)) => return Err(e),
+                Some(Ok(Packet::PingResp)) => waiting_pong = false,
+                Some(Ok(_)) => (),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::external_message::get_external_message;
+    use crate::domain::sensor::SensorValue;
+    use crate::domain::sensor_value_type::{Temperature, ValueType};
+    use tokio::net::TcpListener;
+
+    fn config(port: u16) -> MqttConfig {
+        MqttConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            ..MqttConfig::default()
+        }
+    }
+
+    fn temperature(value: f64) -> ExternalMessage {
+        let event = Event::ValueChanged(SensorValue {
+            id: SensorIdentifier::new("1a2b", "oregon", "temperature"),
+            timestamp: chrono::Utc::now(),
+            value: SensorValueType::Temperature(Temperature::create(value).unwrap()),
+        });
+        get_external_message("garage", "atmosSensor", &event, chrono::Utc::now()).unwrap()
+    }
+
+    async fn next_publish(broker: &mut Framed<TcpStream, MqttCodec>) -> (String, String, bool) {
+        loop {
+            match broker.next().await.unwrap().unwrap() {
+                Packet::Publish { topic, payload, retain } => {
+                    return (topic, String::from_utf8(payload).unwrap(), retain)
+                }
+                Packet::PingReq => broker.send(Packet::PingResp).await.unwrap(),
+                _ => (),
+            }
+        }
+    }
+
+    #[test]
+    fn discovery_of_battery_is_binary_sensor() {
+        let topics = Topics::new(&MqttConfig::default(), "garage");
+        let id = SensorIdentifier::new("1a2b", "oregon", "battery");
+        match topics.discovery(&id, &SensorValueType::Battery(crate::domain::sensor_value_type::BatteryLevel::Ok)) {
+            Packet::Publish { topic, retain, .. } => {
+                assert_eq!(topic, "homeassistant/binary_sensor/garage_oregon_1a2b_battery/config");
+                assert!(retain);
+            }
+            _ => panic!("discovery should be a publish"),
+        }
+    }
+
+    #[test]
+    fn reset_clears_state_and_discovery() {
+        let topics = Topics::new(&MqttConfig::default(), "garage");
+        let id = SensorIdentifier::new("1a2b", "oregon", "temperature");
+        let mut seen = vec![];
+        to_publications(&temperature(20.0), &topics, &mut seen);
+        let reset = get_external_message("garage", "atmosSensor", &Event::SensorReset(id.clone()), chrono::Utc::now()).unwrap();
+
+        let cleared = to_publications(&reset, &topics, &mut seen)
+            .into_iter()
+            .map(|packet| match packet {
+                Packet::Publish { topic, payload, retain } => {
+                    assert!(payload.is_empty() && retain);
+                    topic
+                }
+                _ => panic!("reset should publish"),
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(
+            cleared,
+            vec![
+                "ayasha/oregon/1a2b/temperature".to_string(),
+                "homeassistant/sensor/garage_oregon_1a2b_temperature/config".to_string(),
+            ]
+        );
+        assert!(seen.is_empty());
+    }
+
+    #[test]
+    fn topic_levels_are_sanitized() {
+        let topics = Topics::new(&MqttConfig::default(), "my node");
+        let id = SensorIdentifier::new("a/b", "proto+", "temp#");
+        assert_eq!(topics.state(&id), "ayasha/proto_/a_b/temp_");
+        assert_eq!(topics.availability(), "ayasha/my_node/availability");
+    }
+
+    #[tokio::test]
+    async fn publish_to_broker() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let sender = MqttSender::start(&config(port), "garage");
+
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut broker = MqttCodec.framed(socket);
+        assert_eq!(broker.next().await.unwrap().unwrap(), Packet::Other(0x10));
+        broker.send(Packet::ConnAck { return_code: 0 }).await.unwrap();
+
+        assert_eq!(
+            next_publish(&mut broker).await,
+            ("ayasha/garage/availability".to_string(), "online".to_string(), true)
+        );
+
+        sender.send(temperature(21.5)).unwrap();
+        let (topic, payload, retain) = next_publish(&mut broker).await;
+        assert_eq!(topic, "homeassistant/sensor/garage_oregon_1a2b_temperature/config");
+        assert!(retain);
+        let config: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(config["state_topic"], "ayasha/oregon/1a2b/temperature");
+        assert_eq!(config["device_class"], "temperature");
+        assert_eq!(config["unit_of_measurement"], "°C");
+        assert_eq!(
+            next_publish(&mut broker).await,
+            ("ayasha/oregon/1a2b/temperature".to_string(), "21.5".to_string(), true)
+        );
+
+        // discovery only the first time
+        sender.send(temperature(22.0)).unwrap();
+        assert_eq!(
+            next_publish(&mut broker).await,
+            ("ayasha/oregon/1a2b/temperature".to_string(), "22".to_string(), true)
+        );
+    }
+}
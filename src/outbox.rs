@@ -0,0 +1,338 @@
+use crate::domain::external_message::ExternalMessage;
+use crate::segment_log::SegmentLog;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const OUTBOX_PREFIX: &str = "outbox";
+const ACK_FILE: &str = "outbox.ack";
+/// messages refused too many times by the broker, one JSON object per line
+const REJECTED_FILE: &str = "outbox.rejected";
+/// Confirmations kept in memory before `outbox.ack` is rewritten, at most that
+/// many messages are published again after a crash.
+const ACK_BATCH: u64 = 100;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutboxMessage {
+    pub sequence: u64,
+    /// unix time of the message creation, in seconds
+    pub timestamp: i64,
+    pub routing_key: String,
+    pub payload: String,
+}
+
+/// Bounded queue of the messages waiting for the broker confirmation.
+///
+/// Messages are appended to a segment log and the sequence of the last
+/// confirmed one is kept in `outbox.ack`, so a restart resends the pending
+/// messages in order (at least once). When full, the oldest message is dropped.
+/// The ack file is written by batch and on `sync`, a segment is deleted once the
+/// log rolled over it and all its messages are confirmed.
+pub struct Outbox {
+    log: Option<SegmentLog>,
+    dir: Option<PathBuf>,
+    pending: VecDeque<OutboxMessage>,
+    max_messages: usize,
+    last_sequence: u64,
+    /// sequence of the last message removed from the queue
+    acked: u64,
+    /// sequence written in `outbox.ack`
+    ack_written: u64,
+    /// segment number and last sequence written in it, oldest segment first
+    segments: VecDeque<(u64, u64)>,
+}
+
+impl Outbox {
+    /// Outbox lost on restart, when storage is disabled.
+    pub fn in_memory(max_messages: usize) -> Outbox {
+        Outbox {
+            log: None,
+            dir: None,
+            pending: VecDeque::new(),
+            max_messages,
+            last_sequence: 0,
+            acked: 0,
+            ack_written: 0,
+            segments: VecDeque::new(),
+        }
+    }
+
+    pub fn open(dir: &Path, max_messages: usize, segment_max_bytes: u64, sync: bool) -> io::Result<Outbox> {
+        let mut log = SegmentLog::open(dir, OUTBOX_PREFIX, segment_max_bytes, sync)?;
+        let acked = match fs::read_to_string(dir.join(ACK_FILE)) {
+            Ok(content) => content.trim().parse::<u64>().unwrap_or(0),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+
+        let mut outbox = Outbox {
+            dir: Some(dir.to_path_buf()),
+            last_sequence: acked,
+            acked,
+            ack_written: acked,
+            ..Outbox::in_memory(max_messages)
+        };
+        for record in log.read_all()? {
+            match serde_json::from_slice::<OutboxMessage>(&record) {
+                Ok(message) if message.sequence > acked => {
+                    outbox.last_sequence = message.sequence;
+                    outbox.pending.push_back(message);
+                }
+                Ok(_) => (),
+                Err(e) => println!("unreadable outbox message ignored: {}", e),
+            }
+        }
+        // the segments written before the restart are deleted as a whole once confirmed
+        if !log.is_current_empty() {
+            outbox.segments.push_back((log.current_segment(), outbox.last_sequence));
+            log.roll()?;
+        }
+        outbox.log = Some(log);
+        while outbox.pending.len() > max_messages {
+            outbox.drop_oldest()?;
+        }
+        outbox.write_ack()?;
+        if !outbox.pending.is_empty() {
+            println!("{} messages waiting in the outbox", outbox.pending.len());
+        }
+        Ok(outbox)
+    }
+
+    pub fn push(&mut self, message: ExternalMessage) -> io::Result<()> {
+        if self.pending.len() >= self.max_messages {
+            self.drop_oldest()?;
+        }
+        let message = OutboxMessage {
+            sequence: self.last_sequence + 1,
+            timestamp: chrono::Utc::now().timestamp(),
+            routing_key: message.routing_key,
+            payload: message.inner_message,
+        };
+        if let Some(log) = self.log.as_mut() {
+            let record = serde_json::to_vec(&message)?;
+            log.append(&record)?;
+            let segment = log.current_segment();
+            match self.segments.back_mut() {
+                Some((index, last)) if *index == segment => *last = message.sequence,
+                _ => self.segments.push_back((segment, message.sequence)),
+            }
+        }
+        self.last_sequence = message.sequence;
+        self.pending.push_back(message);
+        Ok(())
+    }
+
+    /// oldest message not yet confirmed
+    pub fn front(&self) -> Option<&OutboxMessage> {
+        self.pending.front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Write the pending messages and the confirmations to disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        if let Some(log) = self.log.as_mut() {
+            log.sync()?;
+        }
+        self.write_ack()
+    }
+
+    /// The broker confirmed the message `sequence`.
+    pub fn ack(&mut self, sequence: u64) -> io::Result<()> {
+        match self.pending.front() {
+            Some(m) if m.sequence == sequence => self.remove_front(),
+            _ => Ok(()),
+        }
+    }
+
+    /// The broker refused the message `sequence` too many times, it is moved
+    /// to `outbox.rejected` so the following messages are published.
+    pub fn reject(&mut self, sequence: u64) -> io::Result<()> {
+        let message = match self.pending.front() {
+            Some(m) if m.sequence == sequence => m,
+            _ => return Ok(()),
+        };
+        println!("message {} for {} rejected", message.sequence, message.routing_key);
+        if let Some(dir) = &self.dir {
+            let mut line = serde_json::to_vec(message)?;
+            line.push(b'\n');
+            let mut file = fs::OpenOptions::new().create(true).append(true).open(dir.join(REJECTED_FILE))?;
+            file.write_all(&line)?;
+        }
+        self.remove_front()
+    }
+
+    fn drop_oldest(&mut self) -> io::Result<()> {
+        if let Some(m) = self.pending.front() {
+            println!("outbox full, message {} for {} dropped", m.sequence, m.routing_key);
+        }
+        self.remove_front()
+    }
+
+    fn remove_front(&mut self) -> io::Result<()> {
+        let message = match self.pending.pop_front() {
+            Some(m) => m,
+            None => return Ok(()),
+        };
+        self.acked = message.sequence;
+        if self.acked - self.ack_written >= ACK_BATCH {
+            self.write_ack()?;
+        }
+        Ok(())
+    }
+
+    /// Write the last confirmed sequence, then delete the segments holding only confirmed messages.
+    fn write_ack(&mut self) -> io::Result<()> {
+        let (log, dir) = match (self.log.as_mut(), &self.dir) {
+            (Some(log), Some(dir)) => (log, dir),
+            _ => return Ok(()),
+        };
+        if self.acked > self.ack_written {
+            let ack_path = dir.join(ACK_FILE);
+            let tmp_path = ack_path.with_extension("ack.tmp");
+            fs::write(&tmp_path, self.acked.to_string())?;
+            fs::rename(&tmp_path, &ack_path)?;
+            self.ack_written = self.acked;
+        }
+        let current = log.current_segment();
+        while let Some((index, last)) = self.segments.front() {
+            match *index < current && *last <= self.ack_written {
+                true => self.segments.pop_front(),
+                false => break,
+            };
+        }
+        let first_needed = self.segments.front().map_or(current, |(index, _)| *index);
+        log.remove_segments_before(first_needed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::command_event::Event;
+    use crate::domain::raw_frame::RawFrame;
+
+    fn message(routing_key: &str) -> ExternalMessage {
+        ExternalMessage {
+            routing_key: routing_key.to_string(),
+            event: Event::UnknowDataReceived(RawFrame::new("20;00;Nodo RadioFrequencyLink;", chrono::Utc::now())),
+            inner_message: "{}".to_string(),
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ayasha_outbox_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn pending_messages_survive_restart() {
+        let dir = temp_dir("restart");
+        let mut outbox = Outbox::open(&dir, 10, 4096, false).unwrap();
+        outbox.push(message("first")).unwrap();
+        outbox.push(message("second")).unwrap();
+        outbox.push(message("third")).unwrap();
+        let sequence = outbox.front().unwrap().sequence;
+        outbox.ack(sequence).unwrap();
+        outbox.sync().unwrap();
+        drop(outbox);
+
+        let mut outbox = Outbox::open(&dir, 10, 4096, false).unwrap();
+        assert_eq!(outbox.len(), 2);
+        assert_eq!(outbox.front().unwrap().routing_key, "second");
+        outbox.push(message("fourth")).unwrap();
+        assert_eq!(outbox.len(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn oldest_dropped_when_full() {
+        let mut outbox = Outbox::in_memory(2);
+        outbox.push(message("first")).unwrap();
+        outbox.push(message("second")).unwrap();
+        outbox.push(message("third")).unwrap();
+        assert_eq!(outbox.len(), 2);
+        assert_eq!(outbox.front().unwrap().routing_key, "second");
+    }
+
+    #[test]
+    fn ack_only_the_front() {
+        let mut outbox = Outbox::in_memory(10);
+        outbox.push(message("first")).unwrap();
+        outbox.push(message("second")).unwrap();
+        outbox.ack(2).unwrap();
+        assert_eq!(outbox.len(), 2);
+        outbox.ack(1).unwrap();
+        assert_eq!(outbox.front().unwrap().routing_key, "second");
+    }
+
+    #[test]
+    fn confirmed_segments_removed() {
+        let dir = temp_dir("compact");
+        let segments = || {
+            fs::read_dir(&dir)
+                .unwrap()
+                .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().ends_with(".log"))
+                .count()
+        };
+        // one message per segment
+        let mut outbox = Outbox::open(&dir, 10, 100, false).unwrap();
+        for name in &["first", "second", "third"] {
+            outbox.push(message(name)).unwrap();
+        }
+        assert_eq!(segments(), 3);
+        outbox.ack(1).unwrap();
+        outbox.ack(2).unwrap();
+        outbox.sync().unwrap();
+        // the current segment is kept even once confirmed
+        assert_eq!(segments(), 1);
+        outbox.ack(3).unwrap();
+        outbox.sync().unwrap();
+        assert_eq!(segments(), 1);
+        drop(outbox);
+
+        let outbox = Outbox::open(&dir, 10, 100, false).unwrap();
+        assert_eq!(outbox.len(), 0);
+        assert_eq!(segments(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn acks_written_by_batch() {
+        let dir = temp_dir("batch");
+        let mut outbox = Outbox::open(&dir, 1000, 4096, false).unwrap();
+        for _ in 0..ACK_BATCH + 2 {
+            outbox.push(message("sensor")).unwrap();
+        }
+        for sequence in 1..=ACK_BATCH + 1 {
+            outbox.ack(sequence).unwrap();
+        }
+        drop(outbox);
+
+        // the last confirmation was not written, its message is published again
+        let outbox = Outbox::open(&dir, 1000, 4096, false).unwrap();
+        assert_eq!(outbox.len(), 2);
+        assert_eq!(outbox.front().unwrap().sequence, ACK_BATCH + 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejected_message_set_aside() {
+        let dir = temp_dir("reject");
+        let mut outbox = Outbox::open(&dir, 10, 4096, false).unwrap();
+        outbox.push(message("first")).unwrap();
+        outbox.push(message("second")).unwrap();
+        outbox.reject(1).unwrap();
+        assert_eq!(outbox.front().unwrap().routing_key, "second");
+
+        let rejected = fs::read_to_string(dir.join(REJECTED_FILE)).unwrap();
+        let line = serde_json::from_str::<OutboxMessage>(rejected.trim()).unwrap();
+        assert_eq!(line.routing_key, "first");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
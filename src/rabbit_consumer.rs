@@ -0,0 +1,183 @@
+use crate::config::AmqpConfig;
+use crate::domain::command_event::Command;
+use crate::domain::errors::{DataFormatingError, DomainError, Result};
+use crate::domain::query::Query;
+use crate::domain::remote_command::RemoteCommand;
+use crate::domain::rf_command::RfCommandReply;
+use crate::listener::{LinkMonitor, LinkStatus, RF_COMMAND_TIMEOUT};
+use crate::state_actor::MessageSender;
+use futures::stream::StreamExt;
+use lapin::{
+    message::Delivery, options::*, types::FieldTable, BasicProperties, Channel, Connection,
+    ConnectionProperties};
+use serde::Serialize;
+use snafu::ResultExt;
+use std::time::Duration;
+
+const RECONNECT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const PREFETCH_COUNT: u16 = 10;
+
+/// Answer published on the `reply_to` queue of a command.
+#[derive(Debug, PartialEq, Serialize)]
+struct CommandReply {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl CommandReply {
+    fn from_result(result: Result<serde_json::Value>) -> CommandReply {
+        match result {
+            Ok(serde_json::Value::Null) => CommandReply { ok: true, result: None, error: None },
+            Ok(value) => CommandReply { ok: true, result: Some(value), error: None },
+            Err(e) => CommandReply { ok: false, result: None, error: Some(e.to_string()) },
+        }
+    }
+}
+
+/// Spawn the consumer of the command queue, to be called inside the tokio runtime.
+pub fn start(config: &AmqpConfig, queue: &str, node_name: &str, sender: MessageSender, link_monitor: LinkMonitor) {
+    let routing_key = config.command_routing_key.replace("{node_name}", node_name);
+    tokio::spawn(supervise(config.clone(), queue.to_string(), routing_key, sender, link_monitor));
+}
+
+async fn supervise(
+    config: AmqpConfig,
+    queue: String,
+    routing_key: String,
+    sender: MessageSender,
+    link_monitor: LinkMonitor,
+) {
+    let max_delay = Duration::from_secs(config.reconnect_max_delay_secs);
+    let mut delay = RECONNECT_INITIAL_DELAY;
+    loop {
+        let error = match connect(&config, &queue, &routing_key).await {
+            Ok((connection, channel)) => {
+                println!("amqp consuming commands on {}", queue);
+                delay = RECONNECT_INITIAL_DELAY;
+                let result = consume(&channel, &queue, &sender, &link_monitor).await;
+                let _ = connection.close(200, "OK").await;
+                match result {
+                    Ok(_) => "consumer cancelled by the broker".to_string(),
+                    Err(e) => e.to_string(),
+                }
+            }
+            Err(e) => e.to_string(),
+        };
+        println!("amqp command queue unavailable: {}, retry in {:?}", error, delay);
+        tokio::time::delay_for(delay).await;
+        delay = std::cmp::min(delay * 2, max_delay);
+    }
+}
+
+async fn connect(config: &AmqpConfig, queue: &str, routing_key: &str) -> lapin::Result<(Connection, Channel)> {
+    let connection = Connection::connect(
+        &config.uri,
+        ConnectionProperties::default().with_default_executor(8),
+    )
+    .await?;
+    let channel = connection.create_channel().await?;
+    let options = QueueDeclareOptions {
+        durable: true,
+        ..QueueDeclareOptions::default()
+    };
+    channel.queue_declare(queue, options, FieldTable::default()).await?;
+    channel
+        .queue_bind(queue, &config.exchange, routing_key, QueueBindOptions::default(), FieldTable::default())
+        .await?;
+    channel.basic_qos(PREFETCH_COUNT, BasicQosOptions::default()).await?;
+    Ok((connection, channel))
+}
+
+/// Execute the commands one at a time, until the consumer is cancelled or the broker fails.
+async fn consume(channel: &Channel, queue: &str, sender: &MessageSender, link_monitor: &LinkMonitor) -> lapin::Result<()> {
+    let mut consumer = channel
+        .basic_consume(queue, "ayasha_rf", BasicConsumeOptions::default(), FieldTable::default())
+        .await?;
+    while let Some(delivery) = consumer.next().await {
+        let (_, delivery) = delivery?;
+        let result = match RemoteCommand::parse(&delivery.data) {
+            Ok(command) => execute(command, sender, link_monitor).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = &result {
+            println!("amqp command refused: {}", e);
+        }
+        reply(channel, &delivery, CommandReply::from_result(result)).await?;
+        // a refused command is acknowledged too, it would be refused again
+        channel.basic_ack(delivery.delivery_tag, BasicAckOptions::default()).await?;
+    }
+    Ok(())
+}
+
+async fn reply(channel: &Channel, delivery: &Delivery, reply: CommandReply) -> lapin::Result<()> {
+    let reply_to = match delivery.properties.reply_to() {
+        Some(r) => r.as_str().to_string(),
+        None => return Ok(()),
+    };
+    let mut properties = BasicProperties::default().with_content_type("application/json".into());
+    if let Some(correlation_id) = delivery.properties.correlation_id() {
+        properties = properties.with_correlation_id(correlation_id.clone());
+    }
+    let payload = serde_json::to_vec(&reply).unwrap_or_default();
+    channel
+        .basic_publish("", &reply_to, BasicPublishOptions::default(), payload, properties)
+        .await?;
+    Ok(())
+}
+
+async fn execute(command: RemoteCommand, sender: &MessageSender, link_monitor: &LinkMonitor) -> Result<serde_json::Value> {
+    match command {
+        RemoteCommand::SendRf(rf_command) => {
+            send_to_link(sender, link_monitor, |reply| Command::SendRf(rf_command, reply)).await
+        }
+        RemoteCommand::SetRfDebug { enabled } => {
+            send_to_link(sender, link_monitor, |reply| Command::SetRfDebug(enabled, reply)).await
+        }
+        RemoteCommand::GetState => query(sender, Query::AllSensors).await,
+        RemoteCommand::Query(q) => query(sender, q).await,
+        RemoteCommand::ResetSensor(id) => sender.reset_sensor(id).await.map(|_| serde_json::Value::Null),
+    }
+}
+
+async fn query(sender: &MessageSender, query: Query) -> Result<serde_json::Value> {
+    let result = sender.query(query).await?;
+    serde_json::to_value(&result).context(DataFormatingError)
+}
+
+/// Hand a command over to the RFLink and wait for its acknowledgement.
+async fn send_to_link<F>(sender: &MessageSender, link_monitor: &LinkMonitor, command: F) -> Result<serde_json::Value>
+where
+    F: FnOnce(RfCommandReply) -> Command,
+{
+    if link_monitor.state().status != LinkStatus::Connected {
+        return Err(DomainError::RfLinkUnavailableError);
+    }
+    let (reply, receiver) = tokio::sync::oneshot::channel();
+    let _ = sender.send(command(reply)).await;
+    match tokio::time::timeout(RF_COMMAND_TIMEOUT, receiver).await {
+        Ok(Ok(result)) => result.map(|_| serde_json::Value::Null),
+        Ok(Err(_)) => Err(DomainError::RfLinkUnavailableError),
+        Err(_) => Err(DomainError::RfCommandRejectedError {
+            value: "no acknowledgement from the RFLink".to_string(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reply_format() {
+        let ok = CommandReply::from_result(Ok(serde_json::Value::Null));
+        assert_eq!(serde_json::to_string(&ok).unwrap(), r#"{"ok":true}"#);
+        let error = CommandReply::from_result(Err(DomainError::RfLinkUnavailableError));
+        assert_eq!(
+            serde_json::to_string(&error).unwrap(),
+            r#"{"ok":false,"error":"RFLink link unavailable"}"#
+        );
+    }
+}
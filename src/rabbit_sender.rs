@@ -1,56 +1,205 @@
-use futures_executor::LocalPool;
-use crate::domain::external_message::{ExternalMessage,MessageSender};
-use crate::domain::errors::Result;
+use crate::config::{AmqpConfig, ExchangeType, StorageConfig};
+use crate::domain::external_message::{ExternalMessage, MessageSender, SinkConnection};
+use crate::domain::errors::{Result, StorageError};
+use crate::errors::{Result as RfResult, StorageOpenError};
+use crate::metrics::METRICS;
+use crate::outbox::{Outbox, OutboxMessage};
+use crate::sinks::SinkMonitor;
 use lapin::{
-    options::*, BasicProperties, Connection,
-    ConnectionProperties};
+    options::*, types::FieldTable, BasicProperties, Channel, Connection,
+    ConnectionProperties, ExchangeKind};
 use snafu::ResultExt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
 
+const RECONNECT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+/// Pause before publishing again a message refused by the broker, doubled on each refusal
+const NACK_RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Refusals of a message before it is set aside in the rejected messages of the outbox
+const NACK_MAX_RETRIES: u32 = 5;
+const CONTENT_TYPE: &str = "application/json";
+const PERSISTENT_DELIVERY_MODE: u8 = 2;
+
+/// Messages are queued in the outbox and published by a background task
+/// holding a single connection, so a broker outage never blocks the caller.
+#[derive(Clone)]
 pub struct RabbitSender {
-    connection_uri: String,
-    exchange_key: String
+    outbox: Arc<Mutex<Outbox>>,
+    wake: Arc<Notify>,
+    monitor: SinkMonitor,
 }
+
 impl RabbitSender {
-    pub fn new(uri: String, exchange_key: String) -> RabbitSender {
-        RabbitSender{connection_uri:uri,exchange_key:exchange_key}
+    /// Spawn the publishing task, to be called inside the tokio runtime.
+    pub fn start(config: &AmqpConfig, node_name: &str, outbox: Outbox) -> RabbitSender {
+        let sender = RabbitSender {
+            outbox: Arc::new(Mutex::new(outbox)),
+            wake: Arc::new(Notify::new()),
+            monitor: SinkMonitor::new(false),
+        };
+        tokio::spawn(supervise(config.clone(), node_name.to_string(), sender.clone()));
+        sender
     }
 }
 
 impl MessageSender for RabbitSender {
     fn send(&self, msg: ExternalMessage) -> Result<()> {
-        send(self, msg)    
+        self.outbox.lock().unwrap().push(msg).context(StorageError)?;
+        self.wake.notify();
+        Ok(())
+    }
+
+    fn pending(&self) -> usize {
+        self.outbox.lock().unwrap().len()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.outbox.lock().unwrap().sync().context(StorageError)
+    }
+
+    fn connection(&self) -> SinkConnection {
+        self.monitor.connection()
     }
 }
 
-async fn inner_send(sender: &RabbitSender, msg: ExternalMessage) -> Result<()> {
-   
-        let conn = Connection::connect(
-            &sender.connection_uri,
-            ConnectionProperties::default().with_default_executor(8),
-        )
-        .await;
-
-        match conn {
-            Err(e) => Err(e),
-            Ok(c) => {
-                let channel = c.create_channel().await;
-                match channel {
-                    Err(e) => Err(e),
-                    Ok(ca) => {
-                        ca
-                            .basic_publish(
-                            &sender.exchange_key,
-                            &msg.routing_key,
-                            BasicPublishOptions::default(),
-                            msg.inner_message.as_bytes().to_vec(),
-                            BasicProperties::default(),
-                            )
-                            .await.and_then(|_| Ok(()))
-                    }
-                }
+/// Outbox kept in the storage directory, or in memory when storage is disabled.
+pub fn open_outbox(config: &AmqpConfig, storage: &StorageConfig) -> RfResult<Outbox> {
+    if !storage.enabled {
+        return Ok(Outbox::in_memory(config.outbox_max_messages));
+    }
+    let dir = storage.path.join("outbox");
+    Outbox::open(&dir, config.outbox_max_messages, storage.segment_max_bytes, storage.sync_writes)
+        .context(StorageOpenError { path: dir.display().to_string() })
+}
+
+async fn supervise(config: AmqpConfig, node_name: String, sender: RabbitSender) {
+    let max_delay = Duration::from_secs(config.reconnect_max_delay_secs);
+    let mut delay = RECONNECT_INITIAL_DELAY;
+    loop {
+        let error = match connect(&config).await {
+            Ok((connection, channel)) => {
+                println!("amqp connected to exchange {}", config.exchange);
+                sender.monitor.set_connected();
+                delay = RECONNECT_INITIAL_DELAY;
+                let error = drain(&channel, &config, &node_name, &sender).await;
+                let _ = connection.close(200, "OK").await;
+                error
             }
-        }.context(crate::domain::errors::ExternalMessageError)
+            Err(e) => e,
+        };
+        println!(
+            "amqp unavailable: {}, {} messages waiting, retry in {:?}",
+            error,
+            sender.outbox.lock().unwrap().len(),
+            delay
+        );
+        sender.monitor.set_failed(&error);
+        tokio::time::delay_for(delay).await;
+        delay = std::cmp::min(delay * 2, max_delay);
+    }
+}
+
+async fn connect(config: &AmqpConfig) -> lapin::Result<(Connection, Channel)> {
+    let connection = Connection::connect(
+        &config.uri,
+        ConnectionProperties::default().with_default_executor(8),
+    )
+    .await?;
+    let channel = connection.create_channel().await?;
+    channel.confirm_select(ConfirmSelectOptions::default()).await?;
+    if config.declare_exchange {
+        let options = ExchangeDeclareOptions {
+            durable: config.exchange_durable,
+            ..ExchangeDeclareOptions::default()
+        };
+        channel
+            .exchange_declare(&config.exchange, exchange_kind(config.exchange_type), options, FieldTable::default())
+            .await?;
+    }
+    Ok((connection, channel))
+}
+
+/// Publish the outbox in order, each message waiting for its confirmation.
+/// Only returns on a broker error.
+async fn drain(channel: &Channel, config: &AmqpConfig, node_name: &str, sender: &RabbitSender) -> lapin::Error {
+    // refusals of the front message
+    let mut refusals = 0;
+    loop {
+        let next = sender.outbox.lock().unwrap().front().cloned();
+        let message = match next {
+            Some(m) => m,
+            None => {
+                sender.wake.notified().await;
+                continue;
+            }
+        };
+        let published = Instant::now();
+        let confirmation = match publish(channel, config, node_name, &message).await {
+            Ok(c) => c,
+            Err(e) => {
+                METRICS.publish_failed();
+                return e;
+            }
+        };
+        METRICS.publish_confirmed(!confirmation.is_nack(), published.elapsed());
+        if confirmation.is_nack() {
+            refusals += 1;
+            if refusals <= NACK_MAX_RETRIES {
+                let delay = NACK_RETRY_DELAY * 2u32.pow(refusals - 1);
+                println!("message {} refused by the broker, retry in {:?}", message.sequence, delay);
+                tokio::time::delay_for(delay).await;
+                continue;
+            }
+        }
+        refusals = 0;
+        let mut outbox = sender.outbox.lock().unwrap();
+        let removed = match confirmation.is_nack() {
+            true => outbox.reject(message.sequence),
+            false => outbox.ack(message.sequence),
+        };
+        if let Err(e) = removed {
+            println!("unable to remove message {} from the outbox: {}", message.sequence, e);
+        }
+    }
+}
+
+fn exchange_kind(exchange_type: ExchangeType) -> ExchangeKind {
+    match exchange_type {
+        ExchangeType::Direct => ExchangeKind::Direct,
+        ExchangeType::Fanout => ExchangeKind::Fanout,
+        ExchangeType::Topic => ExchangeKind::Topic,
+        ExchangeType::Headers => ExchangeKind::Headers,
+    }
+}
+
+/// The message id is kept when a message is published again, so that consumers can drop duplicates.
+fn properties(config: &AmqpConfig, node_name: &str, message: &OutboxMessage) -> BasicProperties {
+    let properties = BasicProperties::default()
+        .with_content_type(CONTENT_TYPE.into())
+        .with_app_id(node_name.into())
+        .with_message_id(format!("{}-{}-{}", node_name, message.timestamp, message.sequence).into())
+        .with_timestamp(message.timestamp.max(0) as u64);
+    match config.persistent {
+        true => properties.with_delivery_mode(PERSISTENT_DELIVERY_MODE),
+        false => properties,
+    }
+}
+
+async fn publish(
+    channel: &Channel,
+    config: &AmqpConfig,
+    node_name: &str,
+    message: &OutboxMessage,
+) -> lapin::Result<lapin::publisher_confirm::Confirmation> {
+    channel
+        .basic_publish(
+            &config.exchange,
+            &message.routing_key,
+            BasicPublishOptions::default(),
+            message.payload.as_bytes().to_vec(),
+            properties(config, node_name, message),
+        )
+        .await?
+        .await
 }
-fn send(sender: &RabbitSender, msg: ExternalMessage) -> Result<()> {
-    LocalPool::new().run_until(inner_send(sender,msg))
-}
\ No newline at end of file
@@ -0,0 +1,276 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// length (u32 le) then crc32 of the payload (u32 le)
+const HEADER_LEN: usize = 8;
+
+/// Append-only log split in numbered segment files `<prefix>-00000001.log`.
+///
+/// Each record is `length | crc32 | payload`. A record torn by a crash is
+/// detected by its length or its checksum and cut off when the log is opened,
+/// so the log always restarts on the last complete record.
+pub struct SegmentLog {
+    dir: PathBuf,
+    prefix: String,
+    max_segment_bytes: u64,
+    sync: bool,
+    current: File,
+    current_index: u64,
+    current_size: u64,
+}
+
+impl SegmentLog {
+    pub fn open(dir: &Path, prefix: &str, max_segment_bytes: u64, sync: bool) -> io::Result<SegmentLog> {
+        fs::create_dir_all(dir)?;
+        let current_index = list_segments(dir, prefix)?
+            .last()
+            .map(|(index, _)| *index)
+            .unwrap_or(1);
+        let path = segment_path(dir, prefix, current_index);
+        let current = OpenOptions::new().create(true).append(true).open(&path)?;
+
+        let content = fs::read(&path)?;
+        let (_, valid_len) = parse_records(&content);
+        if valid_len < content.len() {
+            eprintln!(
+                "truncating {} bytes of incomplete record at the end of {}",
+                content.len() - valid_len,
+                path.display()
+            );
+            current.set_len(valid_len as u64)?;
+            current.sync_all()?;
+        }
+
+        Ok(SegmentLog {
+            dir: dir.to_path_buf(),
+            prefix: prefix.to_string(),
+            max_segment_bytes,
+            sync,
+            current,
+            current_index,
+            current_size: valid_len as u64,
+        })
+    }
+
+    pub fn append(&mut self, payload: &[u8]) -> io::Result<()> {
+        let record_len = (HEADER_LEN + payload.len()) as u64;
+        if self.current_size > 0 && self.current_size + record_len > self.max_segment_bytes {
+            self.roll()?;
+        }
+        let mut record = Vec::with_capacity(record_len as usize);
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+        record.extend_from_slice(payload);
+
+        self.current.write_all(&record)?;
+        if self.sync {
+            self.current.sync_data()?;
+        }
+        self.current_size += record_len;
+        Ok(())
+    }
+
+    /// Payloads of every complete record, oldest first.
+    pub fn read_all(&self) -> io::Result<Vec<Vec<u8>>> {
+        let mut records = vec![];
+        for (_, path) in list_segments(&self.dir, &self.prefix)? {
+            records.append(&mut read_segment(&path)?);
+        }
+        Ok(records)
+    }
+
+    /// Delete every segment before the current one.
+    pub fn remove_previous_segments(&mut self) -> io::Result<()> {
+        self.remove_segments_before(self.current_index)
+    }
+
+    /// Delete the segments numbered below `index`, never the current one.
+    pub fn remove_segments_before(&mut self, index: u64) -> io::Result<()> {
+        let index = std::cmp::min(index, self.current_index);
+        for (segment, path) in list_segments(&self.dir, &self.prefix)? {
+            if segment < index {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// number of the segment the next records are appended to
+    pub fn current_segment(&self) -> u64 {
+        self.current_index
+    }
+
+    /// true until a first record is appended to the current segment
+    pub fn is_current_empty(&self) -> bool {
+        self.current_size == 0
+    }
+
+    /// Write the records appended without `sync` to disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.current.sync_all()
+    }
+
+    /// Close the current segment and continue in a new one.
+    pub fn roll(&mut self) -> io::Result<()> {
+        self.current.sync_all()?;
+        self.current_index += 1;
+        let path = segment_path(&self.dir, &self.prefix, self.current_index);
+        self.current = OpenOptions::new().create(true).append(true).open(path)?;
+        self.current_size = 0;
+        Ok(())
+    }
+}
+
+/// Segments of the log `prefix` in `dir`, oldest first, none when `dir` does not exist.
+/// Unlike `open`, nothing is created nor truncated.
+pub fn segments(dir: &Path, prefix: &str) -> io::Result<Vec<PathBuf>> {
+    match list_segments(dir, prefix) {
+        Ok(segments) => Ok(segments.into_iter().map(|(_, path)| path).collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e),
+    }
+}
+
+/// Payloads of the complete records of one segment, a torn or corrupted end is ignored but left in place.
+pub fn read_segment(path: &Path) -> io::Result<Vec<Vec<u8>>> {
+    let content = fs::read(path)?;
+    let (records, valid_len) = parse_records(&content);
+    if valid_len < content.len() {
+        eprintln!("corrupted record in {} at offset {}, rest of the segment ignored", path.display(), valid_len);
+    }
+    Ok(records)
+}
+
+fn segment_path(dir: &Path, prefix: &str, index: u64) -> PathBuf {
+    dir.join(format!("{}-{:08}.log", prefix, index))
+}
+
+/// segments of the log ordered by index
+fn list_segments(dir: &Path, prefix: &str) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let index = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(prefix))
+            .and_then(|name| name.strip_prefix('-'))
+            .and_then(|name| name.strip_suffix(".log"))
+            .and_then(|index| index.parse::<u64>().ok());
+        if let Some(index) = index {
+            segments.push((index, path));
+        }
+    }
+    segments.sort_by_key(|(index, _)| *index);
+    Ok(segments)
+}
+
+/// complete records of a segment and length of the valid part
+fn parse_records(content: &[u8]) -> (Vec<Vec<u8>>, usize) {
+    let mut records = vec![];
+    let mut offset = 0;
+    while content.len() - offset >= HEADER_LEN {
+        let len = read_u32(&content[offset..]) as usize;
+        let crc = read_u32(&content[offset + 4..]);
+        let start = offset + HEADER_LEN;
+        if content.len() - start < len {
+            break;
+        }
+        let payload = &content[start..start + len];
+        if crc32fast::hash(payload) != crc {
+            break;
+        }
+        records.push(payload.to_vec());
+        offset = start + len;
+    }
+    (records, offset)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ayasha_segment_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn append_and_read() {
+        let dir = temp_dir("append");
+        let mut log = SegmentLog::open(&dir, "values", 1024, false).unwrap();
+        log.append(b"first").unwrap();
+        log.append(b"second").unwrap();
+
+        let reopened = SegmentLog::open(&dir, "values", 1024, false).unwrap();
+        assert_eq!(reopened.read_all().unwrap(), vec![b"first".to_vec(), b"second".to_vec()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn roll_segments() {
+        let dir = temp_dir("roll");
+        let mut log = SegmentLog::open(&dir, "values", 20, false).unwrap();
+        for i in 0..5u8 {
+            log.append(&[i; 10]).unwrap();
+        }
+        assert_eq!(list_segments(&dir, "values").unwrap().len(), 5);
+        assert_eq!(log.read_all().unwrap().len(), 5);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn remove_previous() {
+        let dir = temp_dir("remove");
+        let mut log = SegmentLog::open(&dir, "values", 1024, false).unwrap();
+        log.append(b"old").unwrap();
+        log.roll().unwrap();
+        log.append(b"new").unwrap();
+        log.remove_previous_segments().unwrap();
+        assert_eq!(log.read_all().unwrap(), vec![b"new".to_vec()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn torn_write_is_truncated() {
+        let dir = temp_dir("torn");
+        let mut log = SegmentLog::open(&dir, "values", 1024, false).unwrap();
+        log.append(b"complete").unwrap();
+        drop(log);
+
+        let path = segment_path(&dir, "values", 1);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[20, 0, 0, 0, 1, 2, 3, 4, b'p', b'a']).unwrap();
+        drop(file);
+
+        let mut log = SegmentLog::open(&dir, "values", 1024, false).unwrap();
+        log.append(b"after").unwrap();
+        assert_eq!(log.read_all().unwrap(), vec![b"complete".to_vec(), b"after".to_vec()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_record_is_ignored() {
+        let dir = temp_dir("crc");
+        let mut log = SegmentLog::open(&dir, "values", 1024, false).unwrap();
+        log.append(b"good").unwrap();
+        log.append(b"bad!").unwrap();
+        drop(log);
+
+        let path = segment_path(&dir, "values", 1);
+        let mut content = fs::read(&path).unwrap();
+        let last = content.len() - 1;
+        content[last] ^= 0xFF;
+        fs::write(&path, content).unwrap();
+
+        let log = SegmentLog::open(&dir, "values", 1024, false).unwrap();
+        assert_eq!(log.read_all().unwrap(), vec![b"good".to_vec()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
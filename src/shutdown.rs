@@ -0,0 +1,79 @@
+use tokio::sync::watch;
+
+/// Triggers the shutdown of every task holding a `Shutdown`.
+pub struct ShutdownTrigger {
+    inner: watch::Sender<bool>,
+}
+
+/// Shutdown request seen by a task.
+#[derive(Clone)]
+pub struct Shutdown {
+    inner: watch::Receiver<bool>,
+}
+
+pub fn channel() -> (ShutdownTrigger, Shutdown) {
+    let (sender, receiver) = watch::channel(false);
+    (ShutdownTrigger { inner: sender }, Shutdown { inner: receiver })
+}
+
+impl ShutdownTrigger {
+    pub fn trigger(&self) {
+        let _ = self.inner.broadcast(true);
+    }
+}
+
+impl Shutdown {
+    pub fn is_requested(&self) -> bool {
+        *self.inner.borrow()
+    }
+
+    /// resolves once the shutdown is triggered, or the trigger dropped
+    pub async fn requested(&mut self) {
+        while !self.is_requested() {
+            if self.inner.recv().await.is_none() {
+                return;
+            }
+        }
+    }
+}
+
+/// Resolves on SIGINT or SIGTERM.
+#[cfg(unix)]
+pub async fn signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => (),
+                _ = terminate.recv() => (),
+            }
+        }
+        Err(e) => {
+            println!("unable to listen to SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn every_task_sees_the_trigger() {
+        let (trigger, shutdown) = channel();
+        let mut waiting = shutdown.clone();
+        let task = tokio::spawn(async move { waiting.requested().await });
+        assert!(!shutdown.is_requested());
+
+        trigger.trigger();
+        tokio::time::timeout(Duration::from_secs(1), task).await.unwrap().unwrap();
+        assert!(shutdown.is_requested());
+    }
+}
@@ -0,0 +1,83 @@
+use crate::config::{Config, SinkType};
+use crate::domain::external_message::{CompositeSender, FilteredSender, MessageSender, SinkConnection};
+use crate::errors::*;
+use crate::file_sender::FileSender;
+use crate::mqtt_sender::MqttSender;
+use crate::rabbit_sender::{open_outbox, RabbitSender};
+use crate::webhook_sender::WebhookSender;
+use snafu::ResultExt;
+use std::fmt::Display;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Start every configured sink, to be called inside the tokio runtime.
+pub fn start(config: &Config) -> Result<CompositeSender> {
+    let mut senders = vec![];
+    for sink in config.sinks.iter() {
+        let (name, sender): (String, Box<dyn MessageSender + Send>) = match sink.kind {
+            SinkType::Amqp => (
+                format!("amqp {}", config.amqp.exchange),
+                Box::new(RabbitSender::start(&config.amqp, &config.node_name, open_outbox(&config.amqp, &config.storage)?)),
+            ),
+            SinkType::Mqtt => (
+                format!("mqtt {}", config.mqtt.host),
+                Box::new(MqttSender::start(&config.mqtt, &config.node_name)),
+            ),
+            SinkType::Webhook => {
+                let url = sink.url.clone().unwrap_or_default();
+                let sender = WebhookSender::start(
+                    &url,
+                    Duration::from_secs(sink.timeout_secs),
+                    sink.queue_max_messages,
+                );
+                (format!("webhook {}", url), Box::new(sender))
+            }
+            SinkType::File => {
+                let path = sink.path.clone().unwrap_or_default();
+                let sender = FileSender::open(&path).context(StorageOpenError {
+                    path: path.display().to_string(),
+                })?;
+                (format!("file {}", path.display()), Box::new(sender))
+            }
+        };
+        println!("publishing to {}", name);
+        senders.push(FilteredSender {
+            name,
+            filter: sink.filter.clone(),
+            sender,
+        });
+    }
+    Ok(CompositeSender::new(senders))
+}
+
+/// Connection of a sink to its destination, published by its publishing task.
+#[derive(Clone)]
+pub struct SinkMonitor {
+    inner: Arc<RwLock<SinkConnection>>,
+}
+
+impl SinkMonitor {
+    /// `connected` for the sinks only connecting to send a message
+    pub fn new(connected: bool) -> SinkMonitor {
+        SinkMonitor {
+            inner: Arc::new(RwLock::new(SinkConnection {
+                connected,
+                last_error: None,
+            })),
+        }
+    }
+
+    pub fn connection(&self) -> SinkConnection {
+        self.inner.read().expect("sink state lock poisoned").clone()
+    }
+
+    pub fn set_connected(&self) {
+        self.inner.write().expect("sink state lock poisoned").connected = true;
+    }
+
+    pub fn set_failed(&self, error: &dyn Display) {
+        let mut connection = self.inner.write().expect("sink state lock poisoned");
+        connection.connected = false;
+        connection.last_error = Some(error.to_string());
+    }
+}
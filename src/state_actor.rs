@@ -1,43 +1,341 @@
-use crate::domain::command_event::Command;
-use crate::rabbit_sender::RabbitSender;
-use std::sync::mpsc::channel;
+use crate::config::{Config, StorageConfig};
+use crate::errors::*;
+use crate::file_journal::FileJournal;
+use crate::domain::clock::{Clock, SystemClock};
+use crate::domain::command_event::{Command, Event};
+use crate::domain::errors::{DomainError, Result as DomainResult};
+use crate::domain::external_message::{MessageSettings, SinkState};
+use crate::domain::query::{self, Query, QueryResult};
+use crate::domain::sensor_identifier::SensorIdentifier;
+use crate::listener::LinkWriter;
+use crate::metrics::METRICS;
+use crate::domain::external_message::MessageSender as ExternalMessageSender;
+use crate::shutdown::Shutdown;
+use serde::Serialize;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{channel, Sender};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
 
-use crate::domain::{dispatch, apply, send_external_message};
+use crate::domain::{dispatch, apply, dispatch_line, route, send_external_message};
+use crate::domain::journal::{restore, EventJournal};
 use crate::domain::sensor::SensorRepository;
+use crate::domain::switch_event::SwitchFrames;
+use snafu::ResultExt;
 
 
+/// Commands waiting for the actor, senders wait when it is full.
+const COMMAND_QUEUE_SIZE: usize = 1024;
+/// Time given to the actor to answer a query
+const QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+const SINK_DRAIN_POLL: Duration = Duration::from_millis(100);
+/// Refresh period of the status while no command arrives
+const STATUS_REFRESH: Duration = Duration::from_secs(1);
+
+/// Delivery and storage state of the actor, refreshed after every command.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ActorStatus {
+    pub sinks: Vec<SinkState>,
+    pub storage: StorageStatus,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StorageStatus {
+    pub enabled: bool,
+    pub last_sequence: u64,
+    /// error of the last journal write, cleared by the next successful one
+    pub last_error: Option<String>,
+}
+
 #[derive(Clone)]
 pub struct MessageSender {
-    inner: std::sync::mpsc::Sender<Command>,
+    inner: Sender<Command>,
+    status: Arc<RwLock<ActorStatus>>,
 }
 
 impl MessageSender {
-    pub fn send(&self, mess: Command) {
-        self.inner.send(mess).expect("comm error with state actor");
-    }
-}
-
-pub fn init_actor() -> MessageSender {
-    let (sender, receiver) = channel::<Command>();
-    std::thread::spawn(move || {
-    let args: Vec<String> = std::env::args().collect();
-    let rabbit_address = &args[1];
-    let rabbit_user = &args[2];
-    let uri = format!("amqp://{}@{}/%2f", rabbit_user,rabbit_address);
-    let ex_message_sender = RabbitSender::new( uri,  "Ayasha".to_string());
-        let mut repo = SensorRepository::new();
-        loop {
-            match receiver.recv() {
-                Ok(command) => {
-                    dispatch(command, &repo)
-                    .and_then(|evs| send_external_message(evs, &ex_message_sender) )
-                    .map_or_else(
-                        |e| println!("error during dispatch: {}", e),
-                         |ve|apply(ve, &mut repo));
+    #[cfg(test)]
+    pub fn from_sender(inner: Sender<Command>) -> MessageSender {
+        MessageSender {
+            inner,
+            status: Arc::new(RwLock::new(ActorStatus::default())),
+        }
+    }
+
+    pub fn status(&self) -> ActorStatus {
+        self.status.read().expect("actor status lock poisoned").clone()
+    }
+
+    /// Wait for a place in the command queue, fails once the actor is stopped.
+    pub async fn send(&self, mess: Command) -> Result<()> {
+        METRICS.command_queued();
+        let mut pending = PendingCommand { queued: false };
+        let sent = self.inner.clone().send(mess).await;
+        pending.queued = sent.is_ok();
+        sent.map_err(|_| RfError::ComError {
+            value: "state actor stopped".to_string(),
+        })
+    }
+
+    pub async fn query(&self, query: Query) -> DomainResult<QueryResult> {
+        let (reply, receiver) = oneshot::channel();
+        self.ask(Command::Query(query, reply), receiver).await
+    }
+
+    pub async fn reset_sensor(&self, id: SensorIdentifier) -> DomainResult<()> {
+        let (reply, receiver) = oneshot::channel();
+        self.ask(Command::ResetSensor(id, reply), receiver).await
+    }
+
+    /// The wait for a place in the queue counts in the delay given to the actor.
+    async fn ask<T>(&self, command: Command, receiver: oneshot::Receiver<DomainResult<T>>) -> DomainResult<T> {
+        let answer = async {
+            // a stopped actor drops the reply
+            let _ = self.send(command).await;
+            receiver.await
+        };
+        match tokio::time::timeout(QUERY_TIMEOUT, answer).await {
+            Ok(Ok(result)) => result,
+            _ => Err(DomainError::DataExtractionError {
+                value: "no answer from the state actor".to_string(),
+            }),
+        }
+    }
+}
+
+/// Command counted in the queue depth, the count is given back when the command
+/// is refused or when its sending is cancelled by a timeout.
+struct PendingCommand {
+    queued: bool,
+}
+
+impl Drop for PendingCommand {
+    fn drop(&mut self) {
+        if !self.queued {
+            METRICS.command_dequeued();
+        }
+    }
+}
+
+/// Repository rebuilt by replaying the journal, empty and without journal when storage is disabled.
+pub fn load_repository(config: &StorageConfig) -> Result<(SensorRepository, Option<Box<dyn EventJournal>>)> {
+    let repo = SensorRepository::with_max_values(config.max_values_per_sensor);
+    if !config.enabled {
+        return Ok((repo, None));
+    }
+    let mut journal = FileJournal::open(config).context(StorageOpenError {
+        path: config.path.display().to_string(),
+    })?;
+    let (mut repo, replay) = restore(&mut journal, repo).context(InternalDomainError)?;
+    let events = dispatch(replay, &repo, &SystemClock).context(InternalDomainError)?;
+    eprintln!("{} events replayed from the journal", events.len());
+    apply(events, &mut repo);
+    Ok((repo, Some(Box::new(journal))))
+}
+
+/// unknown frames are not part of the repository state, they are published but never journaled
+fn journal_events(journal: &mut Option<Box<dyn EventJournal>>, events: &[Event]) -> DomainResult<()> {
+    let events = events
+        .iter()
+        .filter(|e| !matches!(e, Event::UnknowDataReceived(_)))
+        .cloned()
+        .collect::<Vec<Event>>();
+    match journal.as_mut() {
+        Some(journal) if !events.is_empty() => journal.append(&events),
+        _ => Ok(()),
+    }
+}
+
+/// to be called once the journaled events are applied to `repo`
+fn take_snapshot(journal: &mut Option<Box<dyn EventJournal>>, repo: &SensorRepository) -> DomainResult<()> {
+    match journal.as_mut() {
+        Some(journal) if journal.needs_snapshot() => journal.save_snapshot(repo),
+        _ => Ok(()),
+    }
+}
+
+struct Actor {
+    link_writer: LinkWriter,
+    repo: SensorRepository,
+    switch_frames: SwitchFrames,
+    journal: Option<Box<dyn EventJournal>>,
+    ex_message_sender: Box<dyn ExternalMessageSender + Send>,
+    settings: MessageSettings,
+    clock: Box<dyn Clock>,
+    storage_error: Option<String>,
+    status: Arc<RwLock<ActorStatus>>,
+}
+
+impl Actor {
+    fn handle(&mut self, command: Command) {
+        let command = match route(command, &self.link_writer) {
+            Some(c) => c,
+            None => return,
+        };
+        let dispatched = match command {
+            Command::IncomingData(line) => match dispatch_line(&line, &self.repo, &self.switch_frames, self.clock.as_ref()) {
+                Ok(dispatched) => {
+                    METRICS.frame_decoded(dispatched.protocol);
+                    if let Some(frame) = dispatched.switch_frame {
+                        self.switch_frames.record(frame);
+                    }
+                    Ok(dispatched.events)
+                }
+                Err(e) => {
+                    METRICS.decode_error(&e);
+                    Err(e)
+                }
+            },
+            command => dispatch(command, &self.repo, self.clock.as_ref()),
+        };
+        match dispatched {
+            Err(e) => println!("error during dispatch: {}", e),
+            Ok(events) => {
+                // a sending failure must not lose the state update
+                if let Err(e) = send_external_message(&events, self.ex_message_sender.as_ref(), &self.settings, self.clock.as_ref()) {
+                    println!("error during message sending: {}", e);
+                }
+                let stored = journal_events(&mut self.journal, &events).map_err(|e| format!("unable to journal events: {}", e));
+                apply(events, &mut self.repo);
+                let snapshot = take_snapshot(&mut self.journal, &self.repo).map_err(|e| format!("unable to save snapshot: {}", e));
+                match stored.and(snapshot) {
+                    Ok(_) => self.storage_error = None,
+                    Err(e) => {
+                        println!("{}", e);
+                        self.storage_error = Some(e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn publish_status(&self) {
+        let status = ActorStatus {
+            sinks: self.ex_message_sender.sinks(),
+            storage: StorageStatus {
+                enabled: self.journal.is_some(),
+                last_sequence: self.journal.as_ref().map_or(0, |j| j.last_sequence()),
+                last_error: self.storage_error.clone(),
+            },
+        };
+        *self.status.write().expect("actor status lock poisoned") = status;
+    }
+
+    /// Give the sinks until `timeout` to deliver their messages, then write everything to disk.
+    async fn stop(self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        while self.ex_message_sender.pending() > 0 && Instant::now() < deadline {
+            tokio::time::delay_for(SINK_DRAIN_POLL).await;
+        }
+        let pending = self.ex_message_sender.pending();
+        if pending > 0 {
+            println!("{} messages not delivered at shutdown", pending);
+        }
+        tokio::task::spawn_blocking(move || self.persist())
+            .await
+            .expect("state actor panicked while persisting");
+    }
+
+    /// Write the sinks and a last snapshot to disk, blocks the thread.
+    fn persist(self) {
+        if let Err(e) = self.ex_message_sender.flush() {
+            println!("unable to flush the sinks: {}", e);
+        }
+        if let Some(mut journal) = self.journal {
+            // a restart reads the snapshot instead of replaying the journal
+            if let Err(e) = journal.save_snapshot(&self.repo) {
+                println!("unable to save snapshot: {}", e);
+            }
+            if let Err(e) = journal.flush() {
+                println!("unable to flush the journal: {}", e);
+            }
+        }
+    }
+}
+
+/// Spawn the actor owning the repository, to be called inside the tokio runtime.
+/// Once `shutdown` is triggered it refuses new commands, handles the queued ones,
+/// flushes the sinks and the journal, then drops `link_writer`.
+pub fn init_actor(
+    config: &Config,
+    link_writer: LinkWriter,
+    repo: SensorRepository,
+    journal: Option<Box<dyn EventJournal>>,
+    ex_message_sender: Box<dyn ExternalMessageSender + Send>,
+    clock: Box<dyn Clock>,
+    mut shutdown: Shutdown,
+) -> (MessageSender, JoinHandle<()>) {
+    let (sender, mut receiver) = channel::<Command>(COMMAND_QUEUE_SIZE);
+    let settings = MessageSettings {
+        node_name: config.node_name.clone(),
+        sensor_routing_key: config.amqp.sensor_routing_key.clone(),
+        switch_routing_key: config.amqp.switch_routing_key.clone(),
+        unknown_routing_key: config.amqp.unknown_routing_key.clone(),
+    };
+    let status = Arc::new(RwLock::new(ActorStatus::default()));
+    let mut actor = Actor {
+        link_writer,
+        repo,
+        switch_frames: SwitchFrames::default(),
+        journal,
+        ex_message_sender,
+        settings,
+        clock,
+        storage_error: None,
+        status: status.clone(),
+    };
+    let timeout = Duration::from_secs(config.shutdown_timeout_secs);
+    let task = tokio::spawn(async move {
+        let mut refresh = tokio::time::interval(STATUS_REFRESH);
+        let mut running = true;
+        while running {
+            tokio::select! {
+                _ = shutdown.requested(), if !shutdown.is_requested() => {
+                    // the commands already queued are still handled
+                    receiver.close();
+                }
+                command = receiver.recv() => match command {
+                    // read only, answered without waiting for the blocking pool
+                    Some(Command::Query(query, reply)) => {
+                        METRICS.command_dequeued();
+                        let _ = reply.send(query::execute(&query, &actor.repo));
+                    }
+                    Some(command) => {
+                        METRICS.command_dequeued();
+                        // the journal and the sinks write to disk, away from the runtime workers
+                        actor = tokio::task::spawn_blocking(move || {
+                            actor.handle(command);
+                            actor.publish_status();
+                            actor
+                        })
+                        .await
+                        .expect("state actor panicked");
+                    }
+                    None => running = false,
                 },
-                Err(e) => println!("inter task comm error {}", e),
+                // the sinks deliver in background, their pending messages change without command
+                _ = refresh.tick() => actor.publish_status(),
             }
         }
+        actor.stop(timeout).await;
+        println!("state actor stopped");
     });
-    MessageSender { inner: sender }
+    (MessageSender { inner: sender, status }, task)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn query_gives_up_on_a_full_queue() {
+        let (mut inner, _receiver) = channel::<Command>(1);
+        let (reply, _) = oneshot::channel();
+        assert!(inner.send(Command::Query(Query::AllSensors, reply)).await.is_ok());
+        let sender = MessageSender::from_sender(inner);
+
+        let asked = tokio::time::timeout(QUERY_TIMEOUT * 2, sender.query(Query::LastValues)).await;
+        assert!(asked.expect("ask must not wait for the queue forever").is_err());
+    }
 }
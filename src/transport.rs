@@ -0,0 +1,121 @@
+use crate::config::{FlowControl, Parity, SerialConfig};
+use crate::errors::*;
+
+use snafu::ResultExt;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+const TCP_SCHEME: &str = "tcp://";
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const TCP_KEEPALIVE: Duration = Duration::from_secs(30);
+
+/// Byte stream to the RFLink, whatever the way it is attached.
+pub trait RfStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> RfStream for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+#[derive(Debug, PartialEq)]
+pub enum Transport {
+    /// Local serial device (ex: /dev/ttyACM0)
+    Serial(String),
+    /// Serial to network bridge such as ser2net or an ESP8266 (ex: tcp://192.168.1.20:23)
+    Tcp(String),
+}
+
+impl Transport {
+    pub fn from_device(device: &str) -> Transport {
+        match device {
+            d if d.starts_with(TCP_SCHEME) => Transport::Tcp(d[TCP_SCHEME.len()..].to_string()),
+            d => Transport::Serial(d.to_string()),
+        }
+    }
+}
+
+pub async fn connect(config: &SerialConfig) -> Result<Box<dyn RfStream>> {
+    match Transport::from_device(&config.device) {
+        Transport::Serial(path) => open_serial(&path, config),
+        Transport::Tcp(address) => open_tcp(&address).await,
+    }
+}
+
+fn open_serial(path: &str, config: &SerialConfig) -> Result<Box<dyn RfStream>> {
+    let settings = to_port_settings(config);
+    #[allow(unused_mut)]
+    let mut port = tokio_serial::Serial::from_path(path, &settings).context(OpenPortError {
+        device: path.to_string(),
+    })?;
+
+    #[cfg(unix)]
+    port.set_exclusive(false).context(ConfigurationError)?;
+
+    Ok(Box::new(port))
+}
+
+async fn open_tcp(address: &str) -> Result<Box<dyn RfStream>> {
+    let stream = match tokio::time::timeout(TCP_CONNECT_TIMEOUT, TcpStream::connect(address)).await {
+        Ok(result) => result,
+        Err(e) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, e)),
+    }
+    .context(OpenPortError {
+        device: format!("{}{}", TCP_SCHEME, address),
+    })?;
+
+    // a bridge losing its wifi never closes the socket, keepalive lets us notice it
+    stream.set_keepalive(Some(TCP_KEEPALIVE)).context(OpenPortError {
+        device: format!("{}{}", TCP_SCHEME, address),
+    })?;
+    stream.set_nodelay(true).context(OpenPortError {
+        device: format!("{}{}", TCP_SCHEME, address),
+    })?;
+
+    Ok(Box::new(stream))
+}
+
+fn to_port_settings(config: &SerialConfig) -> tokio_serial::SerialPortSettings {
+    tokio_serial::SerialPortSettings {
+        baud_rate: config.baud_rate,
+        data_bits: match config.data_bits {
+            5 => tokio_serial::DataBits::Five,
+            6 => tokio_serial::DataBits::Six,
+            7 => tokio_serial::DataBits::Seven,
+            _ => tokio_serial::DataBits::Eight,
+        },
+        flow_control: match config.flow_control {
+            FlowControl::None => tokio_serial::FlowControl::None,
+            FlowControl::Software => tokio_serial::FlowControl::Software,
+            FlowControl::Hardware => tokio_serial::FlowControl::Hardware,
+        },
+        parity: match config.parity {
+            Parity::None => tokio_serial::Parity::None,
+            Parity::Odd => tokio_serial::Parity::Odd,
+            Parity::Even => tokio_serial::Parity::Even,
+        },
+        stop_bits: match config.stop_bits {
+            2 => tokio_serial::StopBits::Two,
+            _ => tokio_serial::StopBits::One,
+        },
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn transport_serial() {
+        assert_eq!(
+            Transport::from_device("/dev/ttyACM0"),
+            Transport::Serial("/dev/ttyACM0".to_string())
+        );
+    }
+
+    #[test]
+    fn transport_tcp() {
+        assert_eq!(
+            Transport::from_device("tcp://192.168.1.20:23"),
+            Transport::Tcp("192.168.1.20:23".to_string())
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use crate::domain::errors::Result;
+use crate::domain::external_message::{ExternalMessage, MessageSender, SinkConnection};
+use crate::message_queue::MessageQueue;
+use crate::sinks::SinkMonitor;
+use hyper::{Body, Client, Method, Request, StatusCode};
+use std::time::Duration;
+
+const RETRY_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// POST every message as JSON to an http endpoint, in order, retrying while
+/// the endpoint is unreachable or answers a server error.
+#[derive(Clone)]
+pub struct WebhookSender {
+    queue: MessageQueue,
+    monitor: SinkMonitor,
+}
+
+impl WebhookSender {
+    /// Spawn the posting task, to be called inside the tokio runtime.
+    pub fn start(url: &str, timeout: Duration, max_messages: usize) -> WebhookSender {
+        let queue = MessageQueue::new(&format!("webhook {}", url), max_messages);
+        // a webhook is only reached to post a message, reachable until a post fails
+        let monitor = SinkMonitor::new(true);
+        tokio::spawn(post_messages(url.to_string(), timeout, queue.clone(), monitor.clone()));
+        WebhookSender { queue, monitor }
+    }
+}
+
+impl MessageSender for WebhookSender {
+    fn send(&self, msg: ExternalMessage) -> Result<()> {
+        self.queue.push(msg);
+        Ok(())
+    }
+
+    fn pending(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn connection(&self) -> SinkConnection {
+        self.monitor.connection()
+    }
+}
+
+async fn post(client: &Client<hyper::client::HttpConnector>, url: &str, timeout: Duration, msg: &ExternalMessage) -> std::result::Result<StatusCode, String> {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header("content-type", "application/json")
+        .body(Body::from(msg.inner_message.clone()))
+        .map_err(|e| e.to_string())?;
+    match tokio::time::timeout(timeout, client.request(request)).await {
+        Ok(Ok(response)) => Ok(response.status()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("timeout".to_string()),
+    }
+}
+
+async fn post_messages(url: String, timeout: Duration, queue: MessageQueue, monitor: SinkMonitor) {
+    let client = Client::new();
+    let mut delay = RETRY_INITIAL_DELAY;
+    loop {
+        let message = match queue.front() {
+            Some(m) => m,
+            None => {
+                queue.pushed().await;
+                continue;
+            }
+        };
+        match post(&client, &url, timeout, &message).await {
+            Ok(status) if status.is_success() => {
+                monitor.set_connected();
+                queue.pop_front();
+            }
+            // the endpoint will never accept this message
+            Ok(status) if status.is_client_error() => {
+                println!("webhook {} refused a message: {}", url, status);
+                monitor.set_connected();
+                queue.pop_front();
+            }
+            failure => {
+                let reason = match failure {
+                    Ok(status) => status.to_string(),
+                    Err(e) => e,
+                };
+                println!("webhook {} unavailable: {}, retry in {:?}", url, reason, delay);
+                monitor.set_failed(&reason);
+                tokio::time::delay_for(delay).await;
+                delay = std::cmp::min(delay * 2, RETRY_MAX_DELAY);
+                continue;
+            }
+        }
+        delay = RETRY_INITIAL_DELAY;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::command_event::Event;
+    use crate::domain::external_message::get_external_message;
+    use crate::domain::raw_frame::RawFrame;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn post_to_endpoint() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let sender = WebhookSender::start(&url, Duration::from_secs(2), 10);
+
+        let event = Event::UnknowDataReceived(RawFrame::new("20;01;Unknown;", chrono::Utc::now()));
+        sender.send(get_external_message("node", "key", &event, chrono::Utc::now()).unwrap()).unwrap();
+
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = vec![0; 4096];
+        let size = socket.read(&mut request).await.unwrap();
+        let request = String::from_utf8_lossy(&request[..size]).to_string();
+        socket
+            .write_all(b"HTTP/1.1 204 No Content\r\ncontent-length: 0\r\n\r\n")
+            .await
+            .unwrap();
+
+        assert!(request.starts_with("POST /hook HTTP/1.1"));
+        assert!(request.contains("application/json"));
+        assert!(request.contains("SensorUnknowDataReceived"));
+    }
+}